@@ -0,0 +1,113 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::warn;
+
+// Sender and hash of each copy of a block we've received, keyed by block offset.
+type BlockSenders = HashMap<u32, Vec<(IpAddr, [u8; 20])>>;
+
+// Remembers who sent each block of pieces that failed the hash check. Once
+// the piece has been downloaded correctly, the blocks that differ point to
+// the peers that sent us bad data.
+#[derive(Debug, Default)]
+pub struct SmartBan {
+    suspects: HashMap<u32, BlockSenders>,
+    banned: HashSet<IpAddr>,
+    state_path: Option<PathBuf>,
+}
+
+impl SmartBan {
+    // Loads previously banned IPs (one per line) and keeps the file up to date
+    // as more peers get banned.
+    pub fn load(state_path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut banned = HashSet::new();
+
+        if state_path.exists() {
+            for line in fs::read_to_string(&state_path)?.lines() {
+                match line.trim().parse() {
+                    Ok(ip) => {
+                        banned.insert(ip);
+                    },
+                    Err(_) if line.trim().is_empty() => {},
+                    Err(_) => {
+                        warn!("Ignoring invalid entry in ban list: {}", line);
+                    },
+                }
+            }
+        }
+
+        Ok(SmartBan { suspects: HashMap::new(), banned, state_path: Some(state_path) })
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn banned(&self) -> Vec<IpAddr> {
+        let mut banned: Vec<IpAddr> = self.banned.iter().copied().collect();
+        banned.sort();
+
+        banned
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.banned.insert(ip) {
+            return Ok(());
+        }
+
+        warn!("Banning peer {} for sending corrupt data", ip);
+
+        if let Some(state_path) = &self.state_path {
+            if let Some(parent) = state_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let contents: Vec<String> = self.banned().iter().map(|ip| ip.to_string()).collect();
+            fs::write(state_path, contents.join("\n") + "\n")?;
+        }
+
+        Ok(())
+    }
+
+    // Records the sender and hash of every block of a piece that failed verification.
+    pub fn piece_failed(&mut self, piece_index: u32, blocks: &[(u32, IpAddr, &[u8])]) {
+        let suspects = self.suspects.entry(piece_index).or_default();
+
+        for (begin, ip, data) in blocks {
+            suspects.entry(*begin).or_default().push((*ip, Sha1::digest(data).into()));
+        }
+    }
+
+    // Compares a verified piece against earlier failed attempts, banning
+    // everyone who sent us a block that doesn't match. Returns the newly banned IPs.
+    pub fn piece_passed(&mut self, piece_index: u32, blocks: &[(u32, IpAddr, &[u8])]) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+        let suspects = match self.suspects.remove(&piece_index) {
+            Some(suspects) => suspects,
+            None => return Ok(vec![]),
+        };
+
+        let mut offenders = vec![];
+
+        for (begin, _, data) in blocks {
+            let good_hash: [u8; 20] = Sha1::digest(data).into();
+
+            for (ip, hash) in suspects.get(begin).into_iter().flatten() {
+                if *hash != good_hash && !offenders.contains(ip) && !self.is_banned(ip) {
+                    offenders.push(*ip);
+                }
+            }
+        }
+
+        for ip in &offenders {
+            self.ban(*ip)?;
+        }
+
+        Ok(offenders)
+    }
+}
@@ -5,7 +5,7 @@ use std::fs;
 use crate::domain::{Torrent, AnnounceResponse};
 
 pub fn decode_bencoded_value(encoded_value: &str) -> serde_bencode::value::Value {
-    let deserialized: serde_bencode::value::Value = serde_bencode::from_str(&encoded_value).unwrap();
+    let deserialized: serde_bencode::value::Value = serde_bencode::from_str(encoded_value).unwrap();
 
     return deserialized;
 }
@@ -15,7 +15,7 @@ fn decoded_value_to_string(decoded_value: &serde_bencode::value::Value) -> Strin
         serde_bencode::value::Value::Int(x) => format!("{}", x),
         serde_bencode::value::Value::Bytes(v) => format!("\"{}\"", std::str::from_utf8(v).unwrap()),
         serde_bencode::value::Value::List(v) => 
-            format!("[{}]", v.iter().map(|x| decoded_value_to_string(x)).collect::<Vec<String>>().join(",")),
+            format!("[{}]", v.iter().map(decoded_value_to_string).collect::<Vec<String>>().join(",")),
         serde_bencode::value::Value::Dict(v) => {
             let mut sorted_keys: Vec<(&Vec<u8>, String)> = v.iter().map(|x| (x.0, decoded_value_to_string(x.1))).collect();
             sorted_keys.sort();
@@ -39,6 +39,6 @@ pub fn decode_torrent(file_path: &str) -> Result<Torrent, &'static str> {
     return Ok(torrent);
 }
 
-pub fn decode_announce_response(response: &Bytes) -> AnnounceResponse {
-    return serde_bencode::from_bytes(response).unwrap();
+pub fn decode_announce_response(response: &Bytes) -> Result<AnnounceResponse, serde_bencode::Error> {
+    return serde_bencode::from_bytes(response);
 }
\ No newline at end of file
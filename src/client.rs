@@ -1,37 +1,772 @@
-use std::{collections::HashMap, io::{BufWriter, Write}};
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::{BufWriter, ErrorKind, Seek, SeekFrom, Write}, net::{IpAddr, SocketAddr}, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
 
 use bytes::{Bytes, BytesMut, BufMut};
-use sha1::{Digest, Sha1};
-use tokio::{net::TcpStream, io::{AsyncWriteExt, AsyncReadExt, BufReader}};
+use serde_bytes::ByteBuf;
+use tokio::{net::TcpStream, io::{AsyncWriteExt, AsyncReadExt}, sync::{broadcast, mpsc, watch, OwnedSemaphorePermit, Semaphore}, time::{timeout_at, Instant}};
 
 use crate::{
-    domain::{Torrent, calculate_info_hash, PeerInfo, PeerMessage, RequestMessage},
+    dht::{default_bind_addr, DhtNode, BOOTSTRAP_NODES, K},
+    events::{event_channel, ClientEvent},
+    domain::{Torrent, has_piece, set_piece, HashRequest, HashesMessage, MessageReader, PeerInfo, PeerMessage, PeerState, ExtendedMessage, DHT_BIT, FAST_EXTENSION_BIT},
+    merkle,
+    mse::{self, EncryptionPolicy, PeerStream},
+    extension::{ExtensionHandshake, ExtensionRegistry, EXTENDED_HANDSHAKE_ID, EXTENSION_PROTOCOL_BIT},
+    picker::PiecePicker,
+    retry::RetryPolicy,
+    pex::{advertised_peer, decode_compact_peers, PexMessage, PexPeer, PexState, PEX_EXTENSION_NAME},
+    ban::SmartBan,
+    ratelimit::{BandwidthLimits, Direction, LimiterPair},
+    stats::TransferStats,
+    proxy::{Proxy, ProxyKind},
+    tracker::{announce_udp, TrackerTiers, UdpAnnounce},
+    storage::Storage,
+    transport::Transport,
+    utp::UtpSocket,
+    lsd::{LocalDiscovery, LSD_GROUP},
+    streaming::{PlaybackCursor, Streaming, StreamingConfig},
+    webseed::{RangesIgnored, WebSeed, WebSeedPeer},
     bencode::decode_announce_response, info, debug, warn};
 
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("Could not download {} piece(s) after exhausting retries: {:?}", .0.len(), .0)]
+    MissingPieces(Vec<u32>),
+    #[error("Piece {0} missed its streaming deadline")]
+    DeadlineMissed(u32),
+}
+
 pub struct Client {
     peer_id: String,
-    connections: HashMap<String, TcpStream>,
+    connections: HashMap<String, PeerConnection>,
     bitfields: HashMap<String, Vec<u8>>,
+    peer_states: HashMap<String, PeerState>,
+    extension_registry: ExtensionRegistry,
+    extensions: HashMap<String, ExtensionHandshake>,
+    peer_addrs: HashMap<String, SocketAddr>,
+    // Peers that connected to us, rather than the other way round.
+    incoming_peers: HashSet<String>,
+    pex: HashMap<String, PexState>,
+    peer_pool: HashSet<String>,
+    dht_state_path: Option<PathBuf>,
+    // UDP port for the DHT node we start, instead of the one it had last time.
+    dht_port: Option<u16>,
+    picker: Option<PiecePicker>,
+    request_timeout: Duration,
+    snub_timeout: Duration,
+    smart_ban: SmartBan,
+    retry_policy: RetryPolicy,
+    events: broadcast::Sender<ClientEvent>,
+    stats: Arc<TransferStats>,
+    // None until we listen for peers, in which case no port is announced.
+    listen_port: Option<u16>,
+    // Sent to trackers as our address, for hosts with more than one.
+    announce_ip: Option<IpAddr>,
+    // Where the router forwards connections to our listen port from outside.
+    // Port mappings can move when they're renewed, so it's watched.
+    external_addr: watch::Receiver<Option<SocketAddr>>,
+    connection_limit: Option<Arc<Semaphore>>,
+    connection_permits: HashMap<String, OwnedSemaphorePermit>,
+    incoming: Option<mpsc::UnboundedReceiver<IncomingPeer>>,
+    limits: BandwidthLimits,
+    streaming: Option<Streaming>,
+    // Streaming pieces that have missed their deadline. Each piece is only
+    // moved to a faster peer once.
+    escalated: HashSet<u32>,
+    // Web seeds act as peers that have every piece, keyed by URL.
+    web_seeds: BTreeMap<String, WebSeedPeer>,
+    // Piece layers of v2 torrents that peers sent us, keyed by pieces root.
+    piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+    // Piece layers we've only verified some chunks of so far.
+    partial_piece_layers: HashMap<merkle::Hash, Vec<Option<merkle::Hash>>>,
+    // Encryption, uTP, the DHT node and the LSD service, which a session can
+    // change while we download.
+    settings: SharedSettings,
+    // Outgoing connections try uTP first when there's a socket for it.
+    utp: Option<Arc<UtpSocket>>,
+    lsd_enabled: bool,
+    // Peers on the local network announcing our torrents, by info hash.
+    lsd_peers: HashMap<String, mpsc::UnboundedReceiver<SocketAddr>>,
+    // Tracker tiers by info hash, in the order they'll be tried.
+    trackers: HashMap<String, TrackerTiers>,
+}
+
+// An open connection, with whatever part of the next message has arrived.
+struct PeerConnection {
+    stream: PeerStream,
+    reader: MessageReader,
+}
+
+// A peer that connected to us and whose handshake has already been answered.
+#[derive(Debug)]
+pub struct IncomingPeer {
+    pub stream: PeerStream,
+    pub handshake: Bytes,
+    pub permit: Option<OwnedSemaphorePermit>,
+}
+
+// Settings that apply to every torrent of a session. Clients read them each
+// time they connect to a peer, so changes reach torrents that are already
+// downloading. Clones share the same settings.
+#[derive(Clone, Default)]
+pub struct SharedSettings {
+    encryption: Arc<Mutex<EncryptionPolicy>>,
+    utp_enabled: Arc<AtomicBool>,
+    dht: Arc<Mutex<Option<Arc<DhtNode>>>>,
+    lsd: Arc<Mutex<Option<Arc<LocalDiscovery>>>>,
+    proxy: Arc<Mutex<Option<Proxy>>>,
+    // Goes through the proxy when it's an HTTP one.
+    http: Arc<Mutex<reqwest::Client>>,
+    force_proxy: Arc<AtomicBool>,
+}
+
+impl SharedSettings {
+    pub fn encryption_policy(&self) -> EncryptionPolicy {
+        *self.encryption.lock().unwrap()
+    }
+
+    pub fn set_encryption_policy(&self, policy: EncryptionPolicy) {
+        *self.encryption.lock().unwrap() = policy;
+    }
+
+    pub fn utp_enabled(&self) -> bool {
+        self.utp_enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_utp_enabled(&self, enabled: bool) {
+        self.utp_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn dht(&self) -> Option<Arc<DhtNode>> {
+        self.dht.lock().unwrap().clone()
+    }
+
+    pub fn set_dht(&self, dht: Arc<DhtNode>) {
+        *self.dht.lock().unwrap() = Some(dht);
+    }
+
+    pub fn lsd(&self) -> Option<Arc<LocalDiscovery>> {
+        self.lsd.lock().unwrap().clone()
+    }
+
+    pub fn set_lsd(&self, lsd: Arc<LocalDiscovery>) {
+        *self.lsd.lock().unwrap() = Some(lsd);
+    }
+
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy.lock().unwrap().clone()
+    }
+
+    pub fn set_proxy(&self, proxy: Proxy) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(http_proxy) = proxy.reqwest_proxy()? {
+            *self.http.lock().unwrap() = reqwest::Client::builder().proxy(http_proxy).build()?;
+        }
+        *self.proxy.lock().unwrap() = Some(proxy);
+
+        Ok(())
+    }
+
+    pub fn http(&self) -> reqwest::Client {
+        self.http.lock().unwrap().clone()
+    }
+
+    pub fn force_proxy(&self) -> bool {
+        self.force_proxy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_force_proxy(&self) {
+        self.force_proxy.store(true, Ordering::Relaxed);
+    }
 }
 
 impl Client {
+    const MAX_OUTSTANDING_REQUESTS: i64 = 250;
+    // Number of block requests we keep in flight to a single peer.
+    const PIPELINE_DEPTH: usize = 5;
+    pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+    pub const DEFAULT_SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+    const ENCRYPTED_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+    const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
     pub fn new(peer_id: String) -> Client {
-        let connections: HashMap<String, TcpStream> = HashMap::new();
+        let connections: HashMap<String, PeerConnection> = HashMap::new();
         let bitfield_received: HashMap<String, Vec<u8>> = HashMap::new();
 
+        let mut extension_registry = ExtensionRegistry::default();
+        extension_registry.register(PEX_EXTENSION_NAME);
+
         Client {
             peer_id,
             connections,
             bitfields: bitfield_received,
+            peer_states: HashMap::new(),
+            extension_registry,
+            extensions: HashMap::new(),
+            peer_addrs: HashMap::new(),
+            incoming_peers: HashSet::new(),
+            pex: HashMap::new(),
+            peer_pool: HashSet::new(),
+            dht_state_path: None,
+            dht_port: None,
+            picker: None,
+            request_timeout: Self::DEFAULT_REQUEST_TIMEOUT,
+            snub_timeout: Self::DEFAULT_SNUB_TIMEOUT,
+            smart_ban: SmartBan::default(),
+            retry_policy: RetryPolicy::default(),
+            events: event_channel(),
+            stats: Arc::new(TransferStats::default()),
+            listen_port: None,
+            announce_ip: None,
+            external_addr: watch::channel(None).1,
+            connection_limit: None,
+            connection_permits: HashMap::new(),
+            incoming: None,
+            limits: BandwidthLimits::default(),
+            streaming: None,
+            escalated: HashSet::new(),
+            web_seeds: BTreeMap::new(),
+            piece_layers: BTreeMap::new(),
+            partial_piece_layers: HashMap::new(),
+            settings: SharedSettings::default(),
+            utp: None,
+            lsd_enabled: false,
+            lsd_peers: HashMap::new(),
+            trackers: HashMap::new(),
         }
     }
 
-    pub fn discover_peers(&self, torrent: &Torrent) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // Receives every event the client emits from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    // Transfer counters, shared so they can be sampled while the client is busy.
+    pub fn stats(&self) -> Arc<TransferStats> {
+        self.stats.clone()
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        // Nobody listening is fine.
+        let _ = self.events.send(event);
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    // The port we tell trackers, the DHT and peers that we accept connections on.
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = Some(port);
+    }
+
+    // The address trackers should hand out for us, instead of the one our
+    // announce came from.
+    pub fn set_announce_ip(&mut self, ip: IpAddr) {
+        self.announce_ip = Some(ip);
+    }
+
+    // The address a port mapping on the router gave us. Trackers, the DHT and
+    // peers are told its port, and trackers its address unless there's an
+    // announce ip.
+    pub fn set_external_addr(&mut self, addr: SocketAddr) {
+        self.external_addr = watch::channel(Some(addr)).1;
+    }
+
+    // Like `set_external_addr`, but keeps up with the address as it changes,
+    // e.g. that of a `PortMapper`.
+    pub fn follow_external_addr(&mut self, external_addr: watch::Receiver<Option<SocketAddr>>) {
+        self.external_addr = external_addr;
+    }
+
+    fn external_addr(&self) -> Option<SocketAddr> {
+        *self.external_addr.borrow()
+    }
+
+    // The port peers outside should connect to, if they can connect at all.
+    fn announced_port(&self) -> Option<u16> {
+        self.external_addr().map(|addr| addr.port()).or(self.listen_port)
+    }
+
+    // Sends tracker announces and peer connections through `proxy`. Peers are
+    // only connected to over TCP then, since the proxy can't carry uTP.
+    pub fn set_proxy(&mut self, proxy: Proxy) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Connecting through proxy {}", proxy);
+        self.settings.set_proxy(proxy)
+    }
+
+    // Refuses every connection that can't go through the proxy: incoming
+    // peers, the DHT, local service discovery, and trackers or web seeds the
+    // proxy can't reach.
+    pub fn force_proxy(&mut self) {
+        self.settings.set_force_proxy();
+    }
+
+    // The DHT talks to other nodes directly, so it's not used when the proxy
+    // is forced.
+    fn dht(&self) -> Option<Arc<DhtNode>> {
+        self.settings.dht().filter(|_| !self.settings.force_proxy())
+    }
+
+    // Every connection, incoming or outgoing, holds a permit from `limit` for
+    // as long as it's open. The semaphore can be shared between clients.
+    pub fn set_connection_limit(&mut self, limit: Arc<Semaphore>) {
+        self.connection_limit = Some(limit);
+    }
+
+    // Peers that connected to us are picked up from `incoming` whenever we
+    // look for a peer to download from.
+    pub fn set_incoming(&mut self, incoming: mpsc::UnboundedReceiver<IncomingPeer>) {
+        self.incoming = Some(incoming);
+    }
+
+    // Handles to this client's bandwidth limiters, which can be changed while
+    // it's downloading.
+    pub fn bandwidth_limits(&self) -> BandwidthLimits {
+        self.limits.clone()
+    }
+
+    // Takes encryption, uTP, DHT and LSD settings from `settings` from now on,
+    // e.g. those of a session, instead of our own.
+    pub fn set_shared_settings(&mut self, settings: SharedSettings) {
+        self.settings = settings;
+    }
+
+    // Shares global limits with other clients, e.g. all the torrents of a session.
+    pub fn set_global_limits(&mut self, global: LimiterPair) {
+        self.limits.set_global(global);
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    // Loads the IPs banned in earlier sessions, and keeps the list at `path`
+    // updated as more peers get banned.
+    pub fn load_ban_list(&mut self, path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        self.smart_ban = SmartBan::load(path)?;
+        Ok(())
+    }
+
+    pub fn banned_ips(&self) -> Vec<IpAddr> {
+        self.smart_ban.banned()
+    }
+
+    // Whether connections we open are encrypted. Incoming connections are
+    // handled by whoever accepts them.
+    pub fn set_encryption_policy(&mut self, policy: EncryptionPolicy) {
+        self.settings.set_encryption_policy(policy);
+    }
+
+    // How long to wait for a requested block, and how long a peer may go
+    // without sending us any data before it's considered to be snubbing us.
+    pub fn set_timeouts(&mut self, request_timeout: Duration, snub_timeout: Duration) {
+        self.request_timeout = request_timeout;
+        self.snub_timeout = snub_timeout;
+    }
+
+    pub fn is_connected(&self, peer_id: &String) -> bool {
+        self.connections.contains_key(peer_id)
+    }
+
+    // Adds HTTP servers to download pieces from, next to peers. Their URLs
+    // take the place of peer ids.
+    pub fn add_web_seeds(&mut self, seeds: &[WebSeed]) {
+        if self.settings.force_proxy() && !self.settings.proxy().is_some_and(|proxy| proxy.carries_web_seeds()) {
+            if !seeds.is_empty() {
+                warn!("Not using web seeds, they can't be reached through the proxy");
+            }
+            return;
+        }
+
+        if let Some(proxy) = self.settings.proxy().filter(|proxy| !proxy.carries_web_seeds()) {
+            if seeds.iter().any(|seed| !self.web_seeds.contains_key(&seed.url)) {
+                warn!("Reaching web seeds directly, proxy {} can't carry them", proxy);
+            }
+        }
+
+        for seed in seeds {
+            if !self.web_seeds.contains_key(&seed.url) {
+                debug!("Adding web seed: {}", seed.url);
+                self.web_seeds.insert(seed.url.clone(), WebSeedPeer::new(seed.clone()));
+            }
+        }
+    }
+
+    pub fn is_web_seed(&self, peer_id: &String) -> bool {
+        self.web_seeds.contains_key(peer_id)
+    }
+
+    pub fn is_snubbed(&self, peer_id: &String) -> bool {
+        self.peer_states.get(peer_id).is_some_and(|state| state.snubbed)
+    }
+
+    // Drops the connection and everything we know about the peer, handing
+    // its outstanding requests back to the picker.
+    pub fn disconnect(&mut self, peer_id: &String) {
+        if self.connections.remove(peer_id).is_some() {
+            debug!("Disconnected from peer: {}", peer_id);
+            self.connection_permits.remove(peer_id);
+            self.limits.remove_peer(peer_id);
+            self.stats.set_connected_peers(self.connections.len());
+            self.emit(ClientEvent::PeerDisconnected { peer_id: peer_id.clone() });
+        }
+
+        self.bitfields.remove(peer_id);
+        self.peer_states.remove(peer_id);
+        self.extensions.remove(peer_id);
+        self.peer_addrs.remove(peer_id);
+        self.incoming_peers.remove(peer_id);
+        self.pex.remove(peer_id);
+
+        if let Some(picker) = &mut self.picker {
+            picker.return_peer_requests(peer_id);
+        }
+    }
+
+    pub fn disconnect_all(&mut self) {
+        let peer_ids: Vec<String> = self.connections.keys().cloned().collect();
+
+        for peer_id in peer_ids {
+            self.disconnect(&peer_id);
+        }
+    }
+
+    fn acquire_connection_permit(&self) -> Result<Option<OwnedSemaphorePermit>, Box<dyn std::error::Error>> {
+        match &self.connection_limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err("Connection limit reached".into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn picker(&mut self, torrent: &Torrent) -> &mut PiecePicker {
+        let streaming = &self.streaming;
+
+        self.picker.get_or_insert_with(|| {
+            let mut picker = PiecePicker::new(torrent);
+            picker.set_streaming(streaming.clone());
+            picker
+        })
+    }
+
+    // Favours the pieces just ahead of the returned cursor, which the caller
+    // moves along as playback progresses. Pieces that miss their deadline are
+    // taken away from slow peers and asked of faster ones.
+    pub fn enable_streaming(&mut self, config: StreamingConfig) -> PlaybackCursor {
+        let streaming = Streaming::new(config);
+        let cursor = streaming.cursor.clone();
+
+        if let Some(picker) = &mut self.picker {
+            picker.set_streaming(Some(streaming.clone()));
+        }
+        self.streaming = Some(streaming);
+
+        cursor
+    }
+
+    // How many connected peers have each piece.
+    fn piece_availability(&self, torrent: &Torrent) -> Vec<u32> {
+        let mut availability = vec![0; torrent.get_num_pieces() as usize];
+
+        for bitfield in self.bitfields.values() {
+            for (piece_index, peers) in availability.iter_mut().enumerate() {
+                if has_piece(bitfield, piece_index) {
+                    *peers += 1;
+                }
+            }
+        }
+
+        availability
+    }
+
+    fn download_rate(&self, peer_id: &String) -> f64 {
+        if let Some(web_seed) = self.web_seeds.get(peer_id) {
+            return web_seed.download_rate();
+        }
+
+        self.peer_states.get(peer_id).map_or(0.0, |state| state.download_rate())
+    }
+
+    // Pieces connected peers suggested we download, e.g. because they have
+    // them cached.
+    fn suggested_pieces(&self) -> Vec<u32> {
+        let mut peer_ids: Vec<&String> = self.connections.keys().collect();
+        peer_ids.sort();
+
+        peer_ids
+            .into_iter()
+            .filter_map(|peer_id| self.peer_states.get(peer_id))
+            .flat_map(|state| state.suggested.iter().copied())
+            .collect()
+    }
+
+    // Whether another peer would get the piece to us sooner: a connected peer
+    // or web seed that has it and has been sending us data faster. Peers we
+    // aren't connected to don't count, since we know nothing about them and
+    // may have just dropped them for being slow.
+    fn has_faster_peer(&self, peer_id: &String, piece_index: u32) -> bool {
+        let rate = self.download_rate(peer_id);
+
+        self.connections.keys().chain(self.web_seeds.keys()).any(|other| {
+            other != peer_id
+                && !self.is_snubbed(other)
+                && (self.is_web_seed(other) || self.bitfields.get(other).is_some_and(|bitfield| has_piece(bitfield, piece_index as usize)))
+                && self.download_rate(other) > rate
+        })
+    }
+
+    // The socket to connect over once uTP is enabled, instead of one of our own.
+    pub fn set_utp_socket(&mut self, utp: Arc<UtpSocket>) {
+        self.utp = Some(utp);
+    }
+
+    // Connects to peers over uTP first, binding a socket for it on demand.
+    pub fn enable_utp(&mut self) {
+        self.settings.set_utp_enabled(true);
+    }
+
+    async fn utp_socket(&mut self) -> Option<Arc<UtpSocket>> {
+        if !self.settings.utp_enabled() {
+            return None;
+        }
+
+        if self.utp.is_none() {
+            match UtpSocket::bind(default_bind_addr(0)).await {
+                Ok(utp) => self.utp = Some(utp),
+                Err(err) => {
+                    warn!("Could not bind uTP socket, using TCP only: {}", err);
+                    self.settings.set_utp_enabled(false);
+                },
+            }
+        }
+
+        self.utp.clone()
+    }
+
+    // Uses an already running DHT node for peer discovery.
+    pub fn set_dht(&mut self, dht: Arc<DhtNode>) {
+        self.settings.set_dht(dht);
+    }
+
+    // Starts a DHT node on demand when the trackers don't give us any peers,
+    // keeping its routing table at `state_path` between runs.
+    pub fn enable_dht(&mut self, state_path: PathBuf) {
+        self.dht_state_path = Some(state_path);
+    }
+
+    pub fn set_dht_port(&mut self, port: u16) {
+        self.dht_port = Some(port);
+    }
+
+    async fn dht_node(&mut self) -> Result<Option<Arc<DhtNode>>, Box<dyn std::error::Error>> {
+        if self.settings.force_proxy() {
+            return Ok(None);
+        }
+        if let Some(dht) = self.settings.dht() {
+            return Ok(Some(dht));
+        }
+
+        let state_path = match &self.dht_state_path {
+            Some(state_path) => state_path.clone(),
+            _ => return Ok(None),
+        };
+
+        let dht = DhtNode::start(self.dht_port, &state_path).await?;
+        info!("Started DHT node on {} with {} known nodes", dht.local_addr()?, dht.num_nodes());
+
+        if dht.num_nodes() < K {
+            let bootstrap_nodes: Vec<String> = BOOTSTRAP_NODES.iter().map(|node| node.to_string()).collect();
+            dht.bootstrap(&bootstrap_nodes).await?;
+        }
+
+        self.settings.set_dht(dht.clone());
+        Ok(Some(dht))
+    }
+
+    // Uses an already running LSD service to find peers on the local network.
+    pub fn set_lsd(&mut self, lsd: Arc<LocalDiscovery>) {
+        self.settings.set_lsd(lsd);
+    }
+
+    // Announces public torrents on the local network, joining the LSD
+    // multicast group on demand.
+    pub fn enable_lsd(&mut self) {
+        self.lsd_enabled = true;
+    }
+
+    // Adds the peers found on the local network since we last looked, and
+    // returns them. Private torrents only get peers from their tracker, so
+    // they're never announced.
+    async fn receive_lsd_peers(&mut self, torrent: &Torrent) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let lsd_enabled = self.lsd_enabled || self.settings.lsd().is_some();
+        if !lsd_enabled || self.settings.force_proxy() || torrent.is_private() {
+            return Ok(vec![]);
+        }
+
+        let info_hash = torrent.info_hash()?;
+        if !self.lsd_peers.contains_key(&info_hash) {
+            if self.settings.lsd().is_none() {
+                match LocalDiscovery::bind(LSD_GROUP).await {
+                    Ok(lsd) => self.settings.set_lsd(lsd),
+                    Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                        warn!("Local peer discovery is unavailable: port {} is taken, probably by another client on this machine", LSD_GROUP.port());
+                        self.lsd_enabled = false;
+                        return Ok(vec![]);
+                    },
+                    Err(err) => {
+                        warn!("Could not join LSD multicast group, disabling local peer discovery: {}", err);
+                        self.lsd_enabled = false;
+                        return Ok(vec![]);
+                    },
+                }
+            }
+
+            let lsd = self.settings.lsd().unwrap();
+            let lsd_peers = lsd.add_torrent(&info_hash, self.listen_port).await;
+            self.lsd_peers.insert(info_hash.clone(), lsd_peers);
+        }
+
+        let mut found = vec![];
+        if let Some(lsd_peers) = self.lsd_peers.get_mut(&info_hash) {
+            while let Ok(peer) = lsd_peers.try_recv() {
+                found.push(peer.to_string());
+            }
+        }
+        self.add_peer_candidates(&found);
+
+        Ok(found)
+    }
+
+    // Gets peers from the tracker and the local network, falling back to the
+    // DHT for public torrents when the tracker is unreachable or has no peers
+    // for us.
+    pub async fn find_peers(&mut self, torrent: &Torrent) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let local_peers = self.receive_lsd_peers(torrent).await?;
+
+        let mut peers = match self.discover_peers(torrent).await {
+            Ok(peers) => peers,
+            Err(err) => {
+                warn!("Could not get peers from tracker: {}", err);
+                self.emit(ClientEvent::Error { message: format!("Could not get peers from tracker: {}", err) });
+                vec![]
+            },
+        };
+
+        if peers.is_empty() && !torrent.is_private() {
+            // The DHT is a fallback, so the local network and web seeds still
+            // get their chance when it can't be reached.
+            match self.dht_peers(torrent).await {
+                Ok(found) => peers.extend(found.iter().map(|addr| addr.to_string())),
+                Err(err) => {
+                    warn!("Could not get peers from the DHT: {}", err);
+                    self.emit(ClientEvent::Error { message: format!("Could not get peers from the DHT: {}", err) });
+                },
+            }
+        }
+        peers.extend(local_peers);
+
+        if peers.is_empty() {
+            let web_seeds = WebSeed::from_torrent(torrent);
+            if !web_seeds.is_empty() {
+                info!("No peers found, downloading from {} web seed(s)", web_seeds.len());
+                return Ok(peers);
+            }
+
+            return Err("Could not find any peers for torrent".into());
+        }
+
+        self.add_peer_candidates(&peers);
+        Ok(peers)
+    }
+
+    // Looks the torrent up in the DHT, announcing our port if we have one.
+    async fn dht_peers(&mut self, torrent: &Torrent) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+        let Some(dht) = self.dht_node().await? else {
+            return Ok(vec![]);
+        };
+
+        let info_hash = hex::decode(torrent.info_hash()?)?;
+        // Without a port to announce we only look peers up.
+        let found = match self.announced_port() {
+            Some(port) => dht.announce(&info_hash, port).await?,
+            None => dht.find_peers(&info_hash).await?,
+        };
+        info!("Found {} peers through the DHT", found.len());
+
+        if let Some(state_path) = &self.dht_state_path {
+            dht.save_state(state_path)?;
+        }
+
+        Ok(found)
+    }
+
+    // Peers we've heard about but aren't necessarily connected to.
+    pub fn peer_candidates(&self) -> Vec<String> {
+        let mut candidates: Vec<String> = self.peer_pool.iter().cloned().collect();
+        candidates.sort();
+
+        candidates
+    }
+
+    pub fn add_peer_candidates(&mut self, peers: &[String]) {
+        self.peer_pool.extend(peers.iter().cloned());
+    }
+
+    // Registers an extension we support, returning the id peers should use
+    // when sending us messages for it.
+    pub fn register_extension(&mut self, name: &str) -> u8 {
+        self.extension_registry.register(name)
+    }
+
+    // The extension handshake the peer sent us, if it supports the extension protocol.
+    pub fn peer_extensions(&self, peer_id: &String) -> Option<&ExtensionHandshake> {
+        self.extensions.get(peer_id)
+    }
+
+    // Announces to the torrent's trackers a tier at a time, returning the
+    // peers of the first tracker that answers.
+    pub async fn discover_peers(&mut self, torrent: &Torrent) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let info_hash = torrent.info_hash()?;
+        let tiers = self.trackers.entry(info_hash.clone()).or_insert_with(|| TrackerTiers::new(torrent)).clone();
+        if tiers.is_empty() {
+            return Err("Torrent has no trackers".into());
+        }
+
+        let mut last_error = String::new();
+        for tracker in tiers.tiers().iter().flatten() {
+            match self.announce(tracker, torrent).await {
+                Ok(peers) => {
+                    if let Some(tiers) = self.trackers.get_mut(&info_hash) {
+                        tiers.promote(tracker);
+                    }
+                    return Ok(peers);
+                },
+                Err(err) => {
+                    debug!("Could not announce to tracker {}: {}", tracker, err);
+                    last_error = err;
+                },
+            }
+        }
+
+        Err(last_error.into())
+    }
+
+    async fn announce(&self, tracker: &str, torrent: &Torrent) -> Result<Vec<String>, String> {
+        self.announce_to(tracker, torrent).await.map_err(|err| err.to_string())
+    }
+
+    async fn announce_to(&self, tracker: &str, torrent: &Torrent) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        if tracker.starts_with("udp://") {
+            return self.announce_to_udp(tracker, torrent).await;
+        }
+
         let mut params = vec![];
 
-        let mut announce_url = torrent.announce.clone();
+        let mut announce_url = tracker.to_string();
 
-        let info_hash = calculate_info_hash(&torrent.info)?;
+        let info_hash = torrent.info_hash()?;
         let decoded_info_hash = hex::decode(info_hash)?;
         let mut urlencoded_info_hash = "".to_string();
 
@@ -39,22 +774,26 @@ impl Client {
             urlencoded_info_hash += "%";
             urlencoded_info_hash += &hex::encode(vec![byte]);
         }
-        announce_url += &format!("?info_hash={}", urlencoded_info_hash);
+        // Private trackers often put a passkey in the query already.
+        let separator = if announce_url.contains('?') { '&' } else { '?' };
+        announce_url += &format!("{}info_hash={}", separator, urlencoded_info_hash);
 
         let peer_id = self.peer_id.clone();
         params.push(("peer_id", peer_id));
 
-        let port = "6881";
-        params.push(("port", port.to_string()));
+        if let Some(port) = self.announced_port() {
+            params.push(("port", port.to_string()));
+        }
 
-        let uploaded = 0;
-        params.push(("uploaded", uploaded.to_string()));
+        if let Some(ip) = self.announce_ip.or(self.external_addr().map(|addr| addr.ip())) {
+            params.push(("ip", ip.to_string()));
+        }
 
-        let downloaded = 0;
-        params.push(("downloaded", downloaded.to_string()));
+        let stats = self.stats.snapshot();
+        params.push(("uploaded", stats.uploaded.to_string()));
+        params.push(("downloaded", stats.downloaded.to_string()));
 
-        let left = torrent.info.length
-            .expect("Did not find length in torrent info.");
+        let left = self.bytes_left(torrent);
         params.push(("left", left.to_string()));
 
         let compact = 1;
@@ -62,202 +801,1103 @@ impl Client {
 
         let url_with_params = reqwest::Url::parse_with_params(&announce_url, params)?;
 
-        let response = reqwest::blocking::get(url_with_params)?;
-        let response_bytes = response.bytes()?;
+        let proxy = self.proxy_for_tracker(tracker, url_with_params.scheme())?;
+        let response_bytes = match &proxy {
+            // reqwest handles HTTP proxies, but SOCKS5 ones need a tunnel.
+            Some(proxy) if proxy.kind == ProxyKind::Socks5 => Bytes::from(proxy.http_get(&url_with_params).await?),
+            _ => self.settings.http().get(url_with_params).send().await?.error_for_status()?.bytes().await?,
+        };
 
-        let decoded_response = decode_announce_response(&response_bytes);
+        let decoded_response = decode_announce_response(&response_bytes)?;
 
-        const PEER_SIZE: usize = 6;
-        const IP_ADDR_SIZE: usize = 4;
-        let peer_count = decoded_response.peers.len() / PEER_SIZE;
+        let peers: Vec<String> = decode_compact_peers(&decoded_response.peers)
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
 
-        let mut peers = vec![];
+        self.emit(ClientEvent::TrackerResponse {
+            announce: tracker.to_string(),
+            peers: peers.len(),
+            interval: decoded_response.interval,
+        });
 
-        for idx in 0..peer_count {
-            let i = idx * PEER_SIZE;
+        Ok(peers)
+    }
 
-            let mut peer_endpoint = vec![];
+    async fn announce_to_udp(&self, tracker: &str, torrent: &Torrent) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let proxy = self.proxy_for_tracker(tracker, "udp")?;
 
-            for j in 0..IP_ADDR_SIZE {
-                peer_endpoint.push(decoded_response.peers[i + j].to_string());
+        let ip = match self.announce_ip.or(self.external_addr().map(|addr| addr.ip())) {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => None,
+        };
+        let stats = self.stats.snapshot();
+        let announce = UdpAnnounce {
+            info_hash: hex::decode(torrent.info_hash()?)?.try_into().map_err(|_| "Info hash is not 20 bytes")?,
+            peer_id: self.peer_id.as_bytes().try_into()?,
+            downloaded: stats.downloaded,
+            left: self.bytes_left(torrent),
+            uploaded: stats.uploaded,
+            ip,
+            // The port is required, and 0 says we can't be connected to.
+            port: self.announced_port().unwrap_or(0),
+        };
+        let response = announce_udp(tracker, &announce, proxy.as_ref()).await?;
 
-                if j != IP_ADDR_SIZE - 1 {
-                    peer_endpoint.push(".".to_string());
-                }
-            }
-            let port_byte0 = i + IP_ADDR_SIZE;
-            let port_byte1 = port_byte0 + 1;
+        let peers: Vec<String> = response.peers.iter().map(|addr| addr.to_string()).collect();
+        self.emit(ClientEvent::TrackerResponse {
+            announce: tracker.to_string(),
+            peers: peers.len(),
+            interval: Some(response.interval.into()),
+        });
 
-            let port = ((decoded_response.peers[port_byte0] as u16) << 8) | decoded_response.peers[port_byte1] as u16;
-            peer_endpoint.push(":".to_string());
-            peer_endpoint.push(port.to_string());
+        Ok(peers)
+    }
+
+    // The proxy to announce to the tracker through, if there's one that can
+    // carry it. Going around the proxy fails when it's forced, and is logged
+    // otherwise.
+    fn proxy_for_tracker(&self, tracker: &str, scheme: &str) -> Result<Option<Proxy>, Box<dyn std::error::Error>> {
+        let proxy = match self.settings.proxy() {
+            Some(proxy) => proxy,
+            None => return Ok(None),
+        };
+        if proxy.carries_tracker(scheme) {
+            return Ok(Some(proxy));
+        }
 
-            peers.push(peer_endpoint.join(""));
+        if self.settings.force_proxy() {
+            return Err(format!("Can't announce to {} through proxy {}", tracker, proxy).into());
         }
+        warn!("Announcing to {} directly, proxy {} can't carry {}:// trackers", tracker, proxy, scheme);
+        Ok(None)
+    }
 
-        return Ok(peers);
+    // Bytes of the pieces we still want, which is everything until we've
+    // started picking pieces.
+    fn bytes_left(&self, torrent: &Torrent) -> u64 {
+        match &self.picker {
+            Some(picker) => picker
+                .missing_pieces()
+                .iter()
+                .filter_map(|piece_index| torrent.get_piece_length(*piece_index as usize).ok())
+                .map(|length| length as u64)
+                .sum(),
+            None => torrent.total_length() as u64,
+        }
     }
 
     pub async fn peer_handshake(&mut self, peer_addr: &String, torrent: &Torrent) -> Result<PeerInfo, Box<dyn std::error::Error>> {
-        let mut stream = TcpStream::connect(peer_addr).await.expect("Failed to connect to peer.");
+        if let Ok(addr) = peer_addr.parse::<SocketAddr>() {
+            if self.smart_ban.is_banned(&addr.ip()) {
+                return Err(format!("Refusing to connect to banned peer {}", peer_addr).into());
+            }
+        }
+
+        let permit = self.acquire_connection_permit()?;
 
-        let info_hash_hex = calculate_info_hash(&torrent.info)?;
-        let decoded_info_hash = hex::decode(info_hash_hex).expect("Could not decode info hash");
+        let info_hash_hex = torrent.info_hash()?;
+        let decoded_info_hash = hex::decode(info_hash_hex)?;
         let message = self.get_handshake_message(&decoded_info_hash);
 
-        stream.write_all(&message).await.expect("Failed to send handshake message to stream.");
+        let mut stream = self.open_stream(peer_addr, &decoded_info_hash, &message).await?;
 
-        let mut buffer = [0; 1024];
-        stream.read(&mut buffer).await.expect("Failed to read peer reply from stream.");
+        let mut buffer = [0; PeerInfo::HANDSHAKE_LENGTH];
+        if let Err(err) = stream.read_exact(&mut buffer).await {
+            return Err(format!("Could not read handshake from peer {}: {}", peer_addr, err).into());
+        }
 
-        let peer_info = PeerInfo::from_bytes(Bytes::from(buffer.to_vec()), &decoded_info_hash).expect("Could not parse peer response.");
+        self.stats.add_overhead_uploaded(message.len() as u64);
+        self.stats.add_overhead_downloaded(buffer.len() as u64);
 
-        let peer_id = hex::encode(&peer_info.id);
+        let peer_info = PeerInfo::from_bytes(Bytes::from(buffer.to_vec()), &decoded_info_hash)?;
 
-        self.connections.insert(peer_id, stream);
+        self.register_peer(stream, &peer_info, torrent, permit).await?;
 
         return Ok(peer_info)
     }
 
-    async fn recv_message(&mut self, peer_id: &String) -> Result<PeerMessage, Box<dyn std::error::Error>> {
-        let stream = self.connections.get_mut(peer_id).expect("Did not find peer in active connections");
-        let mut reader = BufReader::new(stream);
+    // Tries uTP first if it's enabled, then TCP.
+    async fn connect(&mut self, peer_addr: &str) -> Result<Transport, Box<dyn std::error::Error>> {
+        if let Some(proxy) = self.settings.proxy() {
+            let addr: SocketAddr = peer_addr.parse()?;
+            return match timeout_at(Instant::now() + Self::CONNECT_TIMEOUT, proxy.connect(&addr.ip().to_string(), addr.port())).await {
+                Ok(stream) => Ok(Transport::Proxied(stream?, addr)),
+                Err(_) => Err(format!("Timed out connecting to peer {} through the proxy", peer_addr).into()),
+            };
+        }
 
-        PeerMessage::from_stream(& mut reader).await
+        if let (Some(utp), Ok(addr)) = (self.utp_socket().await, peer_addr.parse::<SocketAddr>()) {
+            match timeout_at(Instant::now() + Self::UTP_CONNECT_TIMEOUT, utp.connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(Transport::Utp(stream)),
+                Ok(Err(err)) => {
+                    debug!("uTP connection to {} failed ({}), trying TCP", peer_addr, err);
+                },
+                Err(_) => {
+                    debug!("uTP connection to {} timed out, trying TCP", peer_addr);
+                },
+            }
+        }
+
+        match timeout_at(Instant::now() + Self::CONNECT_TIMEOUT, TcpStream::connect(peer_addr)).await {
+            Ok(stream) => Ok(Transport::Tcp(stream?)),
+            Err(_) => Err(format!("Timed out connecting to peer {}", peer_addr).into()),
+        }
     }
 
-    async fn send_message(&mut self, peer_id: &String, message: &PeerMessage) -> Result<(), Box<dyn std::error::Error>> {
-        let stream = self.connections.get_mut(peer_id).expect("Did not find peer in active connections");
+    // Connects to the peer and sends our handshake, encrypted if the policy
+    // asks for it. With the prefer policy, peers that don't speak MSE are
+    // connected to again in plaintext.
+    async fn open_stream(&mut self, peer_addr: &str, info_hash: &[u8], handshake: &[u8]) -> Result<PeerStream, Box<dyn std::error::Error>> {
+        let policy = self.settings.encryption_policy();
+        if policy != EncryptionPolicy::Plaintext {
+            let stream = self.connect(peer_addr).await?;
+            let deadline = Instant::now() + Self::ENCRYPTED_HANDSHAKE_TIMEOUT;
+            let err = match timeout_at(deadline, mse::initiate(stream, info_hash, handshake, policy)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => err.to_string(),
+                Err(_) => "timed out".to_string(),
+            };
+
+            if policy == EncryptionPolicy::Require {
+                return Err(format!("Encrypted handshake with {} failed: {}", peer_addr, err).into());
+            }
+            debug!("Encrypted handshake with {} failed ({}), retrying in plaintext", peer_addr, err);
+        }
 
-        stream.write(&message.to_bytes()).await?;
-        return Ok(())
+        let mut stream = PeerStream::plaintext(self.connect(peer_addr).await?);
+        stream.write_all(handshake).await?;
+
+        Ok(stream)
     }
 
-    fn get_handshake_message(&self, info_hash: &Vec<u8>) -> Bytes {
-        let mut buf = BytesMut::with_capacity(1024);
-        buf.put_u8(19);
-        buf.put(&b"BitTorrent protocol"[..]);
-        buf.put_bytes(0, 8);
-        buf.put(info_hash.as_slice());
-        buf.put(self.peer_id.as_bytes());
+    // Takes over a connection from a peer that contacted us. Our side of the
+    // handshake has already been sent.
+    pub async fn accept_peer(&mut self, incoming: IncomingPeer, torrent: &Torrent) -> Result<PeerInfo, Box<dyn std::error::Error>> {
+        let addr = incoming.stream.peer_addr()?;
+        if self.settings.force_proxy() {
+            return Err(format!("Refusing connection from {}, only connecting through the proxy", addr).into());
+        }
+        if self.smart_ban.is_banned(&addr.ip()) {
+            return Err(format!("Refusing connection from banned peer {}", addr).into());
+        }
 
-        return buf.into();
+        let info_hash = hex::decode(torrent.info_hash()?)?;
+        if incoming.handshake.len() != PeerInfo::HANDSHAKE_LENGTH || incoming.handshake[28..48] != info_hash[..] {
+            return Err(format!("Peer {} sent a handshake for another torrent", addr).into());
+        }
+
+        // Our reply was sent by whoever accepted the connection.
+        self.stats.add_overhead_downloaded(incoming.handshake.len() as u64);
+        self.stats.add_overhead_uploaded(PeerInfo::HANDSHAKE_LENGTH as u64);
+
+        let peer_info = PeerInfo::from_bytes(incoming.handshake, &info_hash)?;
+        if self.is_connected(&hex::encode(&peer_info.id)) {
+            return Err(format!("Already connected to peer {}", addr).into());
+        }
+
+        self.register_peer(incoming.stream, &peer_info, torrent, incoming.permit).await?;
+        self.incoming_peers.insert(hex::encode(&peer_info.id));
+
+        Ok(peer_info)
     }
 
-    pub async fn download_piece<W: Write>(&mut self, piece_index: u32, torrent: &Torrent, peer_id: &String, out: &mut BufWriter<W>) -> Result<(), Box<dyn std::error::Error>>{
-        // 1. Get bitfield message, if present. Otherwise do the one-time
-        // initialization steps.
-        let bitfield = self.bitfields.get(peer_id);
+    async fn accept_incoming_peers(&mut self, torrent: &Torrent) {
+        let mut pending = vec![];
+        if let Some(incoming) = &mut self.incoming {
+            while let Ok(peer) = incoming.try_recv() {
+                pending.push(peer);
+            }
+        }
 
-        match bitfield {
-            Some(_) => {
-                debug!("Already have bitfield for this peer, proceeding.");
-            },
-            _ => {
-                debug!("Waiting for bitfield message and showing interest.");
-                let bitfield_message = self.recv_message(peer_id).await?;
-                match bitfield_message {
-                    PeerMessage::Bitfield(b) => {
-                        // TODO: Actually check if the bitfield message has the piece
-                        // index we've asked for, otherwise bail.
-                        info!("Received bitfield message from peer: {}", peer_id);
-                        self.bitfields.insert(peer_id.to_string(), b.to_vec());
-                    },
-                    _ => return Err(format!("Invalid peer message type received instead of bitfield message: ").into())
-                }
+        for peer in pending {
+            if let Err(err) = self.accept_peer(peer, torrent).await {
+                debug!("Dropping incoming connection: {}", err);
+            }
+        }
+    }
 
-                // Say that we're interested in this peer.
-                info!("Sending interested message to peer: {}", peer_id);
-                self.send_message(peer_id, &PeerMessage::Interested).await?;
-                info!("Sent interested message: {}", peer_id);
-                let mut proceed = false;
-
-                while !proceed {
-                    let unchoke_message = self.recv_message(peer_id).await?;
-
-                    match unchoke_message {
-                        PeerMessage::Unchoke => {
-                            info!("Received unchoke message from peer: {}", peer_id);
-                            proceed = true;
-                        },
-                        PeerMessage::Keepalive => {
-                            info!("Received keepalive message from peer: {}", peer_id);
-                        },
-                        _ => return Err(format!("Invalid peer message type received instead of unchoke message: ").into())
-                    }
-                }
+    async fn register_peer(&mut self, stream: PeerStream, peer_info: &PeerInfo, torrent: &Torrent, permit: Option<OwnedSemaphorePermit>) -> Result<(), Box<dyn std::error::Error>> {
+        let peer_id = hex::encode(&peer_info.id);
+        let addr = stream.peer_addr()?;
+
+        if let Some(permit) = permit {
+            self.connection_permits.insert(peer_id.clone(), permit);
+        }
+        self.connections.insert(peer_id.clone(), PeerConnection { stream, reader: MessageReader::default() });
+        self.peer_addrs.insert(peer_id.clone(), addr);
+        self.peer_pool.insert(addr.to_string());
+        self.stats.set_connected_peers(self.connections.len());
+
+        let supports_fast = peer_info.supports_fast_extension();
+        self.peer_states.insert(peer_id.clone(), PeerState { supports_fast, ..Default::default() });
+        self.emit(ClientEvent::PeerConnected {
+            peer_id: peer_id.clone(),
+            addr,
+            client: peer_info.client.as_ref().map(|client| client.to_string()),
+        });
+
+        // With the fast extension both sides must announce what they have, and we
+        // don't have anything yet.
+        if supports_fast {
+            self.send_message(&peer_id, &PeerMessage::HaveNone).await?;
+        }
+
+        if peer_info.supports_extension_protocol() {
+            debug!("Peer {} supports the extension protocol, sending extension handshake", peer_id);
+            self.send_extension_handshake(&peer_id, torrent, addr.ip()).await?;
+        }
+
+        // Peers running a DHT node can add ours to their routing table.
+        if peer_info.supports_dht() && !torrent.is_private() {
+            if let Some(dht) = self.dht() {
+                let port = dht.local_addr()?.port();
+                self.send_message(&peer_id, &PeerMessage::Port(port)).await?;
             }
         }
 
-        // We'll keep it simple and download the piece sequentially.
-        let num_blocks = torrent.get_num_blocks(piece_index.try_into().unwrap());
+        Ok(())
+    }
+
+    async fn send_extension_handshake(&mut self, peer_id: &String, torrent: &Torrent, peer_ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let yourip = match peer_ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
 
-        let mut piece_data: Vec<u8> = vec![];
+        let mut extensions = self.extension_registry.to_handshake_map();
+        if torrent.is_private() {
+            extensions.remove(PEX_EXTENSION_NAME);
+        }
 
-        for block_offset in 0..num_blocks {
-            debug!("Downloading block with offset: {}", block_offset);
-            let request_message = RequestMessage{
-                index: piece_index,
-                begin: (block_offset * Torrent::BLOCK_SIZE) as u32,
-                length: torrent.get_block_length(piece_index as usize, block_offset) as u32};
+        let handshake = ExtensionHandshake {
+            m: extensions,
+            v: Some(format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+            p: self.announced_port().map(|port| port.into()),
+            reqq: Some(Self::MAX_OUTSTANDING_REQUESTS),
+            metadata_size: Some(serde_bencode::to_bytes(&torrent.info)?.len() as i64),
+            yourip: Some(ByteBuf::from(yourip)),
+        };
 
-            debug!("Sending request for block with length: {}", request_message.length);
-            
-            self.send_message(peer_id, &PeerMessage::Request(request_message)).await?;
-            debug!("Sent request message for block with offset: {}", block_offset);
+        let message = PeerMessage::Extended(ExtendedMessage { id: EXTENDED_HANDSHAKE_ID, payload: handshake.to_bytes()? });
+        self.send_message(peer_id, &message).await
+    }
 
-            let mut block_pending= true;
+    // Sends a message for one of the extensions the peer told us it supports.
+    pub async fn send_extended_message(&mut self, peer_id: &String, name: &str, payload: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        let id = self.extensions
+            .get(peer_id)
+            .and_then(|handshake| handshake.extension_id(name))
+            .ok_or(format!("Peer {} does not support extension {}", peer_id, name))?;
 
-            while block_pending {
-                let message = self.recv_message(peer_id).await?;
+        self.send_message(peer_id, &PeerMessage::Extended(ExtendedMessage { id, payload })).await
+    }
+
+    // Tells every connected peer that supports PEX about the peers we're
+    // connected to, at most once per PEX interval. Peers we can't send to are
+    // disconnected rather than failing the download.
+    pub async fn send_pex_updates(&mut self, torrent: &Torrent) {
+        if torrent.is_private() {
+            return;
+        }
+
+        let peer_ids: Vec<String> = self.connections.keys().cloned().collect();
+
+        for peer_id in peer_ids {
+            let supports_pex = self.extensions
+                .get(&peer_id)
+                .and_then(|handshake| handshake.extension_id(PEX_EXTENSION_NAME))
+                .is_some();
+            let state = self.pex.entry(peer_id.clone()).or_default();
+
+            if !supports_pex || !state.is_due() {
+                continue;
+            }
+
+            let connected: Vec<PexPeer> = self.peer_addrs
+                .iter()
+                .filter(|(id, _)| **id != peer_id)
+                .filter_map(|(id, addr)| {
+                    let listen_port = self.extensions.get(id).and_then(|handshake| handshake.p);
+                    advertised_peer(*addr, self.incoming_peers.contains(id), listen_port)
+                })
+                .collect();
+
+            let Some(message) = state.next_message(&connected) else {
+                continue;
+            };
+            debug!("Sending PEX message to peer: {}", peer_id);
+            let payload = match message.to_bytes() {
+                Ok(payload) => payload,
+                Err(err) => {
+                    warn!("Could not encode PEX message for peer {}: {}", peer_id, err);
+                    continue;
+                },
+            };
+            if let Err(err) = self.send_extended_message(&peer_id, PEX_EXTENSION_NAME, payload).await {
+                warn!("Could not send PEX message to peer {}: {}", peer_id, err);
+                self.disconnect(&peer_id);
+            }
+        }
+    }
+
+    fn handle_pex_message(&mut self, peer_id: &String, torrent: &Torrent, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if torrent.is_private() {
+            warn!("Ignoring PEX message for private torrent from peer: {}", peer_id);
+            return Ok(());
+        }
+
+        let message = PexMessage::from_bytes(payload)?;
+        let added = message.added_peers();
+        debug!("Received {} peers through PEX from peer: {}", added.len(), peer_id);
+
+        for peer in added {
+            self.peer_pool.insert(peer.addr.to_string());
+        }
+        for addr in message.dropped_peers() {
+            let addr = addr.to_string();
+            let connected = self.peer_addrs.values().any(|connected| connected.to_string() == addr);
+
+            if !connected {
+                self.peer_pool.remove(&addr);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn peer_state(&mut self, peer_id: &String) -> &mut PeerState {
+        self.peer_states.entry(peer_id.to_string()).or_default()
+    }
+
+    // Book-keeping for messages that arrive while we're waiting on something else.
+    async fn handle_message(&mut self, peer_id: &String, torrent: &Torrent, message: PeerMessage) -> Result<(), Box<dyn std::error::Error>> {
+        match message {
+            PeerMessage::Keepalive => {
+                debug!("Received keepalive message from peer: {}", peer_id);
+            },
+            PeerMessage::Choke => {
+                debug!("Peer {} choked us", peer_id);
+                self.peer_state(peer_id).choked = true;
+            },
+            PeerMessage::Unchoke => {
+                debug!("Peer {} unchoked us", peer_id);
+                self.peer_state(peer_id).choked = false;
+            },
+            PeerMessage::Bitfield(b) => {
+                debug!("Received bitfield message from peer: {}", peer_id);
+                self.bitfields.insert(peer_id.to_string(), b.to_vec());
+            },
+            PeerMessage::Have(piece_index) => {
+                let bitfield = self.bitfields.entry(peer_id.to_string()).or_default();
+                set_piece(bitfield, piece_index as usize);
+            },
+            PeerMessage::HaveAll => {
+                debug!("Peer {} has all pieces", peer_id);
+                let num_pieces = torrent.get_num_pieces() as usize;
+                let mut bitfield = vec![0; num_pieces.div_ceil(8)];
+                for piece_index in 0..num_pieces {
+                    set_piece(&mut bitfield, piece_index);
+                }
+                self.bitfields.insert(peer_id.to_string(), bitfield);
+            },
+            PeerMessage::HaveNone => {
+                debug!("Peer {} has no pieces", peer_id);
+                self.bitfields.insert(peer_id.to_string(), vec![]);
+            },
+            PeerMessage::AllowedFast(piece_index) => {
+                debug!("Peer {} allows fast requests for piece {}", peer_id, piece_index);
+                self.peer_state(peer_id).allowed_fast.insert(piece_index);
+            },
+            PeerMessage::Port(port) => {
+                debug!("Peer {} runs a DHT node on port {}", peer_id, port);
+                let addr = self.peer_addrs.get(peer_id).map(|addr| SocketAddr::new(addr.ip(), port));
+                if let (Some(dht), Some(addr), false) = (self.dht(), addr, torrent.is_private()) {
+                    // A node that answers the ping is added to the routing table.
+                    tokio::spawn(async move {
+                        if let Err(err) = dht.ping(addr).await {
+                            debug!("DHT node {} did not answer ping: {}", addr, err);
+                        }
+                    });
+                }
+            },
+            PeerMessage::SuggestPiece(piece_index) => {
+                debug!("Peer {} suggests piece {}", peer_id, piece_index);
+                let state = self.peer_state(peer_id);
+                if !state.suggested.contains(&piece_index) {
+                    state.suggested.push_back(piece_index);
+                }
+            },
+            PeerMessage::Extended(ext) if ext.id == EXTENDED_HANDSHAKE_ID => {
+                let handshake = ExtensionHandshake::from_bytes(&ext.payload)?;
+                debug!("Received extension handshake from peer {}: {:?}", peer_id, handshake.m);
 
-                match message {
-                    PeerMessage::Piece(piece) => {
-                        debug!("Received peice message for block offset: {}", block_offset);
-                        debug!("Got piece with index: {} begin: {}", piece.index, piece.begin);
-                        piece_data.extend(&piece.piece);
-                        block_pending = false;
+                match self.extensions.get_mut(peer_id) {
+                    Some(existing) => existing.merge(handshake),
+                    None => {
+                        self.extensions.insert(peer_id.to_string(), handshake);
+                    },
+                }
+            },
+            PeerMessage::Extended(ext) => {
+                match self.extension_registry.name(ext.id) {
+                    Some(PEX_EXTENSION_NAME) => {
+                        self.handle_pex_message(peer_id, torrent, &ext.payload)?;
+                    },
+                    Some(name) => {
+                        debug!("Ignoring {} extension message from peer: {}", name, peer_id);
+                    },
+                    None => {
+                        warn!("Received unknown extension message {} from peer: {}", ext.id, peer_id);
                     },
-                    _ => {}
                 }
+            },
+            PeerMessage::Request(request) => {
+                // We don't upload, so the peer stays choked. With the fast
+                // extension it has to be told its request won't be served.
+                debug!("Peer {} requested piece {} block at offset {}", peer_id, request.index, request.begin);
+                if self.peer_state(peer_id).supports_fast {
+                    self.send_message(peer_id, &PeerMessage::RejectRequest(request)).await?;
+                }
+            },
+            PeerMessage::HashRequest(request) => {
+                let reply = match self.answer_hash_request(torrent, &request) {
+                    Some(hashes) => PeerMessage::Hashes(hashes),
+                    None => PeerMessage::HashReject(request),
+                };
+                self.send_message(peer_id, &reply).await?;
+            },
+            PeerMessage::Hashes(hashes) => self.hashes_received(peer_id, torrent, hashes),
+            PeerMessage::HashReject(request) => {
+                debug!("Peer {} rejected hash request for {}", peer_id, hex::encode(request.pieces_root));
+            },
+            message => {
+                debug!("Ignoring message {} from peer: {}", message.to_u8(), peer_id);
+            },
+        }
+
+        Ok(())
+    }
+
+    // Files of a v2 torrent whose piece layer we still need, with the number
+    // of pieces they have.
+    fn missing_piece_layers(&self, torrent: &Torrent) -> Vec<(merkle::Hash, usize)> {
+        let piece_length = torrent.info.piece_length as u64;
+
+        torrent
+            .v2_files()
+            .into_iter()
+            .filter(|file| file.length > piece_length)
+            .filter_map(|file| file.pieces_root.map(|root| (root, file.length.div_ceil(piece_length) as usize)))
+            .filter(|(root, _)| torrent.piece_layer(root, &self.piece_layers).is_none())
+            .collect()
+    }
+
+    // Asks the peer for the piece layers the torrent doesn't include.
+    async fn request_piece_layers(&mut self, peer_id: &String, torrent: &Torrent) -> Result<(), Box<dyn std::error::Error>> {
+        let base_layer = merkle::piece_layer_height(torrent.info.piece_length as usize);
+
+        for (pieces_root, num_pieces) in self.missing_piece_layers(torrent) {
+            // Chunks we already have don't need asking for again.
+            let partial = self.partial_piece_layers.get(&pieces_root);
+            let requests: Vec<HashRequest> = HashRequest::piece_layer_requests(pieces_root, base_layer, num_pieces)
+                .into_iter()
+                .filter(|request| partial.is_none_or(|layer| layer[request.index as usize].is_none()))
+                .collect();
+
+            for request in requests {
+                self.send_message(peer_id, &PeerMessage::HashRequest(request)).await?;
             }
         }
 
-        // Checksum
-        let mut hasher = Sha1::new();
-        hasher.update(&piece_data);
-        let sha1_hash = format!("{:x}", hasher.finalize());
+        Ok(())
+    }
 
-        if sha1_hash != torrent.get_piece_sha(piece_index as usize) {
-            return Err(format!("Mismatch while error checking SHA1 checksum").into())
+    // We only keep piece layers, so those are all we can hand out.
+    fn answer_hash_request(&self, torrent: &Torrent, request: &HashRequest) -> Option<HashesMessage> {
+        let piece_length = torrent.info.piece_length as usize;
+        let height = merkle::piece_layer_height(piece_length);
+        if request.base_layer != height {
+            return None;
         }
-        
+
+        let layer = merkle::split_hashes(torrent.piece_layer(&request.pieces_root, &self.piece_layers)?);
+        let (mut hashes, proof) = merkle::layer_hashes(
+            &layer,
+            merkle::pad_hash(height),
+            request.index as usize,
+            request.length as usize,
+            request.proof_layers as usize,
+        )?;
+        hashes.extend(proof);
+
+        Some(HashesMessage { request: *request, hashes })
+    }
+
+    // Keeps a chunk of a piece layer if it hashes up to the file's pieces
+    // root, and the whole layer once every chunk is in.
+    fn hashes_received(&mut self, peer_id: &String, torrent: &Torrent, message: HashesMessage) {
+        let request = message.request;
+        let num_pieces = match self.missing_piece_layers(torrent).into_iter().find(|(root, _)| *root == request.pieces_root) {
+            Some((_, num_pieces)) => num_pieces,
+            None => return,
+        };
+
+        let width = num_pieces.next_power_of_two();
+        let height = merkle::piece_layer_height(torrent.info.piece_length as usize);
+        let index = request.index as usize;
+        let length = request.length as usize;
+        let in_layer = request.base_layer == height && length > 0 && index < num_pieces && message.hashes.len() >= length;
+
+        // The uncles have to go all the way up to the root, which
+        // `verify_hashes` checks.
+        let (hashes, proof) = message.hashes.split_at(length.min(message.hashes.len()));
+        if !in_layer || !merkle::verify_hashes(&request.pieces_root, width, hashes, index, proof) {
+            warn!("Ignoring hashes from peer {} that don't match the piece layer", peer_id);
+            return;
+        }
+
+        let layer = self.partial_piece_layers.entry(request.pieces_root).or_insert_with(|| vec![None; num_pieces]);
+        let end = (index + length).min(num_pieces);
+        for (slot, hash) in layer[index..end].iter_mut().zip(hashes) {
+            *slot = Some(*hash);
+        }
+
+        if layer.iter().all(|hash| hash.is_some()) {
+            debug!("Received piece layer for {} from peer {}", hex::encode(request.pieces_root), peer_id);
+            let layer: Vec<u8> = layer.iter().flatten().flatten().copied().collect();
+            self.partial_piece_layers.remove(&request.pieces_root);
+            self.piece_layers.insert(ByteBuf::from(request.pieces_root.to_vec()), ByteBuf::from(layer));
+        }
+    }
+
+    // Cancel safe, so it can be raced against timeouts. Callers throttle the
+    // message with `throttle_received` once they have it, outside the timeout.
+    async fn recv_message(&mut self, peer_id: &String) -> Result<PeerMessage, Box<dyn std::error::Error>> {
+        let connection = self.connections
+            .get_mut(peer_id)
+            .ok_or(format!("Peer {} is not connected", peer_id))?;
+
+        let message = connection.reader.read_message(&mut connection.stream).await?;
+
+        let overhead = (message.wire_length() - message.payload_length()) as u64;
+        self.stats.add_overhead_downloaded(overhead);
+
+        Ok(message)
+    }
+
+    // Waiting here holds off reading the next message, which slows the peer
+    // down through TCP flow control.
+    async fn throttle_received(&self, peer_id: &str, message: &PeerMessage) {
+        let payload = message.payload_length() as u64;
+        let overhead = (message.wire_length() - message.payload_length()) as u64;
+        self.limits.throttle(peer_id, Direction::Download, payload, overhead).await;
+    }
+
+    async fn send_message(&mut self, peer_id: &String, message: &PeerMessage) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.is_connected(peer_id) {
+            return Err(format!("Peer {} is not connected", peer_id).into());
+        }
+
+        let payload = message.payload_length() as u64;
+        let overhead = (message.wire_length() - message.payload_length()) as u64;
+        self.limits.throttle(peer_id, Direction::Upload, payload, overhead).await;
+
+        let connection = self.connections
+            .get_mut(peer_id)
+            .ok_or(format!("Peer {} is not connected", peer_id))?;
+
+        connection.stream.write_all(&message.to_bytes()).await?;
+        self.stats.add_overhead_uploaded(overhead);
+
+        if let PeerMessage::Piece(piece) = message {
+            self.stats.add_uploaded(piece.piece.len() as u64);
+            self.emit(ClientEvent::BytesTransferred { peer_id: peer_id.clone(), downloaded: 0, uploaded: piece.piece.len() as u64 });
+        }
+
+        Ok(())
+    }
+
+    // Whether our handshakes advertise the DHT: when we run a node, or will
+    // once trackers come up short.
+    pub fn advertises_dht(&self) -> bool {
+        let dht_enabled = self.settings.dht().is_some() || self.dht_state_path.is_some();
+
+        dht_enabled && !self.settings.force_proxy()
+    }
+
+    fn get_handshake_message(&self, info_hash: &[u8]) -> Bytes {
+        dht_handshake_message(&self.peer_id, info_hash, self.advertises_dht())
+    }
+
+    pub async fn download_piece<W: Write>(&mut self, piece_index: u32, torrent: &Torrent, peer_id: &String, out: &mut BufWriter<W>) -> Result<(), Box<dyn std::error::Error>>{
+        let piece_data = match self.fetch_piece(piece_index, torrent, peer_id).await {
+            Ok(piece_data) => piece_data,
+            Err(err) => {
+                // Requests that are still in flight go back to the picker so
+                // they can be asked of someone else.
+                self.picker(torrent).return_peer_requests(peer_id);
+                return Err(err);
+            },
+        };
+
         // Store piece in the output location.
         out.write_all(&piece_data)?;
 
         Ok(())
     }
 
-    pub async fn download_file<W: Write>(&mut self, torrent: &Torrent, peer_id: &String, out: &mut BufWriter<W>) -> Result<(), Box<dyn std::error::Error>> {
-        let num_pieces = torrent.get_num_pieces() as u32;
+    // Gets the peer's bitfield, if we don't have it yet, and shows interest.
+    async fn prepare_peer(&mut self, torrent: &Torrent, peer_id: &String) -> Result<(), Box<dyn std::error::Error>> {
+        if self.bitfields.contains_key(peer_id) {
+            debug!("Already have bitfield for this peer, proceeding.");
+            return Ok(());
+        }
+
+        debug!("Waiting for bitfield message and showing interest.");
 
-        for piece_index in 0..num_pieces {
-            info!("Downloading piece: {}", piece_index);
+        while !self.bitfields.contains_key(peer_id) {
+            let message = match timeout_at(Instant::now() + self.request_timeout, self.recv_message(peer_id)).await {
+                Ok(Ok(message)) => message,
+                Ok(Err(err)) => {
+                    self.disconnect(peer_id);
+                    return Err(err);
+                },
+                Err(_) => {
+                    self.disconnect(peer_id);
+                    return Err(format!("Timed out waiting for bitfield from peer {}", peer_id).into());
+                },
+            };
+            self.throttle_received(peer_id, &message).await;
 
-            let mut piece_done = false;
-            while !piece_done {
-                match self.download_piece(piece_index, torrent, peer_id, out).await {
-                    Ok(_) => {
-                        info!("Downloaded piece: {}", piece_index);
-                        piece_done = true;
-                    },
-                    Err(_) => {
-                        warn!("Failed to download piece: {}, trying again", piece_index);
+            match message {
+                PeerMessage::Piece(_) | PeerMessage::Request(_) => {
+                    return Err("Invalid peer message type received instead of bitfield message".into())
+                },
+                message => self.handle_message(peer_id, torrent, message).await?,
+            }
+        }
+        // TODO: Actually check if the bitfield message has the piece
+        // index we've asked for, otherwise bail.
+        debug!("Received bitfield from peer: {}", peer_id);
+
+        // Say that we're interested in this peer.
+        debug!("Sending interested message to peer: {}", peer_id);
+        self.send_message(peer_id, &PeerMessage::Interested).await?;
+
+        if torrent.is_v2() {
+            self.request_piece_layers(peer_id, torrent).await?;
+        }
+
+        Ok(())
+    }
+
+    // Downloads and verifies a single piece from the peer. Requests that time
+    // out are cancelled and re-queued, rejected requests are re-queued
+    // straight away, and blocks in an allowed fast piece can be requested
+    // even while choked.
+    async fn fetch_piece(&mut self, piece_index: u32, torrent: &Torrent, peer_id: &String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.prepare_peer(torrent, peer_id).await?;
+
+        let num_blocks = torrent.get_num_blocks(piece_index as usize)?;
+        let peer_ip = self.peer_addrs
+            .get(peer_id)
+            .map(|addr| addr.ip())
+            .ok_or(format!("Peer {} is not connected", peer_id))?;
+        self.picker(torrent).start_piece(torrent, piece_index)?;
+
+        let mut blocks: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+
+        while blocks.len() < num_blocks {
+            while self.picker(torrent).outstanding_requests(peer_id) < Self::PIPELINE_DEPTH
+                && self.peer_state(peer_id).can_request(piece_index)
+            {
+                let request = match self.picker(torrent).next_request(piece_index, peer_id) {
+                    Some(request) => request,
+                    None => break,
+                };
+
+                debug!("Sending request for block at offset {} with length: {}", request.begin, request.length);
+                if let Err(err) = self.send_message(peer_id, &PeerMessage::Request(request)).await {
+                    self.disconnect(peer_id);
+                    return Err(err);
+                }
+            }
+
+            // Wake up for the first request to time out or, if we're choked
+            // and have nothing in flight, when the peer counts as snubbing us.
+            let request_timeout = self.request_timeout;
+            let mut deadline = match self.picker(torrent).next_deadline(peer_id, request_timeout) {
+                Some(deadline) => Instant::from_std(deadline),
+                None => Instant::from_std(self.peer_state(peer_id).last_data + self.snub_timeout),
+            };
+
+            // When streaming, also wake up when the piece is due, to hand it
+            // to a faster peer if this one is too slow. That's only done once.
+            let piece_deadline = match self.picker(torrent).deadline(piece_index) {
+                Some(piece_deadline) if !self.escalated.contains(&piece_index) => Some(Instant::from_std(piece_deadline)),
+                _ => None,
+            };
+            if let Some(piece_deadline) = piece_deadline {
+                deadline = deadline.min(piece_deadline);
+            }
+
+            let message = match timeout_at(deadline, self.recv_message(peer_id)).await {
+                Ok(Ok(message)) => Some(message),
+                Ok(Err(err)) => {
+                    self.disconnect(peer_id);
+                    return Err(err);
+                },
+                Err(_) => None,
+            };
+
+            let message = match message {
+                Some(message) => message,
+                None => {
+                    if piece_deadline.is_some_and(|piece_deadline| piece_deadline <= Instant::now()) {
+                        self.escalated.insert(piece_index);
+
+                        if self.has_faster_peer(peer_id, piece_index) {
+                            warn!("Piece {} missed its deadline, moving it from peer {} to a faster peer", piece_index, peer_id);
+
+                            for request in self.picker(torrent).return_peer_requests(peer_id) {
+                                self.send_message(peer_id, &PeerMessage::Cancel(request)).await?;
+                            }
+
+                            return Err(Box::new(DownloadError::DeadlineMissed(piece_index)));
+                        }
+                    }
+
+                    for request in self.picker(torrent).timed_out_requests(peer_id, request_timeout) {
+                        warn!("Request for piece {} block at offset {} timed out", request.index, request.begin);
+                        self.picker(torrent).return_request(request);
+                        self.send_message(peer_id, &PeerMessage::Cancel(request)).await?;
+                    }
+
+                    let snub_timeout = self.snub_timeout;
+                    if self.peer_state(peer_id).check_snubbed(snub_timeout) {
+                        warn!("Peer {} is snubbing us, disconnecting", peer_id);
+                        self.disconnect(peer_id);
+                        return Err(format!("Peer {} sent no data for {:?}", peer_id, snub_timeout).into());
+                    }
+
+                    continue;
+                },
+            };
+            self.throttle_received(peer_id, &message).await;
+
+            match message {
+                PeerMessage::Piece(piece) if piece.index == piece_index => {
+                    debug!("Got piece with index: {} begin: {}", piece.index, piece.begin);
+
+                    if self.picker(torrent).block_received(piece.index, piece.begin, peer_id) {
+                        self.peer_state(peer_id).data_received(piece.piece.len() as u64);
+                        self.stats.add_downloaded(piece.piece.len() as u64);
+                        self.emit(ClientEvent::BytesTransferred { peer_id: peer_id.clone(), downloaded: piece.piece.len() as u64, uploaded: 0 });
+                        blocks.insert(piece.begin, piece.piece);
+                    }
+                },
+                PeerMessage::RejectRequest(request) if request.index == piece_index => {
+                    debug!("Peer {} rejected request for block at offset {}", peer_id, request.begin);
+                    self.picker(torrent).return_request(request);
+                },
+                PeerMessage::Choke => {
+                    self.handle_message(peer_id, torrent, PeerMessage::Choke).await?;
+
+                    // Without the fast extension, a choke silently drops all pending requests.
+                    if !self.peer_state(peer_id).supports_fast {
+                        self.picker(torrent).return_peer_requests(peer_id);
                     }
+                },
+                message => self.handle_message(peer_id, torrent, message).await?,
+            }
+        }
+
+        // Keep track of who sent which block, so that whoever sent corrupt data can be banned.
+        let senders: Vec<(u32, IpAddr, &[u8])> = blocks
+            .iter()
+            .map(|(begin, data)| (*begin, peer_ip, data.as_slice()))
+            .collect();
+        let piece_data: Vec<u8> = blocks.values().flatten().copied().collect();
+
+        if !self.verify_piece(torrent, piece_index, &piece_data) {
+            self.smart_ban.piece_failed(piece_index, &senders);
+            return Err("Mismatch while checking the piece hash".into())
+        }
+
+        for ip in self.smart_ban.piece_passed(piece_index, &senders)? {
+            let offenders: Vec<String> = self.peer_addrs
+                .iter()
+                .filter(|(_, addr)| addr.ip() == ip)
+                .map(|(offender, _)| offender.clone())
+                .collect();
+
+            for offender in offenders {
+                self.disconnect(&offender);
+            }
+        }
+
+        self.piece_completed(torrent, piece_index, peer_id);
+
+        Ok(piece_data)
+    }
+
+    fn verify_piece(&self, torrent: &Torrent, piece_index: u32, data: &[u8]) -> bool {
+        torrent.verify_piece(piece_index, data, &self.piece_layers)
+    }
+
+    fn piece_completed(&mut self, torrent: &Torrent, piece_index: u32, peer_id: &str) {
+        self.picker(torrent).piece_done(piece_index);
+        self.stats.piece_verified(torrent.get_piece_length(piece_index as usize).unwrap_or_default() as u64);
+        self.emit(ClientEvent::PieceVerified { piece_index, peer_id: peer_id.to_string() });
+    }
+
+    async fn fetch_from(&mut self, piece_index: u32, torrent: &Torrent, peer_id: &String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if self.is_web_seed(peer_id) {
+            self.fetch_web_seed_piece(piece_index, torrent, peer_id).await
+        } else {
+            self.fetch_piece(piece_index, torrent, peer_id).await
+        }
+    }
+
+    // Fetches a whole piece from a web seed, which is then verified like one
+    // from a peer.
+    async fn fetch_web_seed_piece(&mut self, piece_index: u32, torrent: &Torrent, url: &String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let seed = self.web_seeds
+            .get(url)
+            .map(|web_seed| web_seed.seed.clone())
+            .ok_or(format!("No web seed {}", url))?;
+
+        let started = Instant::now();
+        let piece_data = match seed.fetch_piece(&self.settings.http(), torrent, piece_index, self.request_timeout).await {
+            Ok(piece_data) => piece_data,
+            Err(err) => {
+                if err.downcast_ref::<RangesIgnored>().is_some() {
+                    warn!("{}, no longer using it", err);
+                    self.web_seeds.remove(url);
                 }
+                return Err(err.to_string().into());
+            },
+        };
+
+        let length = piece_data.len() as u64;
+        if let Some(web_seed) = self.web_seeds.get_mut(url) {
+            web_seed.data_received(length, started.elapsed());
+        }
+        self.stats.add_downloaded(length);
+        self.emit(ClientEvent::BytesTransferred { peer_id: url.clone(), downloaded: length, uploaded: 0 });
+        self.limits.throttle(url, Direction::Download, length, 0).await;
+
+        if !self.verify_piece(torrent, piece_index, &piece_data) {
+            return Err("Mismatch while checking the piece hash".into());
+        }
+
+        self.piece_completed(torrent, piece_index, url);
+
+        Ok(piece_data)
+    }
+
+    // Picks a connected peer that has the piece and hasn't used up its
+    // attempts at it, preferring peers that haven't tried it yet. If every
+    // connected peer has already had a go, more candidates from the peer pool
+    // get connected first.
+    async fn select_peer(&mut self, torrent: &Torrent, piece_index: u32, attempts: &HashMap<String, u32>) -> Result<String, Box<dyn std::error::Error>> {
+        self.accept_incoming_peers(torrent).await;
+        if let Err(err) = self.receive_lsd_peers(torrent).await {
+            debug!("Could not look for peers on the local network: {}", err);
+        }
+
+        let max_attempts_per_peer = self.retry_policy.max_attempts_per_peer;
+        let usable = |client: &Client, peer_id: &String| {
+            attempts.get(peer_id).copied().unwrap_or(0) < max_attempts_per_peer
+                && !client.is_snubbed(peer_id)
+                && client.bitfields.get(peer_id).is_none_or(|bitfield| has_piece(bitfield, piece_index as usize))
+        };
+
+        // Peers we've tried the least first, and the fastest among those.
+        let mut connected: Vec<String> = self.connections.keys().chain(self.web_seeds.keys()).cloned().collect();
+        connected.sort_by(|a, b| {
+            let attempts_a = attempts.get(a).copied().unwrap_or(0);
+            let attempts_b = attempts.get(b).copied().unwrap_or(0);
+
+            attempts_a.cmp(&attempts_b).then(self.download_rate(b).total_cmp(&self.download_rate(a)))
+        });
+
+        let best = connected.iter().find(|peer_id| usable(self, peer_id)).cloned();
+        if let Some(peer_id) = &best {
+            if !attempts.contains_key(peer_id) {
+                return Ok(peer_id.clone());
+            }
+        }
+
+        let connected_addrs: HashSet<String> = self.peer_addrs.values().map(|addr| addr.to_string()).collect();
+        let candidates: Vec<String> = self.peer_candidates()
+            .into_iter()
+            .filter(|addr| !connected_addrs.contains(addr))
+            .collect();
+
+        for addr in candidates {
+            debug!("Initiating handshake with peer: {}", addr);
+
+            match self.peer_handshake(&addr, torrent).await {
+                Ok(peer_info) => {
+                    let peer_id = hex::encode(&peer_info.id);
+
+                    if usable(self, &peer_id) {
+                        return Ok(peer_id);
+                    }
+                },
+                Err(err) => {
+                    debug!("Could not connect to peer {}: {}", addr, err);
+                    self.peer_pool.remove(&addr);
+                },
             }
         }
 
+        match best {
+            Some(peer_id) if self.is_connected(&peer_id) || self.is_web_seed(&peer_id) => Ok(peer_id),
+            _ => Err(format!("No peers left to download piece {} from", piece_index).into()),
+        }
+    }
+
+    // Downloads and verifies a piece, retrying with backoff and moving on to
+    // other peers when it fails, until the retry policy gives up.
+    async fn download_piece_with_retries(&mut self, piece_index: u32, torrent: &Torrent) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut attempts: HashMap<String, u32> = HashMap::new();
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let peer_id = self.select_peer(torrent, piece_index, &attempts).await?;
+
+            // Only the message is kept, so nothing that isn't Send is held
+            // across the backoff below.
+            let (err, escalated) = match self.fetch_from(piece_index, torrent, &peer_id).await {
+                Ok(piece_data) => return Ok(piece_data),
+                Err(err) => {
+                    let escalated = matches!(err.downcast_ref::<DownloadError>(), Some(DownloadError::DeadlineMissed(_)));
+                    (err.to_string(), escalated)
+                },
+            };
+
+            self.picker(torrent).return_peer_requests(&peer_id);
+            *attempts.entry(peer_id.clone()).or_default() += 1;
+            self.emit(ClientEvent::PieceFailed { piece_index, peer_id: peer_id.clone(), reason: err.clone() });
+
+            if attempt >= self.retry_policy.max_attempts_per_piece {
+                return Err(format!("Giving up on piece {} after {} attempts: {}", piece_index, attempt, err).into());
+            }
+
+            // Escalated pieces are urgent, and the next peer is a different one anyway.
+            if escalated {
+                continue;
+            }
+
+            let backoff = self.retry_policy.backoff(attempt);
+            warn!("Failed to download piece {} from peer {}: {}, retrying in {:?}", piece_index, peer_id, err, backoff);
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    // Downloads every piece, writing each one at its offset in the output.
+    // Pieces that can't be downloaded don't stop the others, but are
+    // reported in a `DownloadError::MissingPieces` at the end.
+    pub async fn download_file<W: Write + Seek>(&mut self, torrent: &Torrent, out: &mut BufWriter<W>) -> Result<(), Box<dyn std::error::Error>> {
+        let piece_length = torrent.info.piece_length as u64;
+
+        let result = self.download_pieces(torrent, |piece_index, piece_data| {
+            out.seek(SeekFrom::Start(piece_index as u64 * piece_length))?;
+            out.write_all(piece_data)?;
+            Ok(())
+        }).await;
+
+        out.flush()?;
+        result
+    }
+
+    // Downloads the files that aren't skipped into `storage`, pieces of the
+    // most important files first.
+    pub async fn download_files(&mut self, torrent: &Torrent, storage: &mut Storage) -> Result<(), Box<dyn std::error::Error>> {
+        self.picker(torrent).set_piece_priorities(storage.piece_priorities());
+        storage.create_empty_files()?;
+
+        self.download_pieces(torrent, |piece_index, piece_data| storage.write_piece(piece_index, piece_data)).await
+    }
+
+    async fn download_pieces<F>(&mut self, torrent: &Torrent, mut write_piece: F) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnMut(u32, &[u8]) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let mut missing = vec![];
+        let mut attempted = HashSet::new();
+        self.add_web_seeds(&WebSeed::from_torrent(torrent));
+
+        // Pieces we already have, e.g. from before the download was paused,
+        // aren't picked again.
+        loop {
+            let availability = self.piece_availability(torrent);
+            let suggested = self.suggested_pieces();
+            let piece_index = match self.picker(torrent).pick_piece(&availability, &suggested, &attempted) {
+                Some(piece_index) => piece_index,
+                None => break,
+            };
+            attempted.insert(piece_index);
+
+            debug!("Downloading piece: {}", piece_index);
+
+            let piece_data = match self.download_piece_with_retries(piece_index, torrent).await {
+                Ok(piece_data) => piece_data,
+                Err(err) => {
+                    warn!("Could not download piece {}: {}", piece_index, err);
+                    missing.push(piece_index);
+                    continue;
+                },
+            };
+
+            write_piece(piece_index, &piece_data)?;
+
+            self.send_pex_updates(torrent).await;
+        }
+
+        if !missing.is_empty() {
+            missing.sort();
+            let err = DownloadError::MissingPieces(missing);
+            self.emit(ClientEvent::Error { message: err.to_string() });
+            return Err(Box::new(err));
+        }
+
+        self.emit(ClientEvent::TorrentCompleted { info_hash: torrent.info_hash()? });
+
         Ok(())
     }
 }
+
+// Our half of the handshake, advertising the extensions we support.
+pub fn handshake_message(peer_id: &str, info_hash: &[u8]) -> Bytes {
+    let mut reserved = [0; 8];
+    for (byte, mask) in [EXTENSION_PROTOCOL_BIT, FAST_EXTENSION_BIT] {
+        reserved[byte] |= mask;
+    }
+
+    let mut buf = BytesMut::with_capacity(1024);
+    buf.put_u8(19);
+    buf.put(&b"BitTorrent protocol"[..]);
+    buf.put(&reserved[..]);
+    buf.put(info_hash);
+    buf.put(peer_id.as_bytes());
+
+    return buf.into();
+}
+
+// Our handshake, also advertising the DHT if we run a node. Used for peers we
+// connect to and peers that connect to us alike.
+pub fn dht_handshake_message(peer_id: &str, info_hash: &[u8], dht_enabled: bool) -> Bytes {
+    let mut message = BytesMut::from(&handshake_message(peer_id, info_hash)[..]);
+    if dht_enabled {
+        let (byte, mask) = DHT_BIT;
+        message[20 + byte] |= mask;
+    }
+
+    message.into()
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    domain::{FileInfo, FileTree, FileTreeEntry, FileTreeFile, Torrent, TorrentInfo},
+    merkle::{self, Hash, ZERO_HASH},
+};
+
+// Limits for the piece length picked when none is given.
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+// Roughly how many pieces a torrent should have.
+const TARGET_PIECES: usize = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaVersion {
+    V1,
+    V2,
+    // Both v1 and v2 metadata, with padding files aligning v1 files to pieces.
+    #[default]
+    Hybrid,
+}
+
+impl FromStr for MetaVersion {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_ascii_lowercase().as_str() {
+            "1" | "v1" => Ok(MetaVersion::V1),
+            "2" | "v2" => Ok(MetaVersion::V2),
+            "hybrid" => Ok(MetaVersion::Hybrid),
+            _ => Err(format!("Unknown torrent version: {}", input)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    pub announce: String,
+    pub version: MetaVersion,
+    // Picked from the total size when left out.
+    pub piece_length: Option<usize>,
+    pub private: bool,
+    // Goes in the info dictionary, so it changes the info hash.
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>,
+}
+
+struct SourceFile {
+    path: Vec<String>,
+    disk_path: PathBuf,
+    length: u64,
+}
+
+// Makes a torrent of a file or of everything in a directory.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Torrent, Box<dyn std::error::Error>> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or(format!("Can't make a torrent of {}", path.display()))?;
+
+    let single_file = path.is_file();
+    let files = if single_file {
+        vec![SourceFile { path: vec![name.clone()], disk_path: path.to_path_buf(), length: fs::metadata(path)?.len() }]
+    } else {
+        let mut files = vec![];
+        collect_files(path, &mut vec![], &mut files)?;
+        // Tree order, which hybrid torrents need their v1 file list to be in too.
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    };
+
+    if files.is_empty() {
+        return Err(format!("No files in {}", path.display()).into());
+    }
+
+    let total_length: u64 = files.iter().map(|file| file.length).sum();
+    let piece_length = match options.piece_length {
+        Some(piece_length) => piece_length,
+        None => default_piece_length(total_length),
+    };
+    if options.version != MetaVersion::V1 && (piece_length < MIN_PIECE_LENGTH || !piece_length.is_power_of_two()) {
+        return Err(format!("v2 torrents need a piece length that's a power of two of at least 16KiB, not {}", piece_length).into());
+    }
+
+    let v1 = options.version != MetaVersion::V2;
+    let v2 = options.version != MetaVersion::V1;
+
+    let mut pieces = PieceHasher::new(piece_length);
+    let mut v1_files = vec![];
+    let mut tree = BTreeMap::new();
+    let mut piece_layers = BTreeMap::new();
+
+    for (index, file) in files.iter().enumerate() {
+        let pieces_root = hash_file(file, piece_length, v1.then_some(&mut pieces), &mut piece_layers)?;
+        v1_files.push(FileInfo { length: file.length as i64, path: file.path.clone(), attr: None });
+
+        if v2 {
+            insert_file(&mut tree, &file.path, FileTreeEntry {
+                length: file.length as i64,
+                pieces_root: pieces_root.map(|root| ByteBuf::from(root.to_vec())),
+                attr: None,
+            });
+        }
+
+        // In hybrid torrents every file starts at a piece boundary, like in v2.
+        let padding = pieces.padding_needed();
+        if v2 && v1 && padding > 0 && index + 1 < files.len() {
+            pieces.update(&vec![0; padding]);
+            v1_files.push(FileInfo {
+                length: padding as i64,
+                path: vec![".pad".to_string(), padding.to_string()],
+                attr: Some("p".to_string()),
+            });
+        }
+    }
+
+    let info = TorrentInfo {
+        length: if single_file && v1 { Some(total_length as i64) } else { None },
+        files: if single_file || !v1 { None } else { Some(v1_files) },
+        file_tree: v2.then_some(tree),
+        meta_version: v2.then_some(2),
+        name,
+        piece_length: piece_length as i64,
+        pieces: v1.then(|| ByteBuf::from(pieces.finish())),
+        private: options.private.then_some(1),
+        source: options.source.clone(),
+    };
+
+    Ok(Torrent {
+        announce: options.announce.clone(),
+        announce_list: None,
+        info,
+        url_list: Some(options.web_seeds.clone()).filter(|seeds| !seeds.is_empty()),
+        httpseeds: None,
+        piece_layers: if v2 { Some(piece_layers) } else { None },
+    })
+}
+
+// The smallest power of two that keeps the torrent at about TARGET_PIECES pieces.
+pub fn default_piece_length(total_length: u64) -> usize {
+    let wanted = (total_length / TARGET_PIECES as u64).max(1) as usize;
+
+    wanted.next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+fn collect_files(dir: &Path, path: &mut Vec<String>, files: &mut Vec<SourceFile>) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        path.push(entry.file_name().to_string_lossy().to_string());
+
+        if file_type.is_dir() {
+            collect_files(&entry.path(), path, files)?;
+        } else if file_type.is_file() {
+            files.push(SourceFile { path: path.clone(), disk_path: entry.path(), length: entry.metadata()?.len() });
+        }
+
+        path.pop();
+    }
+
+    Ok(())
+}
+
+fn insert_file(tree: &mut BTreeMap<String, FileTree>, path: &[String], entry: FileTreeEntry) {
+    let (name, dirs) = path.split_last().expect("Files have a name");
+
+    let mut node = tree;
+    for dir in dirs {
+        node = match node.entry(dir.clone()).or_insert_with(|| FileTree::Directory(BTreeMap::new())) {
+            FileTree::Directory(children) => children,
+            FileTree::File(_) => unreachable!("A file and a directory can't have the same path"),
+        };
+    }
+
+    node.insert(name.clone(), FileTree::File(FileTreeFile { file: entry }));
+}
+
+// Reads the file a piece at a time, feeding the v1 piece hasher and building
+// the file's merkle tree. Returns the pieces root, which empty files don't have.
+fn hash_file(
+    file: &SourceFile,
+    piece_length: usize,
+    mut v1_pieces: Option<&mut PieceHasher>,
+    piece_layers: &mut BTreeMap<ByteBuf, ByteBuf>,
+) -> Result<Option<Hash>, Box<dyn std::error::Error>> {
+    let mut input = File::open(&file.disk_path)?;
+    let mut buffer = vec![0; piece_length];
+    let mut layer = vec![];
+    let mut blocks = vec![];
+    let mut remaining = file.length;
+
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(piece_length as u64) as usize];
+        input.read_exact(chunk)?;
+        remaining -= chunk.len() as u64;
+
+        if let Some(pieces) = v1_pieces.as_mut() {
+            pieces.update(chunk);
+        }
+
+        if file.length <= piece_length as u64 {
+            blocks = merkle::block_hashes(chunk);
+        } else {
+            layer.push(merkle::piece_root(chunk, piece_length));
+        }
+    }
+
+    if file.length == 0 {
+        return Ok(None);
+    }
+    if layer.is_empty() {
+        return Ok(Some(merkle::root(&blocks, blocks.len().next_power_of_two(), ZERO_HASH)));
+    }
+
+    let root = merkle::layer_root(&layer, piece_length);
+    piece_layers.insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer.concat()));
+
+    Ok(Some(root))
+}
+
+// SHA-1 hashes of v1 pieces, which run across file boundaries.
+struct PieceHasher {
+    piece_length: usize,
+    hasher: Sha1,
+    in_piece: usize,
+    pieces: Vec<u8>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: usize) -> Self {
+        PieceHasher { piece_length, hasher: Sha1::new(), in_piece: 0, pieces: vec![] }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (self.piece_length - self.in_piece).min(data.len());
+            self.hasher.update(&data[..take]);
+            self.in_piece += take;
+            data = &data[take..];
+
+            if self.in_piece == self.piece_length {
+                let hasher = std::mem::replace(&mut self.hasher, Sha1::new());
+                self.pieces.extend(hasher.finalize());
+                self.in_piece = 0;
+            }
+        }
+    }
+
+    // Bytes left to fill the current piece.
+    fn padding_needed(&self) -> usize {
+        if self.in_piece == 0 { 0 } else { self.piece_length - self.in_piece }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.in_piece > 0 {
+            self.pieces.extend(self.hasher.finalize());
+        }
+
+        self.pieces
+    }
+}
@@ -0,0 +1,797 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{net::UdpSocket, sync::oneshot, task::{JoinHandle, JoinSet}, time::timeout};
+
+use crate::{debug, pex::{decode_compact_peers, encode_compact_peer}, random::{os_random_bytes, random_bytes}, warn};
+
+// DHT work happens on spawned tasks, so errors need to be sendable between them.
+#[derive(Debug, thiserror::Error)]
+pub enum DhtError {
+    #[error("DHT socket error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid KRPC message: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("invalid DHT state file: {0}")]
+    State(#[from] serde_json::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<String> for DhtError {
+    fn from(message: String) -> Self {
+        DhtError::Other(message)
+    }
+}
+
+impl From<&str> for DhtError {
+    fn from(message: &str) -> Self {
+        DhtError::Other(message.to_string())
+    }
+}
+
+pub type DhtResult<T> = Result<T, DhtError>;
+
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// Bucket size and lookup concurrency from BEP 5.
+pub const K: usize = 8;
+const ALPHA: usize = 3;
+
+const ID_LENGTH: usize = 20;
+const COMPACT_NODE_SIZE: usize = ID_LENGTH + 6;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const STALE_NODE_AGE: Duration = Duration::from_secs(15 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// Announces are cheap to make, so what we store for others is bounded.
+const MAX_PEERS_PER_TORRENT: usize = 200;
+const MAX_TORRENTS: usize = 1000;
+const TRANSACTION_ID_LENGTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; ID_LENGTH]);
+
+impl NodeId {
+    // Node ids only need to be spread out, not unpredictable.
+    pub fn random() -> Self {
+        NodeId(random_bytes(ID_LENGTH).try_into().unwrap())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(NodeId)
+    }
+
+    pub fn distance(&self, other: &NodeId) -> [u8; ID_LENGTH] {
+        let mut distance = [0; ID_LENGTH];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+
+        distance
+    }
+
+    // Number of leading bits shared with the other id, which is also the
+    // index of the bucket the other id belongs in.
+    pub fn shared_prefix_len(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+
+        for (i, byte) in distance.iter().enumerate() {
+            if *byte != 0 {
+                return i * 8 + byte.leading_zeros() as usize;
+            }
+        }
+
+        ID_LENGTH * 8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+}
+
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        RoutingTable { own_id, buckets: vec![vec![]; ID_LENGTH * 8 + 1] }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    // Returns false if the bucket was full of good nodes and this one was dropped.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        if id == self.own_id {
+            return false;
+        }
+
+        let bucket = &mut self.buckets[self.own_id.shared_prefix_len(&id)];
+        let now = Instant::now();
+
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = now;
+            return true;
+        }
+
+        if bucket.len() < K {
+            bucket.push(Node { id, addr, last_seen: now });
+            return true;
+        }
+
+        match bucket.iter().position(|node| node.last_seen.elapsed() > STALE_NODE_AGE) {
+            Some(stale) => {
+                bucket[stale] = Node { id, addr, last_seen: now };
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        let bucket = &mut self.buckets[self.own_id.shared_prefix_len(id)];
+        bucket.retain(|node| node.id != *id);
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Clone)]
+pub struct KrpcArgs {
+    pub id: ByteBuf,
+    pub target: Option<ByteBuf>,
+    pub info_hash: Option<ByteBuf>,
+    pub port: Option<i64>,
+    pub token: Option<ByteBuf>,
+    pub implied_port: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Clone)]
+pub struct KrpcResponse {
+    pub id: ByteBuf,
+    pub nodes: Option<ByteBuf>,
+    pub values: Option<Vec<ByteBuf>>,
+    pub token: Option<ByteBuf>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Clone)]
+pub struct KrpcMessage {
+    pub t: ByteBuf,
+    pub y: String,
+    pub q: Option<String>,
+    pub a: Option<KrpcArgs>,
+    pub r: Option<KrpcResponse>,
+    pub e: Option<(i64, String)>,
+}
+
+impl KrpcMessage {
+    pub fn from_bytes(payload: &[u8]) -> DhtResult<Self> {
+        Ok(serde_bencode::from_bytes(payload)?)
+    }
+
+    pub fn to_bytes(&self) -> DhtResult<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    fn sender_id(&self) -> Option<NodeId> {
+        let id = match (&self.a, &self.r) {
+            (Some(args), _) => &args.id,
+            (_, Some(response)) => &response.id,
+            _ => return None,
+        };
+
+        NodeId::from_bytes(id)
+    }
+}
+
+pub fn encode_compact_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut buf = vec![];
+
+    for node in nodes.iter().filter(|node| node.addr.is_ipv4()) {
+        buf.extend_from_slice(&node.id.0);
+        buf.extend(encode_compact_peer(&node.addr));
+    }
+
+    buf
+}
+
+pub fn decode_compact_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(COMPACT_NODE_SIZE)
+        .filter_map(|chunk| {
+            let id = NodeId::from_bytes(&chunk[..ID_LENGTH])?;
+            let addr = *decode_compact_peers(&chunk[ID_LENGTH..]).first()?;
+            Some((id, addr))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PersistedNode {
+    id: String,
+    addr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PersistedState {
+    id: String,
+    nodes: Vec<PersistedNode>,
+    // The port we ran on, which the nodes that know us will send to.
+    #[serde(default)]
+    port: Option<u16>,
+}
+
+// Result of an iterative lookup: the closest nodes that answered, with the
+// write tokens they handed out, and any peers found along the way.
+#[derive(Debug, Default)]
+pub struct LookupResult {
+    pub nodes: Vec<(Node, Option<ByteBuf>)>,
+    pub peers: HashSet<SocketAddr>,
+}
+
+struct TokenSecrets {
+    current: Vec<u8>,
+    previous: Vec<u8>,
+    rotated_at: Instant,
+}
+
+// Queries waiting for a reply, by the node they went to and their transaction id.
+type PendingKey = (SocketAddr, Vec<u8>);
+
+pub struct DhtNode {
+    socket: Arc<UdpSocket>,
+    routing_table: Mutex<RoutingTable>,
+    peers: Mutex<HashMap<NodeId, HashMap<SocketAddr, Instant>>>,
+    secrets: Mutex<TokenSecrets>,
+    pending: Mutex<HashMap<PendingKey, oneshot::Sender<KrpcMessage>>>,
+    // Answers queries until the node is dropped.
+    receiver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl DhtNode {
+    // Binds the node and starts answering queries. Any routing table saved at
+    // `state_path` is loaded, keeping the node id stable across runs.
+    // Starts a node on `port`, or if none is given on the port saved in
+    // `state_path`, so that the nodes in the saved routing table can still
+    // reach us. The DHT can't share the peer listener's port, whose UDP side
+    // uTP has already taken. If the port is taken, any free one is used.
+    pub async fn start(port: Option<u16>, state_path: &Path) -> DhtResult<Arc<DhtNode>> {
+        let saved_port = || fs::read(state_path).ok().and_then(|contents| serde_json::from_slice::<PersistedState>(&contents).ok()?.port);
+        let port = port.or_else(saved_port).unwrap_or(0);
+
+        match DhtNode::bind(default_bind_addr(port), Some(state_path)).await {
+            Err(DhtError::Io(err)) if port != 0 => {
+                warn!("Could not start the DHT node on port {}, using a random port instead: {}", port, err);
+                DhtNode::bind(default_bind_addr(0), Some(state_path)).await
+            },
+            result => result,
+        }
+    }
+
+    pub async fn bind(addr: SocketAddr, state_path: Option<&Path>) -> DhtResult<Arc<DhtNode>> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+
+        let state = match state_path {
+            Some(path) if path.exists() => {
+                let contents = fs::read(path)?;
+                Some(serde_json::from_slice::<PersistedState>(&contents)?)
+            },
+            _ => None,
+        };
+
+        let own_id = state
+            .as_ref()
+            .and_then(|state| hex::decode(&state.id).ok())
+            .and_then(|id| NodeId::from_bytes(&id))
+            .unwrap_or_else(NodeId::random);
+
+        let mut routing_table = RoutingTable::new(own_id);
+        for node in state.iter().flat_map(|state| state.nodes.iter()) {
+            let id = hex::decode(&node.id).ok().and_then(|id| NodeId::from_bytes(&id));
+            if let (Some(id), Ok(addr)) = (id, node.addr.parse()) {
+                routing_table.insert(id, addr);
+            }
+        }
+
+        let node = Arc::new(DhtNode {
+            socket,
+            routing_table: Mutex::new(routing_table),
+            peers: Mutex::new(HashMap::new()),
+            // Anyone who can guess the secret can forge tokens and announce
+            // peers for any torrent, so it comes from the OS.
+            secrets: Mutex::new(TokenSecrets {
+                current: os_random_bytes(ID_LENGTH)?,
+                previous: os_random_bytes(ID_LENGTH)?,
+                rotated_at: Instant::now(),
+            }),
+            pending: Mutex::new(HashMap::new()),
+            receiver: Mutex::new(None),
+        });
+
+        // The task only holds on to the node while handling a message, so
+        // dropping the node stops it.
+        let receiver = tokio::spawn(Self::run(node.socket.clone(), Arc::downgrade(&node)));
+        *node.receiver.lock().unwrap() = Some(receiver);
+
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.routing_table.lock().unwrap().own_id()
+    }
+
+    pub fn local_addr(&self) -> DhtResult<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.routing_table.lock().unwrap().len()
+    }
+
+    pub fn save_state(&self, path: &Path) -> DhtResult<()> {
+        let port = self.socket.local_addr()?.port();
+        let routing_table = self.routing_table.lock().unwrap();
+        let state = PersistedState {
+            port: Some(port),
+            id: hex::encode(routing_table.own_id().0),
+            nodes: routing_table
+                .closest(&routing_table.own_id(), usize::MAX)
+                .iter()
+                .map(|node| PersistedNode { id: hex::encode(node.id.0), addr: node.addr.to_string() })
+                .collect(),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_vec(&state)?)?;
+
+        Ok(())
+    }
+
+    async fn run(socket: Arc<UdpSocket>, node: Weak<DhtNode>) {
+        let mut buf = vec![0; 65536];
+
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("DHT socket error: {}", err);
+                    continue;
+                },
+            };
+
+            match node.upgrade() {
+                Some(node) => node.handle_packet(&buf[..len], addr).await,
+                None => return,
+            }
+        }
+    }
+
+    async fn handle_packet(&self, packet: &[u8], addr: SocketAddr) {
+        let message = match KrpcMessage::from_bytes(packet) {
+            Ok(message) => message,
+            Err(_) => {
+                debug!("Ignoring malformed KRPC message from {}", addr);
+                return;
+            },
+        };
+
+        match message.y.as_str() {
+            "q" => {
+                let response = self.handle_query(&message, addr);
+                if let Ok(bytes) = response.to_bytes() {
+                    let _ = self.socket.send_to(&bytes, addr).await;
+                }
+            },
+            "r" | "e" => {
+                let sender = self.pending.lock().unwrap().remove(&(addr, message.t.to_vec()));
+                let Some(sender) = sender else {
+                    debug!("Ignoring unexpected KRPC reply from {}", addr);
+                    return;
+                };
+
+                // Only nodes that answered one of our queries go in the
+                // routing table, so nobody can fill it by just sending queries.
+                if let Some(id) = message.sender_id() {
+                    self.routing_table.lock().unwrap().insert(id, addr);
+                }
+                let _ = sender.send(message);
+            },
+            _ => {
+                debug!("Ignoring KRPC message of unknown type {} from {}", message.y, addr);
+            },
+        }
+    }
+
+    fn handle_query(&self, query: &KrpcMessage, addr: SocketAddr) -> KrpcMessage {
+        let own_id = ByteBuf::from(self.id().0.to_vec());
+        let error = |code: i64, reason: &str| KrpcMessage {
+            t: query.t.clone(),
+            y: "e".to_string(),
+            e: Some((code, reason.to_string())),
+            ..Default::default()
+        };
+
+        let args = match &query.a {
+            Some(args) => args,
+            None => return error(203, "Missing arguments"),
+        };
+        let mut response = KrpcResponse { id: own_id, ..Default::default() };
+
+        match query.q.as_deref() {
+            Some("ping") => {},
+            Some("find_node") => {
+                let target = match args.target.as_ref().and_then(|target| NodeId::from_bytes(target)) {
+                    Some(target) => target,
+                    None => return error(203, "Missing target"),
+                };
+                let closest = self.routing_table.lock().unwrap().closest(&target, K);
+                response.nodes = Some(ByteBuf::from(encode_compact_nodes(&closest)));
+            },
+            Some("get_peers") => {
+                let info_hash = match args.info_hash.as_ref().and_then(|hash| NodeId::from_bytes(hash)) {
+                    Some(info_hash) => info_hash,
+                    None => return error(203, "Missing info_hash"),
+                };
+                response.token = Some(ByteBuf::from(self.make_token(&addr.ip())));
+
+                let peers = self.stored_peers(&info_hash);
+                if peers.is_empty() {
+                    let closest = self.routing_table.lock().unwrap().closest(&info_hash, K);
+                    response.nodes = Some(ByteBuf::from(encode_compact_nodes(&closest)));
+                } else {
+                    response.values = Some(peers.iter().map(|peer| ByteBuf::from(encode_compact_peer(peer))).collect());
+                }
+            },
+            Some("announce_peer") => {
+                let info_hash = match args.info_hash.as_ref().and_then(|hash| NodeId::from_bytes(hash)) {
+                    Some(info_hash) => info_hash,
+                    None => return error(203, "Missing info_hash"),
+                };
+                let token_valid = args.token.as_ref().is_some_and(|token| self.is_valid_token(token, &addr.ip()));
+                if !token_valid {
+                    return error(203, "Bad token");
+                }
+
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => addr.port(),
+                    (_, Some(port)) => match u16::try_from(port) {
+                        Ok(port) => port,
+                        Err(_) => return error(203, "Bad port"),
+                    },
+                    _ => return error(203, "Missing port"),
+                };
+
+                self.store_peer(info_hash, SocketAddr::new(addr.ip(), port));
+            },
+            _ => return error(204, "Method Unknown"),
+        }
+
+        KrpcMessage { t: query.t.clone(), y: "r".to_string(), r: Some(response), ..Default::default() }
+    }
+
+    // Keeps at most MAX_PEERS_PER_TORRENT peers for each of MAX_TORRENTS
+    // torrents, replacing the oldest announce when a torrent is full.
+    fn store_peer(&self, info_hash: NodeId, peer: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_TORRENTS {
+            for swarm in peers.values_mut() {
+                swarm.retain(|_, announced| announced.elapsed() < PEER_TTL);
+            }
+            peers.retain(|_, swarm| !swarm.is_empty());
+
+            if peers.len() >= MAX_TORRENTS {
+                debug!("Not storing peer {}, already storing peers for {} torrents", peer, peers.len());
+                return;
+            }
+        }
+
+        let swarm = peers.entry(info_hash).or_default();
+        if !swarm.contains_key(&peer) && swarm.len() >= MAX_PEERS_PER_TORRENT {
+            swarm.retain(|_, announced| announced.elapsed() < PEER_TTL);
+            if swarm.len() >= MAX_PEERS_PER_TORRENT {
+                if let Some(oldest) = swarm.iter().min_by_key(|(_, announced)| **announced).map(|(peer, _)| *peer) {
+                    swarm.remove(&oldest);
+                }
+            }
+        }
+        swarm.insert(peer, Instant::now());
+    }
+
+    fn stored_peers(&self, info_hash: &NodeId) -> Vec<SocketAddr> {
+        let mut peers = self.peers.lock().unwrap();
+
+        match peers.get_mut(info_hash) {
+            Some(swarm) => {
+                swarm.retain(|_, announced| announced.elapsed() < PEER_TTL);
+                swarm.keys().copied().collect()
+            },
+            None => vec![],
+        }
+    }
+
+    fn rotate_secrets(&self) -> std::sync::MutexGuard<'_, TokenSecrets> {
+        let mut secrets = self.secrets.lock().unwrap();
+
+        if secrets.rotated_at.elapsed() >= TOKEN_ROTATION {
+            match os_random_bytes(ID_LENGTH) {
+                Ok(secret) => {
+                    secrets.previous = std::mem::replace(&mut secrets.current, secret);
+                },
+                Err(err) => {
+                    warn!("Could not rotate the DHT token secret, keeping the current one: {}", err);
+                },
+            }
+            secrets.rotated_at = Instant::now();
+        }
+
+        secrets
+    }
+
+    fn token_for(secret: &[u8], ip: &IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn make_token(&self, ip: &IpAddr) -> Vec<u8> {
+        Self::token_for(&self.rotate_secrets().current, ip)
+    }
+
+    // Tokens handed out before the last rotation are still accepted.
+    fn is_valid_token(&self, token: &[u8], ip: &IpAddr) -> bool {
+        let secrets = self.rotate_secrets();
+
+        token == Self::token_for(&secrets.current, ip) || token == Self::token_for(&secrets.previous, ip)
+    }
+
+    async fn query(&self, addr: SocketAddr, method: &str, args: KrpcArgs) -> DhtResult<KrpcResponse> {
+        // Replies are only accepted from the node we asked with the id we
+        // sent, which has to be unguessable for spoofed replies to fail.
+        let transaction = os_random_bytes(TRANSACTION_ID_LENGTH)?;
+        let message = KrpcMessage {
+            t: ByteBuf::from(transaction.clone()),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(args),
+            ..Default::default()
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert((addr, transaction.clone()), sender);
+        self.socket.send_to(&message.to_bytes()?, addr).await?;
+
+        let reply = match timeout(QUERY_TIMEOUT, receiver).await {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.pending.lock().unwrap().remove(&(addr, transaction));
+                return Err(format!("DHT query {} to {} timed out", method, addr).into());
+            },
+        };
+
+        match (reply.r, reply.e) {
+            (Some(response), _) => Ok(response),
+            (_, Some((code, reason))) => Err(format!("DHT node {} returned error {}: {}", addr, code, reason).into()),
+            _ => Err(format!("Invalid DHT response from {}", addr).into()),
+        }
+    }
+
+    fn args(&self) -> KrpcArgs {
+        KrpcArgs { id: ByteBuf::from(self.id().0.to_vec()), ..Default::default() }
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> DhtResult<NodeId> {
+        let response = self.query(addr, "ping", self.args()).await?;
+
+        NodeId::from_bytes(&response.id).ok_or_else(|| "Invalid node id in ping response".into())
+    }
+
+    pub async fn find_node(&self, addr: SocketAddr, target: &NodeId) -> DhtResult<Vec<(NodeId, SocketAddr)>> {
+        let args = KrpcArgs { target: Some(ByteBuf::from(target.0.to_vec())), ..self.args() };
+        let response = self.query(addr, "find_node", args).await?;
+
+        Ok(response.nodes.map(|nodes| decode_compact_nodes(&nodes)).unwrap_or_default())
+    }
+
+    pub async fn get_peers(&self, addr: SocketAddr, info_hash: &NodeId) -> DhtResult<KrpcResponse> {
+        let args = KrpcArgs { info_hash: Some(ByteBuf::from(info_hash.0.to_vec())), ..self.args() };
+
+        self.query(addr, "get_peers", args).await
+    }
+
+    pub async fn announce_peer(&self, addr: SocketAddr, info_hash: &NodeId, port: u16, token: ByteBuf) -> DhtResult<()> {
+        let args = KrpcArgs {
+            info_hash: Some(ByteBuf::from(info_hash.0.to_vec())),
+            port: Some(port.into()),
+            token: Some(token),
+            ..self.args()
+        };
+        self.query(addr, "announce_peer", args).await?;
+
+        Ok(())
+    }
+
+    // Joins the network by looking up our own id through the given nodes.
+    pub async fn bootstrap(self: &Arc<Self>, nodes: &[String]) -> DhtResult<()> {
+        for node in nodes {
+            let addrs = match tokio::net::lookup_host(node).await {
+                Ok(addrs) => addrs,
+                Err(err) => {
+                    warn!("Could not resolve DHT bootstrap node {}: {}", node, err);
+                    continue;
+                },
+            };
+
+            for addr in addrs.filter(|addr| addr.is_ipv4()) {
+                if let Err(err) = self.ping(addr).await {
+                    debug!("DHT bootstrap node {} did not answer: {}", addr, err);
+                }
+            }
+        }
+
+        let own_id = self.id();
+        self.lookup(own_id, false).await;
+
+        if self.num_nodes() == 0 {
+            return Err("Could not reach any DHT nodes".into());
+        }
+
+        Ok(())
+    }
+
+    // Iterative Kademlia lookup, querying the closest unqueried nodes ALPHA at a time
+    // until no closer nodes turn up.
+    pub async fn lookup(self: &Arc<Self>, target: NodeId, want_peers: bool) -> LookupResult {
+        let mut candidates: BTreeMap<[u8; ID_LENGTH], (NodeId, SocketAddr)> = self.routing_table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .iter()
+            .map(|node| (node.id.distance(&target), (node.id, node.addr)))
+            .collect();
+        let mut queried: HashSet<NodeId> = HashSet::new();
+        let mut responded: BTreeMap<[u8; ID_LENGTH], (Node, Option<ByteBuf>)> = BTreeMap::new();
+        let mut result = LookupResult::default();
+
+        loop {
+            let batch: Vec<(NodeId, SocketAddr)> = candidates
+                .values()
+                .take(K)
+                .filter(|(id, _)| !queried.contains(id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for (id, addr) in batch {
+                queried.insert(id);
+
+                let node = self.clone();
+                queries.spawn(async move {
+                    let response = if want_peers {
+                        node.get_peers(addr, &target).await
+                    } else {
+                        let args = KrpcArgs { target: Some(ByteBuf::from(target.0.to_vec())), ..node.args() };
+                        node.query(addr, "find_node", args).await
+                    };
+                    (id, addr, response)
+                });
+            }
+
+            while let Some(joined) = queries.join_next().await {
+                let (id, addr, response) = match joined {
+                    Ok(joined) => joined,
+                    Err(_) => continue,
+                };
+
+                let response = match response {
+                    Ok(response) => response,
+                    Err(err) => {
+                        debug!("DHT lookup query failed: {}", err);
+                        candidates.remove(&id.distance(&target));
+                        self.routing_table.lock().unwrap().remove(&id);
+                        continue;
+                    },
+                };
+
+                let node = Node { id, addr, last_seen: Instant::now() };
+                responded.insert(id.distance(&target), (node, response.token.clone()));
+
+                for value in response.values.iter().flatten() {
+                    result.peers.extend(decode_compact_peers(value));
+                }
+
+                for (id, addr) in response.nodes.map(|nodes| decode_compact_nodes(&nodes)).unwrap_or_default() {
+                    if id != self.id() && !queried.contains(&id) {
+                        candidates.insert(id.distance(&target), (id, addr));
+                    }
+                }
+            }
+        }
+
+        result.nodes = responded.into_values().take(K).collect();
+        result
+    }
+
+    pub async fn find_peers(self: &Arc<Self>, info_hash: &[u8]) -> DhtResult<Vec<SocketAddr>> {
+        let info_hash = NodeId::from_bytes(info_hash).ok_or("Invalid info hash")?;
+        let result = self.lookup(info_hash, true).await;
+
+        Ok(result.peers.into_iter().collect())
+    }
+
+    // Looks up the swarm and announces that we're part of it on the closest nodes,
+    // returning the peers found along the way.
+    pub async fn announce(self: &Arc<Self>, info_hash: &[u8], port: u16) -> DhtResult<Vec<SocketAddr>> {
+        let info_hash = NodeId::from_bytes(info_hash).ok_or("Invalid info hash")?;
+        let result = self.lookup(info_hash, true).await;
+
+        for (node, token) in &result.nodes {
+            if let Some(token) = token {
+                if let Err(err) = self.announce_peer(node.addr, &info_hash, port, token.clone()).await {
+                    debug!("Could not announce to DHT node {}: {}", node.addr, err);
+                }
+            }
+        }
+
+        Ok(result.peers.into_iter().collect())
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.abort();
+        }
+    }
+}
+
+pub fn default_bind_addr(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+}
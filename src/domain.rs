@@ -1,35 +1,349 @@
-use bytes::Bytes;
+use std::{collections::{BTreeMap, HashSet, VecDeque}, time::{Duration, Instant}};
+
+use bytes::{Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
 extern crate sha1;
 
 use sha1::{Digest, Sha1};
-use tokio::{io::{BufReader, AsyncReadExt}, net::TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::debug;
+use crate::{
+    debug,
+    extension::{EXTENDED_MESSAGE_ID, EXTENSION_PROTOCOL_BIT},
+    merkle::{self, Hash, ZERO_HASH},
+    peer_id::{identify_client, PeerClient},
+    sha256::sha256,
+};
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct Torrent {
+    // Torrents with an announce-list may leave this out.
+    #[serde(default)]
     pub announce: String,
+    // BEP 12 tracker tiers, tried in order.
+    #[serde(rename = "announce-list", default, skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: TorrentInfo,
+    // BEP 19 web seeds, given either as a single URL or as a list.
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none", deserialize_with = "deserialize_url_list")]
+    pub url_list: Option<Vec<String>>,
+    // BEP 17 HTTP seeds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub httpseeds: Option<Vec<String>>,
+    // v2: the piece layer of every file bigger than a piece, keyed by the
+    // file's pieces root.
+    #[serde(rename = "piece layers", default, skip_serializing_if = "Option::is_none")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+}
+
+fn deserialize_url_list<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let urls = match Option::<UrlList>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(UrlList::One(url)) => vec![url],
+        Some(UrlList::Many(urls)) => urls,
+    };
+
+    Ok(Some(urls.into_iter().filter(|url| !url.is_empty()).collect()))
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct TorrentInfo {
+    // Single-file torrents have a length, multi-file torrents a list of files
+    // inside a directory called `name`.
     pub length: Option<i64>,
+    pub files: Option<Vec<FileInfo>>,
+    // v2 torrents (BEP 52) list their files in a tree instead. Hybrid
+    // torrents have both, with padding files in `files` so that every file
+    // starts at a piece boundary.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<String, FileTree>>,
+    #[serde(rename = "meta version", default, skip_serializing_if = "Option::is_none")]
+    pub meta_version: Option<i64>,
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: i64,
-    pub pieces: ByteBuf,
+    // SHA-1 hashes of the pieces, which v2-only torrents don't have.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<ByteBuf>,
+    // 1 for private torrents (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+    // Set by private trackers so that the same content has a different info
+    // hash on each of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct FileInfo {
+    pub length: i64,
+    // Path components relative to the torrent's directory.
+    pub path: Vec<String>,
+    // "p" marks padding files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl FileInfo {
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+// A node of a v2 file tree. Directories map names to what's in them, and a
+// file is a node with a single empty key holding its details.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(untagged)]
+pub enum FileTree {
+    File(FileTreeFile),
+    Directory(BTreeMap<String, FileTree>),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FileTreeFile {
+    #[serde(rename = "")]
+    pub file: FileTreeEntry,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct FileTreeEntry {
+    pub length: i64,
+    // Root of the file's merkle tree. Empty files don't have one.
+    #[serde(rename = "pieces root", default, skip_serializing_if = "Option::is_none")]
+    pub pieces_root: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+// A file of a v2 torrent, where every file starts at a piece boundary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub offset: u64,
+    pub length: u64,
+    pub pieces_root: Option<Hash>,
 }
 
 impl Torrent {
     pub const BLOCK_SIZE: usize = 16 * 1024;
     const SHA_LENGTH: usize = 20;
 
+    // The tracker tiers. When there's an announce-list, `announce` is only
+    // there for clients that don't support one.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self.announce_list
+            .iter()
+            .flatten()
+            .map(|tier| tier.iter().filter(|url| !url.is_empty()).cloned().collect::<Vec<String>>())
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        if tiers.is_empty() && !self.announce.is_empty() {
+            return vec![vec![self.announce.clone()]];
+        }
+
+        tiers
+    }
+
+    pub fn total_length(&self) -> usize {
+        match self.info.length {
+            Some(length) => length as usize,
+            None => self.files().iter().map(|file| file.length as usize).sum(),
+        }
+    }
+
+    // The files in the torrent, in the order their data appears in the pieces.
+    // A single-file torrent has just the one, named after the torrent. For
+    // v2-only torrents the gaps that align files to pieces show up as padding
+    // files, like in hybrid torrents.
+    pub fn files(&self) -> Vec<FileInfo> {
+        if self.info.length.is_none() {
+            if let Some(files) = &self.info.files {
+                return files.clone();
+            }
+
+            if self.is_v2() {
+                let mut files = vec![];
+                let mut offset = 0;
+
+                for file in self.v2_files() {
+                    if file.offset > offset {
+                        let gap = file.offset - offset;
+                        files.push(FileInfo { length: gap as i64, path: vec![".pad".to_string(), gap.to_string()], attr: Some("p".to_string()) });
+                    }
+
+                    files.push(FileInfo { length: file.length as i64, path: file.path, attr: None });
+                    offset = file.offset + file.length;
+                }
+
+                return files;
+            }
+        }
+
+        vec![FileInfo { length: self.info.length.unwrap_or(0), path: vec![self.info.name.clone()], attr: None }]
+    }
+
+    // A v2 torrent with a single file in its tree is a single-file torrent.
+    pub fn is_multi_file(&self) -> bool {
+        if self.info.length.is_some() {
+            return false;
+        }
+
+        self.info.files.is_some() || (self.is_v2() && !matches!(self.v2_files().as_slice(), [file] if file.path.len() == 1))
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.info.meta_version == Some(2) && self.info.file_tree.is_some()
+    }
+
+    // Torrents that work in both v1 and v2 swarms.
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v2() && self.info.pieces.is_some()
+    }
+
+    // The files of a v2 torrent in tree order, at their piece-aligned offsets.
+    pub fn v2_files(&self) -> Vec<V2File> {
+        fn walk(tree: &BTreeMap<String, FileTree>, path: &mut Vec<String>, files: &mut Vec<V2File>, offset: &mut u64, piece_length: u64) {
+            for (name, node) in tree {
+                path.push(name.clone());
+
+                match node {
+                    FileTree::File(FileTreeFile { file }) => {
+                        files.push(V2File {
+                            path: path.clone(),
+                            offset: *offset,
+                            length: file.length as u64,
+                            pieces_root: file.pieces_root.as_ref().and_then(|root| root.as_slice().try_into().ok()),
+                        });
+                        *offset += (file.length as u64).div_ceil(piece_length) * piece_length;
+                    },
+                    FileTree::Directory(children) => walk(children, path, files, offset, piece_length),
+                }
+
+                path.pop();
+            }
+        }
+
+        let mut files = vec![];
+        if let Some(tree) = &self.info.file_tree {
+            walk(tree, &mut vec![], &mut files, &mut 0, self.info.piece_length as u64);
+        }
+
+        files
+    }
+
+    // The hash to identify the torrent by when talking to trackers, the DHT
+    // and peers: the v1 info hash, or the v2 one cut to 20 bytes for v2-only
+    // torrents.
+    pub fn info_hash(&self) -> Result<String, Box<dyn std::error::Error>> {
+        if self.is_v2() && self.info.pieces.is_none() {
+            let v2_hash = calculate_info_hash_v2(&self.info)?;
+            return Ok(v2_hash[..40].to_string());
+        }
+
+        calculate_info_hash(&self.info)
+    }
+
+    // The piece layer of the file with the given pieces root, from the torrent
+    // or else from `received`, e.g. hashes peers have sent us.
+    pub fn piece_layer<'a>(&'a self, pieces_root: &Hash, received: &'a BTreeMap<ByteBuf, ByteBuf>) -> Option<&'a [u8]> {
+        let key = ByteBuf::from(pieces_root.to_vec());
+
+        self.piece_layers
+            .as_ref()
+            .and_then(|layers| layers.get(&key))
+            .or_else(|| received.get(&key))
+            .map(|layer| layer.as_slice())
+    }
+
+    // Checks that every piece layer hashes up to its file's pieces root.
+    pub fn validate_piece_layers(&self) -> Result<(), String> {
+        let piece_length = self.info.piece_length as usize;
+        if self.is_v2() && (piece_length < merkle::BLOCK_SIZE || !piece_length.is_power_of_two()) {
+            return Err(format!("Invalid piece length for a v2 torrent: {}", piece_length));
+        }
+
+        let received = BTreeMap::new();
+        for file in self.v2_files() {
+            let root = match file.pieces_root {
+                Some(root) if file.length > piece_length as u64 => root,
+                _ => continue,
+            };
+
+            let layer = self
+                .piece_layer(&root, &received)
+                .ok_or(format!("Missing piece layer for {}", file.path.join("/")))?;
+            let expected = (file.length as usize).div_ceil(piece_length);
+
+            if layer.len() != expected * 32 || merkle::layer_root(&merkle::split_hashes(layer), piece_length) != root {
+                return Err(format!("Piece layer for {} doesn't match its pieces root", file.path.join("/")));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks a downloaded piece against the SHA-1 piece hashes and, for v2
+    // torrents, against the file's merkle tree as well.
+    pub fn verify_piece(&self, piece_index: u32, data: &[u8], received_layers: &BTreeMap<ByteBuf, ByteBuf>) -> bool {
+        if self.info.pieces.is_some() && hex::encode(Sha1::digest(data)) != self.get_piece_sha(piece_index as usize) {
+            return false;
+        }
+
+        if self.is_v2() {
+            return self.verify_piece_v2(piece_index, data, received_layers);
+        }
+
+        self.info.pieces.is_some()
+    }
+
+    fn verify_piece_v2(&self, piece_index: u32, data: &[u8], received_layers: &BTreeMap<ByteBuf, ByteBuf>) -> bool {
+        let piece_length = self.info.piece_length as u64;
+        let start = piece_index as u64 * piece_length;
+
+        let file = match self.v2_files().into_iter().find(|file| file.offset <= start && start < file.offset + file.length) {
+            Some(file) => file,
+            // Pieces that are all padding.
+            None => return data.iter().all(|byte| *byte == 0),
+        };
+        let root = match file.pieces_root {
+            Some(root) => root,
+            None => return false,
+        };
+
+        // Padding after the end of the file isn't part of its tree.
+        let in_file = (file.offset + file.length - start).min(data.len() as u64) as usize;
+        if data[in_file..].iter().any(|byte| *byte != 0) {
+            return false;
+        }
+        let data = &data[..in_file];
+
+        if file.length <= piece_length {
+            let blocks = merkle::block_hashes(data);
+            return merkle::root(&blocks, blocks.len().next_power_of_two(), ZERO_HASH) == root;
+        }
+
+        let layer = match self.piece_layer(&root, received_layers) {
+            Some(layer) => layer,
+            None => return false,
+        };
+        let index = ((start - file.offset) / piece_length) as usize;
+
+        layer.get(index * 32..(index + 1) * 32) == Some(merkle::piece_root(data, piece_length as usize).as_slice())
+    }
+
     pub fn get_num_pieces(&self) -> i64 {
-        let length = self.info.length.unwrap();
+        let length = self.total_length() as i64;
         let piece_length = self.info.piece_length;
         let mut num_pieces = length / piece_length;
         if length % piece_length != 0 {
@@ -39,24 +353,37 @@ impl Torrent {
         return num_pieces;
     }
 
-    pub fn get_num_blocks(&self, piece_index: usize) -> usize {
-        if piece_index + 1 == self.get_num_pieces() as usize {
-            let last_piece_length = (self.info.length.unwrap() % self.info.piece_length) as usize;
-
-            return last_piece_length / Self::BLOCK_SIZE + ((last_piece_length % Self::BLOCK_SIZE != 0) as usize);
+    pub fn get_piece_length(&self, piece_index: usize) -> Result<usize, Box<dyn std::error::Error>> {
+        let num_pieces = self.get_num_pieces();
+        if piece_index as i64 >= num_pieces {
+            return Err(format!("Piece {} is out of range, the torrent has {} pieces", piece_index, num_pieces).into());
         }
 
-        self.info.piece_length as usize / Self::BLOCK_SIZE
+        let length = self.total_length();
+        let piece_length = self.info.piece_length as usize;
+
+        Ok((length - piece_index * piece_length).min(piece_length))
     }
 
-    pub fn get_block_length(&self, piece_index: usize, block_offset: usize) -> usize {
-        match block_offset {
-            _ if block_offset + 1 == self.get_num_blocks(piece_index) && piece_index + 1 == self.get_num_pieces() as usize => {
-                let last_piece_length = self.info.length.unwrap() % self.info.piece_length;
-                last_piece_length as usize % Self::BLOCK_SIZE
-            },
-            _ => Self::BLOCK_SIZE,
+    pub fn get_num_blocks(&self, piece_index: usize) -> Result<usize, Box<dyn std::error::Error>> {
+        let piece_length = self.get_piece_length(piece_index)?;
+
+        Ok(piece_length / Self::BLOCK_SIZE + (!piece_length.is_multiple_of(Self::BLOCK_SIZE) as usize))
+    }
+
+    pub fn get_block_length(&self, piece_index: usize, block_offset: usize) -> Result<usize, Box<dyn std::error::Error>> {
+        let piece_length = self.get_piece_length(piece_index)?;
+        if block_offset * Self::BLOCK_SIZE >= piece_length {
+            return Err(format!("Block {} is out of range for piece {}", block_offset, piece_index).into());
         }
+
+        Ok((piece_length - block_offset * Self::BLOCK_SIZE).min(Self::BLOCK_SIZE))
+    }
+
+    // Private torrents (BEP 27) may only get peers from their trackers, never
+    // from the DHT, PEX or local service discovery.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
     }
 
     pub fn get_piece_sha(&self, piece_index: usize) -> String {
@@ -65,7 +392,8 @@ impl Torrent {
         let start_idx = piece_index * Self::SHA_LENGTH;
         let end_idx = start_idx + Self::SHA_LENGTH;
 
-        hex::encode(&self.info.pieces[start_idx..end_idx])
+        let pieces = self.info.pieces.as_ref().expect("v2-only torrents don't have SHA-1 piece hashes");
+        hex::encode(&pieces[start_idx..end_idx])
     }
 }
 
@@ -75,43 +403,137 @@ pub struct AnnounceResponse {
     pub peers: ByteBuf,
 }
 
+// Reserved byte index and bit mask advertising Fast Extension support (BEP 6).
+pub const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+// Reserved byte index and bit mask advertising a DHT node (BEP 5).
+pub const DHT_BIT: (usize, u8) = (7, 0x01);
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct PeerInfo {
     pub id: ByteBuf,
+    pub reserved: ByteBuf,
+    // The client the peer runs, if its id says.
+    pub client: Option<PeerClient>,
 }
 
 impl PeerInfo {
+    pub const HANDSHAKE_LENGTH: usize = 68;
+
     pub fn from_bytes(
         input: Bytes,
-        decoded_info_hash: &Vec<u8>,
+        decoded_info_hash: &[u8],
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        // Check first byte (should be 19).
-        let first_byte = input.get(0).expect("Did not find first byte in peer info");
-        assert_eq!(*first_byte, b'\x13');
+        if input.len() < Self::HANDSHAKE_LENGTH {
+            return Err(format!("Peer handshake is {} bytes, expected {}", input.len(), Self::HANDSHAKE_LENGTH).into());
+        }
 
-        // Check magic string "BitTorrent protocol".
-        let magic_bytes = input
-            .get(1..20)
-            .expect("Did not find magic string in peer info");
-        assert_eq!(magic_bytes, b"BitTorrent protocol");
+        // Check first byte (should be 19) and the magic string "BitTorrent protocol".
+        if input[0] != b'\x13' || &input[1..20] != b"BitTorrent protocol" {
+            return Err("Peer did not send a BitTorrent handshake".into());
+        }
 
-        // Next 8 bytes are the zero bytes.
-        // let zero_bytes = input.get(20..28).expect("Did not find zero bytes in peer info");
-        // assert_eq!(zero_bytes, BytesMut::zeroed(8));
+        // Next 8 bytes are the reserved bytes, used to advertise protocol extensions.
+        let reserved_bytes = &input[20..28];
 
-        let info_hash_bytes = input
-            .get(28..48)
-            .expect("Did not get info hash in peer info");
-        assert_eq!(info_hash_bytes, decoded_info_hash);
+        let info_hash_bytes = &input[28..48];
+        if info_hash_bytes != decoded_info_hash {
+            return Err(format!("Peer sent a handshake for another torrent: {}", hex::encode(info_hash_bytes)).into());
+        }
 
-        let peer_id_bytes = input
-            .get(48..68)
-            .expect("Did not find peer id in peer info");
+        let peer_id_bytes = &input[48..68];
 
         Ok(Self {
             id: ByteBuf::from(peer_id_bytes),
+            reserved: ByteBuf::from(reserved_bytes),
+            client: identify_client(peer_id_bytes),
         })
     }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        let (byte, mask) = EXTENSION_PROTOCOL_BIT;
+        self.reserved.get(byte).is_some_and(|b| b & mask != 0)
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        let (byte, mask) = FAST_EXTENSION_BIT;
+        self.reserved.get(byte).is_some_and(|b| b & mask != 0)
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        let (byte, mask) = DHT_BIT;
+        self.reserved.get(byte).is_some_and(|b| b & mask != 0)
+    }
+}
+
+// What we know about the other end of a connection.
+#[derive(Debug)]
+pub struct PeerState {
+    // Whether the peer is choking us.
+    pub choked: bool,
+    pub supports_fast: bool,
+    // Pieces we may request even while choked.
+    pub allowed_fast: HashSet<u32>,
+    pub suggested: VecDeque<u32>,
+    // When the peer last sent us a block (or when we connected).
+    pub last_data: Instant,
+    pub snubbed: bool,
+    // Piece data received from the peer since we connected.
+    pub downloaded: u64,
+    pub connected_at: Instant,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        PeerState {
+            choked: true,
+            supports_fast: false,
+            allowed_fast: HashSet::new(),
+            suggested: VecDeque::new(),
+            last_data: Instant::now(),
+            snubbed: false,
+            downloaded: 0,
+            connected_at: Instant::now(),
+        }
+    }
+}
+
+impl PeerState {
+    pub fn can_request(&self, piece_index: u32) -> bool {
+        !self.choked || self.allowed_fast.contains(&piece_index)
+    }
+
+    pub fn data_received(&mut self, bytes: u64) {
+        self.last_data = Instant::now();
+        self.snubbed = false;
+        self.downloaded += bytes;
+    }
+
+    // Average bytes per second the peer has sent us.
+    pub fn download_rate(&self) -> f64 {
+        let elapsed = self.connected_at.elapsed().as_secs_f64();
+
+        if elapsed > 0.0 { self.downloaded as f64 / elapsed } else { 0.0 }
+    }
+
+    // A peer that hasn't sent us any data for a while is considered to be snubbing us.
+    pub fn check_snubbed(&mut self, snub_timeout: Duration) -> bool {
+        self.snubbed = self.last_data.elapsed() >= snub_timeout;
+        self.snubbed
+    }
+}
+
+pub fn has_piece(bitfield: &[u8], piece_index: usize) -> bool {
+    bitfield
+        .get(piece_index / 8)
+        .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+}
+
+pub fn set_piece(bitfield: &mut Vec<u8>, piece_index: usize) {
+    if bitfield.len() <= piece_index / 8 {
+        bitfield.resize(piece_index / 8 + 1, 0);
+    }
+
+    bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
@@ -126,11 +548,22 @@ pub enum PeerMessage {
     Request(RequestMessage),
     Piece(PieceMessage),
     Cancel(RequestMessage),
+    // The UDP port of the sender's DHT node.
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(RequestMessage),
+    AllowedFast(u32),
+    Extended(ExtendedMessage),
+    HashRequest(HashRequest),
+    Hashes(HashesMessage),
+    HashReject(HashRequest),
     Keepalive,
 }
 
 impl PeerMessage {
-    pub async fn from_stream(input: &mut BufReader<& mut TcpStream>) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn from_stream<R: AsyncRead + Unpin>(input: &mut R) -> Result<Self, Box<dyn std::error::Error>> {
         // Get the message length.
         let message_length = input.read_u32().await?;
 
@@ -151,16 +584,16 @@ impl PeerMessage {
                 Ok(PeerMessage::Have(have_idx))
             },
             5 => {
-                let mut payload = ByteBuf::with_capacity(message_length.try_into()?);
+                let mut payload = vec![0; message_length as usize - 1];
                 input.read_exact(&mut payload).await?;
-                Ok(PeerMessage::Bitfield(payload))
+                Ok(PeerMessage::Bitfield(ByteBuf::from(payload)))
             },
             6 => {
                 let index = input.read_u32().await?;
                 let begin = input.read_u32().await?;
                 let length = input.read_u32().await?;
 
-                Ok(PeerMessage::Request(RequestMessage { index: index.into(), begin: begin.into(), length: length.into() }))
+                Ok(PeerMessage::Request(RequestMessage { index, begin, length }))
             },
             7 => {
                 let index = input.read_u32().await?;
@@ -169,21 +602,70 @@ impl PeerMessage {
                 let piece_length = message_length - 2 * 4 - 1;
                 debug!("Reading piece data of length: {}", piece_length);
                 let mut piece_data = vec![0; piece_length as usize];
-                let bytes_read = input.read_exact(&mut piece_data).await?;
+                input.read_exact(&mut piece_data).await?;
 
-                Ok(PeerMessage::Piece(PieceMessage { index:  index.into(), begin: begin.into(), piece: piece_data }))
+                Ok(PeerMessage::Piece(PieceMessage { index, begin, piece: piece_data }))
             },
             8 => {
                 let index = input.read_u32().await?;
                 let begin = input.read_u32().await?;
                 let length = input.read_u32().await?;
 
-                Ok(PeerMessage::Cancel(RequestMessage { index: index.into(), begin: begin.into(), length: length.into() }))
+                Ok(PeerMessage::Cancel(RequestMessage { index, begin, length }))
+            },
+            9 => Ok(PeerMessage::Port(input.read_u16().await?)),
+            0x0D => Ok(PeerMessage::SuggestPiece(input.read_u32().await?)),
+            0x0E => Ok(PeerMessage::HaveAll),
+            0x0F => Ok(PeerMessage::HaveNone),
+            0x10 => {
+                let index = input.read_u32().await?;
+                let begin = input.read_u32().await?;
+                let length = input.read_u32().await?;
+
+                Ok(PeerMessage::RejectRequest(RequestMessage { index, begin, length }))
+            },
+            0x11 => Ok(PeerMessage::AllowedFast(input.read_u32().await?)),
+            0x15 => Ok(PeerMessage::HashRequest(HashRequest::from_stream(input, message_length).await?)),
+            0x16 => {
+                let request = HashRequest::from_stream(input, message_length).await?;
+
+                let mut payload = vec![0; message_length as usize - 1 - HashRequest::LENGTH];
+                input.read_exact(&mut payload).await?;
+
+                Ok(PeerMessage::Hashes(HashesMessage { request, hashes: merkle::split_hashes(&payload) }))
+            },
+            0x17 => Ok(PeerMessage::HashReject(HashRequest::from_stream(input, message_length).await?)),
+            EXTENDED_MESSAGE_ID => {
+                if message_length < 2 {
+                    return Err(format!("Extended message of length {} has no extension id", message_length).into());
+                }
+                let id = input.read_u8().await?;
+
+                let mut payload = vec![0; message_length as usize - 2];
+                input.read_exact(&mut payload).await?;
+
+                Ok(PeerMessage::Extended(ExtendedMessage { id, payload }))
             },
             _ => Err(format!("Invalid peer message type: {}", message_type).into())
         }
     }
 
+    // Size of the message on the wire, including the length prefix.
+    pub fn wire_length(&self) -> usize {
+        match self {
+            PeerMessage::Piece(piece) => 4 + 1 + 2 * 4 + piece.piece.len(),
+            message => message.to_bytes().len(),
+        }
+    }
+
+    // The part of the message that's piece data, as opposed to protocol overhead.
+    pub fn payload_length(&self) -> usize {
+        match self {
+            PeerMessage::Piece(piece) => piece.piece.len(),
+            _ => 0,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             PeerMessage::Choke => b"\x00\x00\x00\x01\x00".to_vec(),
@@ -209,11 +691,29 @@ impl PeerMessage {
                 buf.extend(bytes.iter());
                 buf
             },
-            PeerMessage::Request(req) | PeerMessage::Cancel(req) => {
+            PeerMessage::Port(port) => {
+                let mut buf = vec![];
+                let length: u32 = 3;
+                buf.extend_from_slice(&length.to_be_bytes());
+                buf.push(9);
+                buf.extend_from_slice(&port.to_be_bytes());
+                buf
+            },
+            PeerMessage::HaveAll => b"\x00\x00\x00\x01\x0E".to_vec(),
+            PeerMessage::HaveNone => b"\x00\x00\x00\x01\x0F".to_vec(),
+            PeerMessage::SuggestPiece(idx) | PeerMessage::AllowedFast(idx) => {
+                let mut buf = vec![];
+                let length: u32 = 5;
+                buf.extend_from_slice(&length.to_be_bytes());
+                buf.push(self.to_u8());
+                buf.extend_from_slice(&idx.to_be_bytes());
+                buf
+            },
+            PeerMessage::Request(req) | PeerMessage::Cancel(req) | PeerMessage::RejectRequest(req) => {
                 let mut buf: Vec<u8> = vec![];
                 let length: u32 = 3*4 + 1;
                 buf.extend_from_slice(&length.to_be_bytes());
-                buf.push(6);
+                buf.push(self.to_u8());
 
                 buf.extend(req.to_bytes());
 
@@ -224,14 +724,46 @@ impl PeerMessage {
 
                 let length: u32 = 2*4 + 1 + req.piece.len() as u32;
                 buf.extend_from_slice(&length.to_be_bytes());
-                buf.push(6);
+                buf.push(7);
 
                 buf.extend(req.to_bytes());
 
                 buf
             },
+            PeerMessage::Extended(ext) => {
+                let mut buf: Vec<u8> = vec![];
+
+                let length: u32 = (ext.payload.len() + 2).try_into().expect("Could not convert Peer message length to 4-byte integer");
+                buf.extend_from_slice(&length.to_be_bytes());
+                buf.push(EXTENDED_MESSAGE_ID);
+                buf.push(ext.id);
+
+                buf.extend(&ext.payload);
+                buf
+            },
+            PeerMessage::HashRequest(request) | PeerMessage::HashReject(request) => {
+                let mut buf = vec![];
+                let length = (HashRequest::LENGTH + 1) as u32;
+                buf.extend_from_slice(&length.to_be_bytes());
+                buf.push(self.to_u8());
+
+                buf.extend(request.to_bytes());
+                buf
+            },
+            PeerMessage::Hashes(hashes) => {
+                let mut buf = vec![];
+                let length = (HashRequest::LENGTH + 1 + hashes.hashes.len() * 32) as u32;
+                buf.extend_from_slice(&length.to_be_bytes());
+                buf.push(self.to_u8());
+
+                buf.extend(hashes.request.to_bytes());
+                for hash in &hashes.hashes {
+                    buf.extend(hash);
+                }
+                buf
+            },
             PeerMessage::Keepalive => {
-                vec![0]
+                vec![0; 4]
             },
         }
     }
@@ -247,13 +779,58 @@ impl PeerMessage {
             PeerMessage::Request(_) => 6,
             PeerMessage::Piece(_) => 7,
             PeerMessage::Cancel(_) => 8,
+            PeerMessage::Port(_) => 9,
+            PeerMessage::SuggestPiece(_) => 0x0D,
+            PeerMessage::HaveAll => 0x0E,
+            PeerMessage::HaveNone => 0x0F,
+            PeerMessage::RejectRequest(_) => 0x10,
+            PeerMessage::AllowedFast(_) => 0x11,
+            PeerMessage::Extended(_) => EXTENDED_MESSAGE_ID,
+            PeerMessage::HashRequest(_) => 0x15,
+            PeerMessage::Hashes(_) => 0x16,
+            PeerMessage::HashReject(_) => 0x17,
             _ => 9,
         }
     }
 }
 
+// Splits what a peer sends into messages. Bytes that arrive before a read is
+// cancelled, e.g. by a timeout, stay buffered for the next one, so unlike
+// `PeerMessage::from_stream` this never loses its place in the stream.
+#[derive(Debug, Default)]
+pub struct MessageReader {
+    buffer: BytesMut,
+}
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+impl MessageReader {
+    // Far more than a block, or the bitfield, hashes or extension messages
+    // of any reasonable torrent.
+    const MAX_MESSAGE_LENGTH: usize = 1 << 21;
+
+    pub async fn read_message<R: AsyncRead + Unpin>(&mut self, input: &mut R) -> Result<PeerMessage, Box<dyn std::error::Error>> {
+        loop {
+            if self.buffer.len() >= 4 {
+                let length = u32::from_be_bytes(self.buffer[..4].try_into().unwrap()) as usize;
+                if length > Self::MAX_MESSAGE_LENGTH {
+                    return Err(format!("Peer message of {} bytes is too long", length).into());
+                }
+
+                if self.buffer.len() >= 4 + length {
+                    let frame = self.buffer.split_to(4 + length);
+                    return PeerMessage::from_stream(&mut &frame[..]).await;
+                }
+                self.buffer.reserve(4 + length - self.buffer.len());
+            }
+
+            // Whatever this reads is in the buffer, even if we're cancelled later.
+            if input.read_buf(&mut self.buffer).await? == 0 {
+                return Err("Peer closed the connection".into());
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
 pub struct RequestMessage {
     pub index: u32,
     pub begin: u32,
@@ -261,7 +838,7 @@ pub struct RequestMessage {
 }
 
 impl RequestMessage {
-    fn to_bytes(&self) -> Vec<u8> {
+    fn to_bytes(self) -> Vec<u8> {
         let mut buf = vec![];
         buf.extend(self.index.to_be_bytes());
         buf.extend(self.begin.to_be_bytes());
@@ -271,6 +848,82 @@ impl RequestMessage {
     }
 }
 
+// Asks for hashes from a file's merkle tree (BEP 52): `length` hashes of
+// layer `base_layer` (0 being the blocks) starting at `index`, plus the uncle
+// hashes for `proof_layers` layers above them.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct HashRequest {
+    pub pieces_root: Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    const LENGTH: usize = 32 + 4 * 4;
+
+    // Requests for a file's whole piece layer, `num_pieces` hashes at
+    // `base_layer`, in chunks small enough for peers to answer. Each chunk
+    // asks for the uncles up to the root, so it can be checked on its own.
+    pub fn piece_layer_requests(pieces_root: Hash, base_layer: u32, num_pieces: usize) -> Vec<HashRequest> {
+        let width = num_pieces.next_power_of_two();
+        let length = width.min(merkle::MAX_REQUEST_HASHES);
+
+        (0..num_pieces)
+            .step_by(length)
+            .map(|index| HashRequest {
+                pieces_root,
+                base_layer,
+                index: index as u32,
+                length: length as u32,
+                proof_layers: (width / length).trailing_zeros(),
+            })
+            .collect()
+    }
+
+    // `message_length` is that of the whole message, which must have room for
+    // the request, so we don't read into the next one.
+    async fn from_stream<R: AsyncRead + Unpin>(input: &mut R, message_length: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        if (message_length as usize) < 1 + Self::LENGTH {
+            return Err(format!("Hash message of length {} is too short", message_length).into());
+        }
+
+        let mut pieces_root = [0; 32];
+        input.read_exact(&mut pieces_root).await?;
+
+        Ok(HashRequest {
+            pieces_root,
+            base_layer: input.read_u32().await?,
+            index: input.read_u32().await?,
+            length: input.read_u32().await?,
+            proof_layers: input.read_u32().await?,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = self.pieces_root.to_vec();
+        for value in [self.base_layer, self.index, self.length, self.proof_layers] {
+            buf.extend(value.to_be_bytes());
+        }
+
+        buf
+    }
+}
+
+// The requested hashes followed by the uncle hashes.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct HashesMessage {
+    pub request: HashRequest,
+    pub hashes: Vec<Hash>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct ExtendedMessage {
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct PieceMessage {
     pub index: u32,
@@ -299,3 +952,10 @@ pub fn calculate_info_hash(
 
     return Ok(format!("{:x}", hasher.finalize()));
 }
+
+// The SHA-256 info hash of v2 and hybrid torrents.
+pub fn calculate_info_hash_v2(torrent_info: &TorrentInfo) -> Result<String, Box<dyn std::error::Error>> {
+    let serialized = serde_bencode::to_bytes(&torrent_info)?;
+
+    Ok(hex::encode(sha256(&serialized)))
+}
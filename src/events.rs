@@ -0,0 +1,24 @@
+use std::net::SocketAddr;
+
+use tokio::sync::broadcast;
+
+// Events a `Client` emits while it works, for anyone who wants to follow along.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    PeerConnected { peer_id: String, addr: SocketAddr, client: Option<String> },
+    PeerDisconnected { peer_id: String },
+    PieceVerified { piece_index: u32, peer_id: String },
+    PieceFailed { piece_index: u32, peer_id: String, reason: String },
+    TrackerResponse { announce: String, peers: usize, interval: Option<i64> },
+    BytesTransferred { peer_id: String, downloaded: u64, uploaded: u64 },
+    TorrentCompleted { info_hash: String },
+    Error { message: String },
+}
+
+// Subscribers that fall this far behind start missing events.
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+pub fn event_channel() -> broadcast::Sender<ClientEvent> {
+    let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+    sender
+}
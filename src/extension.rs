@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+// Message id used for all extension protocol messages (BEP 10).
+pub const EXTENDED_MESSAGE_ID: u8 = 20;
+
+// Extended message id reserved for the extension handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+// Reserved byte index and bit mask advertising extension protocol support.
+pub const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Clone)]
+pub struct ExtensionHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    pub v: Option<String>,
+    pub p: Option<i64>,
+    pub reqq: Option<i64>,
+    pub metadata_size: Option<i64>,
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtensionHandshake {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_bencode::from_bytes(payload)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    // Extension ids of zero mean the extension was disabled by the peer.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(id) if *id > 0 => u8::try_from(*id).ok(),
+            _ => None,
+        }
+    }
+
+    // Later handshakes only carry the entries that changed, so merge them into
+    // what we already know about the peer.
+    pub fn merge(&mut self, update: ExtensionHandshake) {
+        for (name, id) in update.m {
+            if id == 0 {
+                self.m.remove(&name);
+            } else {
+                self.m.insert(name, id);
+            }
+        }
+
+        self.v = update.v.or(self.v.take());
+        self.p = update.p.or(self.p);
+        self.reqq = update.reqq.or(self.reqq);
+        self.metadata_size = update.metadata_size.or(self.metadata_size);
+        self.yourip = update.yourip.or(self.yourip.take());
+    }
+}
+
+// The ids we assign to the extensions we support. Peers use these ids when
+// sending extended messages to us.
+#[derive(Debug, Default)]
+pub struct ExtensionRegistry {
+    extensions: BTreeMap<String, u8>,
+}
+
+impl ExtensionRegistry {
+    pub fn register(&mut self, name: &str) -> u8 {
+        if let Some(id) = self.extensions.get(name) {
+            return *id;
+        }
+
+        let id = self.extensions.len() as u8 + 1;
+        self.extensions.insert(name.to_string(), id);
+
+        id
+    }
+
+    pub fn name(&self, id: u8) -> Option<&str> {
+        self.extensions
+            .iter()
+            .find(|(_, ext_id)| **ext_id == id)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn to_handshake_map(&self) -> BTreeMap<String, i64> {
+        self.extensions
+            .iter()
+            .map(|(name, id)| (name.clone(), *id as i64))
+            .collect()
+    }
+}
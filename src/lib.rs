@@ -1,7 +1,41 @@
+// Explicit returns are part of this codebase's style.
+#![allow(clippy::needless_return)]
+
+pub mod ban;
 pub mod bencode;
 pub mod client;
+pub mod config;
+pub mod creator;
+pub mod dht;
 pub mod domain;
+pub mod events;
+pub mod extension;
+pub mod listen;
 pub mod logging;
+pub mod lsd;
+pub mod merkle;
+pub mod mse;
+pub mod natpmp;
+pub mod peer_id;
+pub mod pex;
+pub mod picker;
+pub mod portmap;
+pub mod progress;
+pub mod proxy;
+pub mod random;
+pub mod ratelimit;
+pub mod retry;
+pub mod server;
+pub mod session;
+pub mod sha256;
+pub mod stats;
+pub mod storage;
+pub mod streaming;
 pub mod tests;
+pub mod tracker;
+pub mod transport;
+pub mod upnp;
+pub mod utp;
+pub mod webseed;
 
 pub use logging::get_logger;
\ No newline at end of file
@@ -0,0 +1,143 @@
+// Where we accept peer connections: one or more local addresses, all on the
+// same port, which is what we announce to trackers and peers.
+
+use std::{
+    fmt,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+// Ports tried when none is given, as clients traditionally do.
+pub const DEFAULT_PORT_RANGE: (u16, u16) = (6881, 6889);
+// A random port can be taken on another address by the time we bind there.
+const RANDOM_PORT_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenPort {
+    Fixed(u16),
+    // The first port in the range that's free on every address.
+    Range(u16, u16),
+    // Whatever port the operating system hands out.
+    Random,
+}
+
+impl Default for ListenPort {
+    fn default() -> Self {
+        ListenPort::Range(DEFAULT_PORT_RANGE.0, DEFAULT_PORT_RANGE.1)
+    }
+}
+
+impl FromStr for ListenPort {
+    type Err = String;
+
+    // "6881", "6881-6889" or "random".
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        let parse = |port: &str| port.trim().parse::<u16>().map_err(|_| format!("Invalid port: {}", input));
+
+        if input.eq_ignore_ascii_case("random") {
+            return Ok(ListenPort::Random);
+        }
+
+        match input.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first == 0 || first > last {
+                    return Err(format!("Invalid port range: {}", input));
+                }
+                Ok(ListenPort::Range(first, last))
+            },
+            None => match parse(input)? {
+                0 => Ok(ListenPort::Random),
+                port => Ok(ListenPort::Fixed(port)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ListenPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenPort::Fixed(port) => write!(f, "{}", port),
+            ListenPort::Range(first, last) => write!(f, "{}-{}", first, last),
+            ListenPort::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl TryFrom<String> for ListenPort {
+    type Error = String;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl From<ListenPort> for String {
+    fn from(port: ListenPort) -> Self {
+        port.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListenConfig {
+    // Every IPv4 interface when empty.
+    pub addresses: Vec<IpAddr>,
+    pub port: ListenPort,
+}
+
+impl ListenConfig {
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        if self.addresses.is_empty() {
+            return vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)];
+        }
+
+        self.addresses.clone()
+    }
+
+    // Binds a listener on every address, all on the same port.
+    pub async fn bind(&self) -> io::Result<Vec<TcpListener>> {
+        let ports = match self.port {
+            ListenPort::Fixed(port) => vec![port],
+            ListenPort::Range(first, last) => (first..=last).collect(),
+            ListenPort::Random => vec![0; RANDOM_PORT_ATTEMPTS],
+        };
+
+        let addresses = self.addresses();
+        let mut last_error = None;
+        for port in ports {
+            match bind_all(&addresses, port).await {
+                Ok(listeners) => return Ok(listeners),
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        Err(last_error.expect("There's always a port to try"))
+    }
+}
+
+impl From<SocketAddr> for ListenConfig {
+    fn from(addr: SocketAddr) -> Self {
+        let port = if addr.port() == 0 { ListenPort::Random } else { ListenPort::Fixed(addr.port()) };
+
+        ListenConfig { addresses: vec![addr.ip()], port }
+    }
+}
+
+async fn bind_all(addresses: &[IpAddr], port: u16) -> io::Result<Vec<TcpListener>> {
+    let first = TcpListener::bind((addresses[0], port)).await?;
+    // With port 0 the rest go on the port the first one got.
+    let port = first.local_addr()?.port();
+
+    let mut listeners = vec![first];
+    for address in &addresses[1..] {
+        listeners.push(TcpListener::bind((*address, port)).await?);
+    }
+
+    Ok(listeners)
+}
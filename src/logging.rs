@@ -1,4 +1,4 @@
-use std::{env, io, io::Write};
+use std::{env, io, io::{IsTerminal, Write}};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum LogLevel {
@@ -22,7 +22,10 @@ impl SimpleLogger {
             let output = io::stderr();
             let mut handle = output.lock();
 
-            writeln!(handle, "[{:?}] {}", level, message).expect("Failed to write to stderr");
+            // Clear any progress line being drawn so the message doesn't run into it.
+            let clear_line = if output.is_terminal() { "\r\x1b[2K" } else { "" };
+
+            writeln!(handle, "{}[{:?}] {}", clear_line, level, message).expect("Failed to write to stderr");
         }
     }
 
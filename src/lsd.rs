@@ -0,0 +1,232 @@
+// Local Service Discovery (BEP 14): peers on the same network find each other
+// by multicasting the info hashes they're downloading, along with the port
+// they listen on.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle, time::interval};
+
+use crate::{debug, random::random_u64};
+
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Keeps each announcement to a single small datagram.
+const MAX_INFO_HASHES_PER_ANNOUNCEMENT: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub port: u16,
+    // Hex encoded, in lowercase.
+    pub info_hashes: Vec<String>,
+    // Lets us recognize our own announcements when they loop back.
+    pub cookie: Option<String>,
+}
+
+impl Announcement {
+    pub fn to_bytes(&self, group: SocketAddrV4) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", group, self.port);
+        for info_hash in &self.info_hashes {
+            message += &format!("Infohash: {}\r\n", info_hash);
+        }
+        if let Some(cookie) = &self.cookie {
+            message += &format!("cookie: {}\r\n", cookie);
+        }
+        message += "\r\n\r\n";
+
+        message.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Announcement, String> {
+        let message = std::str::from_utf8(bytes).map_err(|_| "LSD announcement is not text".to_string())?;
+        let mut lines = message.split("\r\n");
+
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err("Not a BT-SEARCH announcement".to_string());
+        }
+
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or(format!("Invalid LSD header: {}", line))?;
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse::<u16>().map_err(|_| format!("Invalid LSD port: {}", value))?),
+                "infohash" => {
+                    if value.len() != 40 || hex::decode(value).is_err() {
+                        return Err(format!("Invalid LSD info hash: {}", value));
+                    }
+                    info_hashes.push(value.to_ascii_lowercase());
+                },
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {},
+            }
+        }
+
+        let port = port.filter(|port| *port != 0).ok_or("LSD announcement without a port")?;
+        if info_hashes.is_empty() {
+            return Err("LSD announcement without an info hash".to_string());
+        }
+
+        Ok(Announcement { port, info_hashes, cookie })
+    }
+}
+
+struct Subscription {
+    // None when we don't accept connections, so only listen for peers.
+    port: Option<u16>,
+    peers: mpsc::UnboundedSender<SocketAddr>,
+}
+
+type Subscriptions = Arc<Mutex<HashMap<String, Subscription>>>;
+
+// Announces the torrents added to it on the local network every few
+// minutes, and hands out the peers that other clients announce for them.
+pub struct LocalDiscovery {
+    socket: Arc<UdpSocket>,
+    group: SocketAddrV4,
+    cookie: String,
+    subscriptions: Subscriptions,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl LocalDiscovery {
+    // Joins `group`. With port 0 a free port is picked, which everyone taking
+    // part then has to use.
+    //
+    // The socket can't be bound with address reuse, so only one client on a
+    // machine can listen on the group's port: if another one already has it,
+    // this fails with `AddrInUse`, and while we have it, they can't join.
+    pub async fn bind(group: SocketAddrV4) -> io::Result<Arc<LocalDiscovery>> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port())).await?;
+        socket.join_multicast_v4(*group.ip(), Ipv4Addr::UNSPECIFIED)?;
+
+        let socket = Arc::new(socket);
+        let group = SocketAddrV4::new(*group.ip(), socket.local_addr()?.port());
+        // Only tells our own announcements apart, so it needn't be secure.
+        let cookie = format!("{:016x}", random_u64());
+        let subscriptions: Subscriptions = Arc::new(Mutex::new(HashMap::new()));
+
+        let tasks = vec![
+            tokio::spawn(receive_loop(socket.clone(), cookie.clone(), subscriptions.clone())),
+            tokio::spawn(announce_loop(socket.clone(), group, cookie.clone(), subscriptions.clone())),
+        ];
+
+        Ok(Arc::new(LocalDiscovery { socket, group, cookie, subscriptions, tasks }))
+    }
+
+    pub fn group(&self) -> SocketAddrV4 {
+        self.group
+    }
+
+    // Starts announcing a torrent that we accept connections for on `port`,
+    // if there is one. Peers announcing it come out of the returned channel,
+    // and dropping it stops the announcements.
+    pub async fn add_torrent(&self, info_hash: &str, port: Option<u16>) -> mpsc::UnboundedReceiver<SocketAddr> {
+        let info_hash = info_hash.to_ascii_lowercase();
+        let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+        self.subscriptions.lock().unwrap().insert(info_hash.clone(), Subscription { port, peers: peers_tx });
+
+        if let Some(port) = port {
+            let announcement = Announcement { port, info_hashes: vec![info_hash], cookie: Some(self.cookie.clone()) };
+            send_announcement(&self.socket, self.group, &announcement).await;
+        }
+
+        peers_rx
+    }
+
+    // Info hashes we're looking for peers for.
+    pub fn torrents(&self) -> Vec<String> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|_, subscription| !subscription.peers.is_closed());
+
+        let mut torrents: Vec<String> = subscriptions.keys().cloned().collect();
+        torrents.sort();
+        torrents
+    }
+}
+
+impl Drop for LocalDiscovery {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn send_announcement(socket: &UdpSocket, group: SocketAddrV4, announcement: &Announcement) {
+    if let Err(err) = socket.send_to(&announcement.to_bytes(group), group).await {
+        debug!("Could not send LSD announcement: {}", err);
+    }
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, cookie: String, subscriptions: Subscriptions) {
+    let mut buffer = vec![0; 2048];
+
+    loop {
+        let (length, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                debug!("Could not receive LSD announcement: {}", err);
+                continue;
+            },
+        };
+
+        let announcement = match Announcement::from_bytes(&buffer[..length]) {
+            Ok(announcement) => announcement,
+            Err(err) => {
+                debug!("Ignoring LSD message from {}: {}", addr, err);
+                continue;
+            },
+        };
+        if announcement.cookie.as_ref() == Some(&cookie) {
+            continue;
+        }
+
+        let peer = SocketAddr::new(addr.ip(), announcement.port);
+        let subscriptions = subscriptions.lock().unwrap();
+        for info_hash in &announcement.info_hashes {
+            if let Some(subscription) = subscriptions.get(info_hash) {
+                debug!("Found peer {} for {} through LSD", peer, info_hash);
+                let _ = subscription.peers.send(peer);
+            }
+        }
+    }
+}
+
+async fn announce_loop(socket: Arc<UdpSocket>, group: SocketAddrV4, cookie: String, subscriptions: Subscriptions) {
+    let mut ticks = interval(ANNOUNCE_INTERVAL);
+    // Torrents are announced as they're added, so skip the immediate tick.
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+
+        let mut by_port: HashMap<u16, Vec<String>> = HashMap::new();
+        {
+            let mut subscriptions = subscriptions.lock().unwrap();
+            subscriptions.retain(|_, subscription| !subscription.peers.is_closed());
+            for (info_hash, subscription) in subscriptions.iter() {
+                if let Some(port) = subscription.port {
+                    by_port.entry(port).or_default().push(info_hash.clone());
+                }
+            }
+        }
+
+        for (port, info_hashes) in by_port {
+            for chunk in info_hashes.chunks(MAX_INFO_HASHES_PER_ANNOUNCEMENT) {
+                let announcement = Announcement { port, info_hashes: chunk.to_vec(), cookie: Some(cookie.clone()) };
+                send_announcement(&socket, group, &announcement).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Merkle trees of v2 torrents (BEP 52). Every file has its own tree over the
+// SHA-256 hashes of its 16KiB blocks, padded with zero hashes to a power of
+// two, and its root is the file's "pieces root".
+
+use crate::sha256::{sha256, Sha256};
+
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+// The most hashes a peer has to send for one hash request.
+pub const MAX_REQUEST_HASHES: usize = 512;
+
+pub type Hash = [u8; 32];
+
+// Leaves past the end of a file.
+pub const ZERO_HASH: Hash = [0; 32];
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+// Hashes of the 16KiB blocks of `data`. The last block may be shorter.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(sha256).collect()
+}
+
+// Root of a tree whose bottom layer is `leaves` padded with `pad` to `width`,
+// which has to be a power of two.
+pub fn root(leaves: &[Hash], width: usize, pad: Hash) -> Hash {
+    let mut layer = leaves.to_vec();
+    layer.resize(width.max(1), pad);
+
+    while layer.len() > 1 {
+        layer = layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+    }
+
+    layer[0]
+}
+
+// Root of a tree of 2^height zero leaves.
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold(ZERO_HASH, |hash, _| hash_pair(&hash, &hash))
+}
+
+// How far the piece layer is above the blocks.
+pub fn piece_layer_height(piece_length: usize) -> u32 {
+    (piece_length / BLOCK_SIZE).max(1).trailing_zeros()
+}
+
+// Root of the subtree covering one piece of a file bigger than a piece. The
+// last piece is padded to the full piece size.
+pub fn piece_root(data: &[u8], piece_length: usize) -> Hash {
+    root(&block_hashes(data), piece_length / BLOCK_SIZE, ZERO_HASH)
+}
+
+// A file's pieces root, given its piece layer. Missing pieces at the end count
+// as all-zero pieces.
+pub fn layer_root(layer: &[Hash], piece_length: usize) -> Hash {
+    root(layer, layer.len().next_power_of_two(), pad_hash(piece_layer_height(piece_length)))
+}
+
+// The pieces root and the piece layer of a file. Files that fit in a single
+// piece have no piece layer, and their tree is only as wide as it needs to be.
+pub fn file_hashes(data: &[u8], piece_length: usize) -> (Hash, Vec<Hash>) {
+    if data.len() <= piece_length {
+        let blocks = block_hashes(data);
+        return (root(&blocks, blocks.len().next_power_of_two(), ZERO_HASH), vec![]);
+    }
+
+    let layer: Vec<Hash> = data.chunks(piece_length).map(|piece| piece_root(piece, piece_length)).collect();
+    (layer_root(&layer, piece_length), layer)
+}
+
+pub fn split_hashes(bytes: &[u8]) -> Vec<Hash> {
+    bytes.chunks_exact(32).map(|hash| hash.try_into().unwrap()).collect()
+}
+
+// Answers a hash request: `length` hashes of `layer`, starting at `index`,
+// followed by the uncle hashes for up to `proof_layers` layers above them.
+// The layer is padded with `pad` to a power of two.
+pub fn layer_hashes(layer: &[Hash], pad: Hash, index: usize, length: usize, proof_layers: usize) -> Option<(Vec<Hash>, Vec<Hash>)> {
+    let width = layer.len().next_power_of_two();
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > width {
+        return None;
+    }
+
+    let mut levels = vec![layer.to_vec()];
+    levels[0].resize(width, pad);
+    while levels[levels.len() - 1].len() > 1 {
+        let above = levels[levels.len() - 1].chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+        levels.push(above);
+    }
+
+    let hashes = levels[0][index..index + length].to_vec();
+
+    let mut position = index / length;
+    let proof = levels[length.trailing_zeros() as usize..levels.len() - 1]
+        .iter()
+        .take(proof_layers)
+        .map(|level| {
+            let uncle = level[position ^ 1];
+            position /= 2;
+            uncle
+        })
+        .collect();
+
+    Some((hashes, proof))
+}
+
+// Checks hashes from a layer `width` wide, starting at `index`, against the
+// root using the uncle hashes in `proof`, which have to go all the way up.
+pub fn verify_hashes(root: &Hash, width: usize, hashes: &[Hash], index: usize, proof: &[Hash]) -> bool {
+    if !hashes.len().is_power_of_two() || !index.is_multiple_of(hashes.len()) || hashes.len() << proof.len() != width {
+        return false;
+    }
+
+    let mut node = self::root(hashes, hashes.len(), ZERO_HASH);
+    let mut position = index / hashes.len();
+
+    for uncle in proof {
+        node = if position.is_multiple_of(2) { hash_pair(&node, uncle) } else { hash_pair(uncle, &node) };
+        position /= 2;
+    }
+
+    node == *root
+}
@@ -0,0 +1,522 @@
+// Message Stream Encryption, also known as protocol encryption: a
+// Diffie-Hellman key exchange followed by RC4, which hides BitTorrent
+// connections from traffic shaping. It's obfuscation rather than real security.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    random::{os_random_bytes, random_bytes, random_u64},
+    transport::Transport,
+};
+
+type MseError = Box<dyn std::error::Error + Send + Sync>;
+
+// The 768-bit prime of the key exchange, with generator 2.
+const PRIME: [u8; 96] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u8 = 2;
+const KEY_LENGTH: usize = 96;
+const PRIVATE_KEY_LENGTH: usize = 20;
+// Random padding after the public keys, and the most a peer may send.
+const MAX_PADDING: usize = 512;
+// The verification constant that marks where the encrypted part starts.
+const VC: [u8; 8] = [0; 8];
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    // Only plain BitTorrent connections.
+    #[default]
+    Plaintext,
+    // Try encrypted connections first but fall back to plaintext, and accept both.
+    Prefer,
+    // Only encrypted connections, both ways.
+    Require,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_ascii_lowercase().as_str() {
+            "plaintext" | "disabled" => Ok(EncryptionPolicy::Plaintext),
+            "prefer" | "enabled" => Ok(EncryptionPolicy::Prefer),
+            "require" | "forced" => Ok(EncryptionPolicy::Require),
+            _ => Err(format!("Unknown encryption policy: {}", input)),
+        }
+    }
+}
+
+// RC4, with the first 1024 bytes of key stream dropped as MSE asks.
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        let mut rc4 = Rc4 { state, i: 0, j: 0 };
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    // Encrypts or decrypts in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+// A peer connection over TCP or uTP, which may be encrypted.
+pub struct PeerStream {
+    stream: Transport,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    // Already decrypted bytes that are read before the connection's.
+    buffered: Vec<u8>,
+}
+
+impl PeerStream {
+    pub fn plaintext(stream: impl Into<Transport>) -> Self {
+        PeerStream { stream: stream.into(), decrypt: None, encrypt: None, buffered: vec![] }
+    }
+
+    fn encrypted(stream: Transport, decrypt: Rc4, encrypt: Rc4) -> Self {
+        PeerStream { stream, decrypt: Some(decrypt), encrypt: Some(encrypt), buffered: vec![] }
+    }
+
+    // Puts `data` back in front of whatever hasn't been read yet.
+    pub fn unread(&mut self, data: &[u8]) {
+        self.buffered.splice(..0, data.iter().copied());
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn is_utp(&self) -> bool {
+        self.stream.is_utp()
+    }
+}
+
+impl std::fmt::Debug for PeerStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerStream").field("stream", &self.stream).field("encrypted", &self.is_encrypted()).finish()
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let length = this.buffered.len().min(buf.remaining());
+            buf.put_slice(&this.buffered[..length]);
+            this.buffered.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.decrypt {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let cipher = match &mut this.encrypt {
+            Some(cipher) => cipher,
+            None => return Pin::new(&mut this.stream).poll_write(cx, buf),
+        };
+
+        // Encrypt with a copy of the cipher, and only move the real one past
+        // the bytes the socket took.
+        let mut ahead = cipher.clone();
+        let mut encrypted = buf.to_vec();
+        ahead.apply(&mut encrypted);
+
+        let written = ready!(Pin::new(&mut this.stream).poll_write(cx, &encrypted))?;
+        if written == buf.len() {
+            *cipher = ahead;
+        } else {
+            cipher.apply(&mut encrypted[..written]);
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+// Opens an encrypted connection as the side that connected, sending `payload`
+// (our BitTorrent handshake) along with the key exchange. With the prefer
+// policy the other side may pick a plaintext stream after the handshake.
+pub async fn initiate(stream: impl Into<Transport>, info_hash: &[u8], payload: &[u8], policy: EncryptionPolicy) -> Result<PeerStream, MseError> {
+    let provide = match policy {
+        EncryptionPolicy::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+
+    let mut stream = stream.into();
+    let keys = KeyPair::generate()?;
+    stream.write_all(&[keys.public.clone(), random_padding()].concat()).await?;
+
+    let mut their_public = [0; KEY_LENGTH];
+    stream.read_exact(&mut their_public).await?;
+    let secret = keys.shared_secret(&their_public)?;
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut encrypted = VC.to_vec();
+    encrypted.extend(provide.to_be_bytes());
+    encrypted.extend(0u16.to_be_bytes());
+    encrypted.extend((payload.len() as u16).to_be_bytes());
+    encrypted.extend(payload);
+    encrypt.apply(&mut encrypted);
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(&hash(&[b"req2", info_hash]), &hash(&[b"req3", &secret])));
+    message.extend(encrypted);
+    stream.write_all(&message).await?;
+
+    // Their answer starts with VC, encrypted, somewhere after their padding.
+    let mut marker = VC;
+    decrypt.apply(&mut marker);
+    sync_to(&mut stream, &marker, MAX_PADDING + marker.len()).await?;
+
+    let mut header = [0; 6];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    let select = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let padding_length = u16::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+
+    if padding_length > MAX_PADDING {
+        return Err(format!("Peer sent {} bytes of padding", padding_length).into());
+    }
+    let mut padding = vec![0; padding_length];
+    stream.read_exact(&mut padding).await?;
+    decrypt.apply(&mut padding);
+
+    match select {
+        CRYPTO_RC4 => Ok(PeerStream::encrypted(stream, decrypt, encrypt)),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => Ok(PeerStream::plaintext(stream)),
+        _ => Err(format!("Peer selected unsupported crypto method {:#x}", select).into()),
+    }
+}
+
+// What the side that connected sent during an encrypted handshake.
+pub struct AcceptedStream {
+    pub stream: PeerStream,
+    pub info_hash: Vec<u8>,
+    // The start of what the peer sends once encrypted: usually its BitTorrent
+    // handshake, which may be cut short or followed by other messages, but it
+    // may also be empty.
+    pub payload: Vec<u8>,
+}
+
+// Answers an encrypted handshake from a peer that connected to us. `received`
+// is what has been read off the connection already, and the torrent is
+// identified by trying every info hash in `info_hashes`.
+pub async fn accept(stream: impl Into<Transport>, received: &[u8], info_hashes: &[Vec<u8>], policy: EncryptionPolicy) -> Result<AcceptedStream, MseError> {
+    if policy == EncryptionPolicy::Plaintext {
+        return Err("Encrypted connections are disabled".into());
+    }
+    if received.len() > KEY_LENGTH {
+        return Err("Read past the peer's public key".into());
+    }
+
+    let mut stream = stream.into();
+    let mut their_public = received.to_vec();
+    their_public.resize(KEY_LENGTH, 0);
+    stream.read_exact(&mut their_public[received.len()..]).await?;
+
+    let keys = KeyPair::generate()?;
+    stream.write_all(&[keys.public.clone(), random_padding()].concat()).await?;
+    let secret = keys.shared_secret(&their_public)?;
+
+    sync_to(&mut stream, &hash(&[b"req1", &secret]), MAX_PADDING + 20).await?;
+
+    let mut obfuscated = [0; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| xor(&hash(&[b"req2", info_hash]), &req3) == obfuscated)
+        .ok_or("Encrypted handshake for an unknown torrent")?
+        .clone();
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut header = [0; 14];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        return Err("Bad verification constant in encrypted handshake".into());
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let padding_length = u16::from_be_bytes(header[12..].try_into().unwrap()) as usize;
+
+    if padding_length > MAX_PADDING {
+        return Err(format!("Peer sent {} bytes of padding", padding_length).into());
+    }
+    let mut padding = vec![0; padding_length + 2];
+    stream.read_exact(&mut padding).await?;
+    decrypt.apply(&mut padding);
+    let payload_length = u16::from_be_bytes(padding[padding_length..].try_into().unwrap()) as usize;
+
+    let mut payload = vec![0; payload_length];
+    stream.read_exact(&mut payload).await?;
+    decrypt.apply(&mut payload);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Require {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(format!("No acceptable crypto method in {:#x}", provide).into());
+    };
+
+    let mut reply = VC.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    let stream = match select {
+        CRYPTO_RC4 => PeerStream::encrypted(stream, decrypt, encrypt),
+        _ => PeerStream::plaintext(stream),
+    };
+
+    Ok(AcceptedStream { stream, info_hash, payload })
+}
+
+// Reads until `marker` has gone by, giving up after `limit` bytes.
+async fn sync_to(stream: &mut Transport, marker: &[u8], limit: usize) -> Result<(), MseError> {
+    let mut window = vec![];
+
+    while !window.ends_with(marker) {
+        if window.len() >= limit {
+            return Err("Could not find the end of the peer's padding".into());
+        }
+        window.push(stream.read_u8().await?);
+    }
+
+    Ok(())
+}
+
+// The padding length only needs to vary, so it's not cryptographically random.
+fn random_padding() -> Vec<u8> {
+    random_bytes((random_u64() % (MAX_PADDING as u64 + 1)) as usize)
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0; 20];
+    for (out, (a, b)) in out.iter_mut().zip(a.iter().zip(b)) {
+        *out = a ^ b;
+    }
+    out
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: Vec<u8>,
+}
+
+impl KeyPair {
+    // Fails without a secure source of randomness, since a guessable private
+    // key would let anyone read the connection.
+    fn generate() -> Result<Self, MseError> {
+        let private_bytes = os_random_bytes(PRIVATE_KEY_LENGTH).map_err(|err| format!("Could not generate a private key: {}", err))?;
+        let private = BigUint::from_bytes_be(&private_bytes);
+        let public = BigUint::from_bytes_be(&[GENERATOR]).mod_pow(&private, &BigUint::from_bytes_be(&PRIME));
+
+        Ok(KeyPair { private, public: public.to_bytes_be(KEY_LENGTH) })
+    }
+
+    fn shared_secret(&self, their_public: &[u8]) -> Result<Vec<u8>, MseError> {
+        let prime = BigUint::from_bytes_be(&PRIME);
+        let their_public = BigUint::from_bytes_be(their_public);
+        // Keys of 0, 1 and p - 1 would give a predictable secret.
+        if their_public.bits() <= 1 || !their_public.less_than(&prime) || their_public == prime.minus_one() {
+            return Err("Peer sent an invalid public key".into());
+        }
+
+        Ok(their_public.mod_pow(&self.private, &prime).to_bytes_be(KEY_LENGTH))
+    }
+}
+
+// Just enough unsigned big number arithmetic for the key exchange, in
+// little-endian 32-bit limbs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BigUint(Vec<u32>);
+
+impl BigUint {
+    fn from_bytes_be(bytes: &[u8]) -> Self {
+        let mut limbs = vec![0u32; bytes.len().div_ceil(4)];
+        for (index, byte) in bytes.iter().rev().enumerate() {
+            limbs[index / 4] |= (*byte as u32) << (8 * (index % 4));
+        }
+        BigUint(limbs)
+    }
+
+    fn to_bytes_be(&self, length: usize) -> Vec<u8> {
+        (0..length)
+            .rev()
+            .map(|index| (self.0.get(index / 4).copied().unwrap_or(0) >> (8 * (index % 4))) as u8)
+            .collect()
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0.get(index / 32).is_some_and(|limb| (limb >> (index % 32)) & 1 == 1)
+    }
+
+    fn bits(&self) -> usize {
+        match self.0.iter().rposition(|limb| *limb != 0) {
+            Some(top) => top * 32 + 32 - self.0[top].leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    fn less_than(&self, other: &BigUint) -> bool {
+        let length = self.0.len().max(other.0.len());
+        for index in (0..length).rev() {
+            let (a, b) = (self.0.get(index).copied().unwrap_or(0), other.0.get(index).copied().unwrap_or(0));
+            if a != b {
+                return a < b;
+            }
+        }
+        false
+    }
+
+    fn minus_one(&self) -> BigUint {
+        let mut result = self.clone();
+        for limb in result.0.iter_mut() {
+            let (value, borrow) = limb.overflowing_sub(1);
+            *limb = value;
+            if !borrow {
+                break;
+            }
+        }
+        result
+    }
+
+    // Subtracts a smaller number in place.
+    fn subtract(&mut self, other: &BigUint) {
+        let mut borrow = 0i64;
+        for index in 0..self.0.len() {
+            let value = self.0[index] as i64 - other.0.get(index).copied().unwrap_or(0) as i64 - borrow;
+            borrow = (value < 0) as i64;
+            self.0[index] = value.rem_euclid(1 << 32) as u32;
+        }
+    }
+
+    fn multiply(&self, other: &BigUint) -> BigUint {
+        let mut product = vec![0u32; self.0.len() + other.0.len()];
+        for (i, a) in self.0.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, b) in other.0.iter().enumerate() {
+                let value = product[i + j] as u64 + *a as u64 * *b as u64 + carry;
+                product[i + j] = value as u32;
+                carry = value >> 32;
+            }
+            product[i + other.0.len()] = carry as u32;
+        }
+        BigUint(product)
+    }
+
+    // Shift and subtract, a bit at a time.
+    fn remainder(&self, modulus: &BigUint) -> BigUint {
+        let mut remainder = BigUint(vec![0; modulus.0.len() + 1]);
+
+        for index in (0..self.bits()).rev() {
+            let mut carry = self.bit(index) as u32;
+            for limb in remainder.0.iter_mut() {
+                let next = *limb >> 31;
+                *limb = (*limb << 1) | carry;
+                carry = next;
+            }
+
+            if !remainder.less_than(modulus) {
+                remainder.subtract(modulus);
+            }
+        }
+
+        remainder
+    }
+
+    fn mod_pow(&self, exponent: &BigUint, modulus: &BigUint) -> BigUint {
+        let base = self.remainder(modulus);
+        let mut result = BigUint(vec![1]);
+
+        for index in (0..exponent.bits()).rev() {
+            result = result.multiply(&result).remainder(modulus);
+            if exponent.bit(index) {
+                result = result.multiply(&base).remainder(modulus);
+            }
+        }
+
+        result
+    }
+}
@@ -0,0 +1,223 @@
+// Port mapping with NAT-PMP (RFC 6886) and its successor PCP (RFC 6887).
+// Both talk to the gateway over UDP on port 5351. PCP is tried first, and
+// gateways that only speak NAT-PMP answer it with an unsupported version.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::{
+    debug,
+    portmap::{PortMapping, Protocol},
+    random::random_bytes,
+};
+
+pub const NAT_PMP_PORT: u16 = 5351;
+
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP_OPCODE: u8 = 1;
+const PCP_MAP_LENGTH: usize = 60;
+// Both protocols use result code 1 for a version they don't speak.
+const UNSUPPORTED_VERSION: u16 = 1;
+// Requests are resent with the timeout doubling each time, from 250ms.
+const ATTEMPTS: u32 = 4;
+const FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Pcp,
+    NatPmp,
+}
+
+#[derive(Debug)]
+pub struct NatPmpGateway {
+    addr: SocketAddr,
+    // Unknown until the gateway answers a PCP request.
+    version: Option<Version>,
+    // PCP ties a mapping to its nonce, so renewals and deletions reuse it.
+    nonce: [u8; 12],
+}
+
+impl NatPmpGateway {
+    pub fn new(addr: SocketAddr) -> NatPmpGateway {
+        // Only has to differ from other clients' behind the same gateway,
+        // the non-cryptographic generator will do.
+        let nonce = random_bytes(12).try_into().unwrap();
+
+        NatPmpGateway { addr, version: None, nonce }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_pcp(&self) -> bool {
+        self.version == Some(Version::Pcp)
+    }
+
+    // Maps `internal_port` to the same port outside, or whatever the gateway
+    // picks instead. A lifetime of zero removes the mapping.
+    pub async fn map(&mut self, protocol: Protocol, internal_port: u16, lifetime: Duration) -> Result<PortMapping, Box<dyn std::error::Error>> {
+        if self.version != Some(Version::NatPmp) {
+            match self.map_pcp(protocol, internal_port, lifetime).await? {
+                Some(mapping) => {
+                    self.version = Some(Version::Pcp);
+                    return Ok(mapping);
+                },
+                None => {
+                    debug!("Gateway {} does not speak PCP, falling back to NAT-PMP", self.addr);
+                    self.version = Some(Version::NatPmp);
+                },
+            }
+        }
+
+        self.map_nat_pmp(protocol, internal_port, lifetime).await
+    }
+
+    pub async fn unmap(&mut self, protocol: Protocol, internal_port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        self.map(protocol, internal_port, Duration::ZERO).await?;
+        Ok(())
+    }
+
+    // None when the gateway only speaks NAT-PMP.
+    async fn map_pcp(&self, protocol: Protocol, internal_port: u16, lifetime: Duration) -> Result<Option<PortMapping>, Box<dyn std::error::Error>> {
+        let socket = UdpSocket::bind(unspecified_addr(self.addr)).await?;
+        socket.connect(self.addr).await?;
+        let client_ip = socket.local_addr()?.ip();
+
+        let mut request = vec![PCP_VERSION, PCP_MAP_OPCODE, 0, 0];
+        request.extend(lifetime_secs(lifetime).to_be_bytes());
+        request.extend(mapped_ipv6(client_ip).octets());
+        request.extend(self.nonce);
+        request.extend([protocol.number(), 0, 0, 0]);
+        request.extend(internal_port.to_be_bytes());
+        request.extend(internal_port.to_be_bytes());
+        // Any external address will do.
+        request.extend(mapped_ipv6(IpAddr::V4(Ipv4Addr::UNSPECIFIED)).octets());
+
+        let response = request_response(&socket, &request).await?;
+        if response.first() == Some(&NAT_PMP_VERSION) {
+            return Ok(None);
+        }
+        if response.len() < PCP_MAP_LENGTH || response[1] != 0x80 | PCP_MAP_OPCODE {
+            return Err(format!("Invalid PCP response from {}", self.addr).into());
+        }
+
+        let result = response[3] as u16;
+        if result == UNSUPPORTED_VERSION {
+            return Ok(None);
+        }
+        if result != 0 {
+            return Err(format!("PCP gateway {} refused mapping: result code {}", self.addr, result).into());
+        }
+        if response[24..36] != self.nonce {
+            return Err(format!("PCP response from {} has the wrong nonce", self.addr).into());
+        }
+
+        let lifetime = u32::from_be_bytes(response[4..8].try_into().unwrap());
+        let external_port = u16::from_be_bytes(response[42..44].try_into().unwrap());
+        let external_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&response[44..60]).unwrap());
+        let external_ip = match external_ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(external_ip),
+        };
+
+        Ok(Some(PortMapping {
+            protocol,
+            internal_port,
+            external: SocketAddr::new(external_ip, external_port),
+            lifetime: Duration::from_secs(lifetime.into()),
+        }))
+    }
+
+    async fn map_nat_pmp(&self, protocol: Protocol, internal_port: u16, lifetime: Duration) -> Result<PortMapping, Box<dyn std::error::Error>> {
+        let socket = UdpSocket::bind(unspecified_addr(self.addr)).await?;
+        socket.connect(self.addr).await?;
+
+        let opcode = match protocol {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        };
+        let mut request = vec![NAT_PMP_VERSION, opcode, 0, 0];
+        request.extend(internal_port.to_be_bytes());
+        // Removing a mapping asks for external port 0.
+        let suggested_port = if lifetime.is_zero() { 0 } else { internal_port };
+        request.extend(suggested_port.to_be_bytes());
+        request.extend(lifetime_secs(lifetime).to_be_bytes());
+
+        let response = request_response(&socket, &request).await?;
+        check_nat_pmp_response(&response, opcode, 16, self.addr)?;
+
+        let external_port = u16::from_be_bytes(response[10..12].try_into().unwrap());
+        let lifetime = u32::from_be_bytes(response[12..16].try_into().unwrap());
+        // NAT-PMP mappings don't say which address they're on.
+        let external_ip = if lifetime == 0 { Ipv4Addr::UNSPECIFIED } else { self.external_ip(&socket).await? };
+
+        Ok(PortMapping {
+            protocol,
+            internal_port,
+            external: SocketAddr::new(IpAddr::V4(external_ip), external_port),
+            lifetime: Duration::from_secs(lifetime.into()),
+        })
+    }
+
+    async fn external_ip(&self, socket: &UdpSocket) -> Result<Ipv4Addr, Box<dyn std::error::Error>> {
+        let response = request_response(socket, &[NAT_PMP_VERSION, 0]).await?;
+        check_nat_pmp_response(&response, 0, 12, self.addr)?;
+
+        Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+    }
+}
+
+fn check_nat_pmp_response(response: &[u8], opcode: u8, length: usize, gateway: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    if response.len() < length || response[0] != NAT_PMP_VERSION || response[1] != 0x80 | opcode {
+        return Err(format!("Invalid NAT-PMP response from {}", gateway).into());
+    }
+
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(format!("NAT-PMP gateway {} refused request: result code {}", gateway, result).into());
+    }
+
+    Ok(())
+}
+
+// Sends `request` until the gateway answers, doubling the timeout each time.
+async fn request_response(socket: &UdpSocket, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut wait = FIRST_TIMEOUT;
+    let mut buffer = vec![0; 1100];
+
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await?;
+        if let Ok(received) = timeout(wait, socket.recv(&mut buffer)).await {
+            let length = received?;
+            return Ok(buffer[..length].to_vec());
+        }
+        wait *= 2;
+    }
+
+    Err(io::Error::new(io::ErrorKind::TimedOut, "Gateway did not answer"))
+}
+
+fn lifetime_secs(lifetime: Duration) -> u32 {
+    lifetime.as_secs().min(u32::MAX as u64) as u32
+}
+
+fn mapped_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn unspecified_addr(gateway: SocketAddr) -> SocketAddr {
+    match gateway {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
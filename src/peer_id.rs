@@ -0,0 +1,157 @@
+// Peer ids: generating ours in Azureus style, and working out which client
+// (and which version of it) another peer runs from the id it sent.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::random::random_bytes;
+
+pub const PEER_ID_LENGTH: usize = 20;
+
+// Azureus style: a dash, a two letter client code, four version characters
+// and another dash.
+pub const DEFAULT_CLIENT_PREFIX: &str = "-CC0100-";
+
+const RANDOM_CHARACTERS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// Shadow style version characters, each standing for its index.
+const SHADOW_CHARACTERS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BT", "BitTorrent"),
+    ("CC", "bittorrent-starter-rust"),
+    ("CD", "Enhanced CTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UM", "uTorrent Mac"),
+    ("UT", "uTorrent"),
+    ("UW", "uTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// The client a peer id belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerClient {
+    pub name: String,
+    pub version: String,
+}
+
+impl fmt::Display for PeerClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+// A fresh peer id: the prefix followed by random letters and digits.
+pub fn generate_peer_id(prefix: &str) -> Result<String, String> {
+    if prefix.len() > PEER_ID_LENGTH || !prefix.is_ascii() {
+        return Err(format!("Peer id prefix must be at most {} ASCII characters: {}", PEER_ID_LENGTH, prefix));
+    }
+
+    // Peer ids are public anyway, the non-cryptographic generator will do.
+    let suffix: String = random_bytes(PEER_ID_LENGTH - prefix.len())
+        .into_iter()
+        .map(|byte| RANDOM_CHARACTERS[byte as usize % RANDOM_CHARACTERS.len()] as char)
+        .collect();
+
+    Ok(format!("{}{}", prefix, suffix))
+}
+
+// Recognizes Azureus style ids like "-TR2940-", Shadow style ones like
+// "T03I--" and Mainline ones like "M4-3-6--".
+pub fn identify_client(peer_id: &[u8]) -> Option<PeerClient> {
+    if peer_id.len() != PEER_ID_LENGTH {
+        return None;
+    }
+
+    azureus_client(peer_id).or_else(|| mainline_client(peer_id)).or_else(|| shadow_client(peer_id))
+}
+
+fn azureus_client(peer_id: &[u8]) -> Option<PeerClient> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' || !peer_id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("Unknown ({})", code));
+
+    // Each character is a version component, with letters counting up from 10.
+    let mut components: Vec<u32> = peer_id[3..7]
+        .iter()
+        .map(|&c| match c {
+            b'0'..=b'9' => (c - b'0') as u32,
+            b'A'..=b'Z' => (c - b'A') as u32 + 10,
+            _ => (c - b'a') as u32 + 36,
+        })
+        .collect();
+    if components.last() == Some(&0) {
+        components.pop();
+    }
+
+    Some(PeerClient { name, version: join_version(&components) })
+}
+
+fn mainline_client(peer_id: &[u8]) -> Option<PeerClient> {
+    if peer_id[0] != b'M' {
+        return None;
+    }
+
+    let end = peer_id[1..10].windows(2).position(|pair| pair == b"--")? + 1;
+    let version = std::str::from_utf8(&peer_id[1..end]).ok()?;
+    let components: Vec<u32> = version.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+
+    Some(PeerClient { name: "Mainline".to_string(), version: join_version(&components) })
+}
+
+fn shadow_client(peer_id: &[u8]) -> Option<PeerClient> {
+    let name = SHADOW_CLIENTS.iter().find(|(code, _)| *code == peer_id[0]).map(|(_, name)| name.to_string())?;
+
+    let end = peer_id[1..7].iter().position(|&c| c == b'-')? + 1;
+    if end < 2 || peer_id[end + 1] != b'-' {
+        return None;
+    }
+
+    let components: Vec<u32> = peer_id[1..end]
+        .iter()
+        .map(|c| SHADOW_CHARACTERS.iter().position(|known| known == c).map(|index| index as u32))
+        .collect::<Option<_>>()?;
+
+    Some(PeerClient { name, version: join_version(&components) })
+}
+
+fn join_version(components: &[u32]) -> String {
+    components.iter().map(|component| component.to_string()).collect::<Vec<String>>().join(".")
+}
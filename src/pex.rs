@@ -0,0 +1,194 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+pub const PEX_EXTENSION_NAME: &str = "ut_pex";
+
+// Peers shouldn't be sent PEX messages more often than this.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Upper bound on the number of added and dropped peers in a single message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_SUPPORTS_UTP: u8 = 0x04;
+pub const FLAG_SUPPORTS_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+const IPV4_PEER_SIZE: usize = 6;
+const IPV6_PEER_SIZE: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    pub added6_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn new(added: &[PexPeer], dropped: &[SocketAddr]) -> Self {
+        let mut message = PexMessage::default();
+
+        for peer in added {
+            match peer.addr {
+                SocketAddr::V4(_) => {
+                    message.added.extend(encode_compact_peer(&peer.addr));
+                    message.added_flags.push(peer.flags);
+                },
+                SocketAddr::V6(_) => {
+                    message.added6.extend(encode_compact_peer(&peer.addr));
+                    message.added6_flags.push(peer.flags);
+                },
+            }
+        }
+
+        for addr in dropped {
+            match addr {
+                SocketAddr::V4(_) => message.dropped.extend(encode_compact_peer(addr)),
+                SocketAddr::V6(_) => message.dropped6.extend(encode_compact_peer(addr)),
+            }
+        }
+
+        message
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_bencode::from_bytes(payload)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn added_peers(&self) -> Vec<PexPeer> {
+        let with_flags = |addrs: Vec<SocketAddr>, flags: &ByteBuf| {
+            addrs
+                .into_iter()
+                .enumerate()
+                .map(|(i, addr)| PexPeer { addr, flags: flags.get(i).copied().unwrap_or(0) })
+                .collect::<Vec<PexPeer>>()
+        };
+
+        let mut peers = with_flags(decode_compact_peers(&self.added), &self.added_flags);
+        peers.extend(with_flags(decode_compact_peers6(&self.added6), &self.added6_flags));
+
+        peers
+    }
+
+    pub fn dropped_peers(&self) -> Vec<SocketAddr> {
+        let mut peers = decode_compact_peers(&self.dropped);
+        peers.extend(decode_compact_peers6(&self.dropped6));
+
+        peers
+    }
+}
+
+// What we last told a peer about, so that subsequent messages only carry the difference.
+#[derive(Debug, Default)]
+pub struct PexState {
+    pub last_sent: Option<Instant>,
+    pub advertised: HashSet<SocketAddr>,
+}
+
+impl PexState {
+    pub fn is_due(&self) -> bool {
+        self.last_sent.is_none_or(|sent| sent.elapsed() >= PEX_INTERVAL)
+    }
+
+    // Works out the next message for the peer given the peers we're currently
+    // connected to, and records it as sent.
+    pub fn next_message(&mut self, connected: &[PexPeer]) -> Option<PexMessage> {
+        let current: HashSet<SocketAddr> = connected.iter().map(|peer| peer.addr).collect();
+
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|peer| !self.advertised.contains(&peer.addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self.advertised
+            .difference(&current)
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for peer in &added {
+            self.advertised.insert(peer.addr);
+        }
+        for addr in &dropped {
+            self.advertised.remove(addr);
+        }
+        self.last_sent = Some(Instant::now());
+
+        Some(PexMessage::new(&added, &dropped))
+    }
+}
+
+// The address to tell other peers about. Peers that connected to us did so
+// from an ephemeral port, so they're only advertised at the listen port from
+// their extension handshake, and not as reachable since we haven't reached them.
+pub fn advertised_peer(addr: SocketAddr, incoming: bool, listen_port: Option<i64>) -> Option<PexPeer> {
+    if !incoming {
+        return Some(PexPeer { addr, flags: FLAG_REACHABLE });
+    }
+
+    let port = listen_port.and_then(|port| u16::try_from(port).ok()).filter(|port| *port != 0)?;
+    Some(PexPeer { addr: SocketAddr::new(addr.ip(), port), flags: 0 })
+}
+
+pub fn encode_compact_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut buf = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+
+    buf
+}
+
+pub fn decode_compact_peers(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(IPV4_PEER_SIZE)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            SocketAddr::new(IpAddr::V4(ip), port)
+        })
+        .collect()
+}
+
+pub fn decode_compact_peers6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(IPV6_PEER_SIZE)
+        .map(|chunk| {
+            let octets: [u8; 16] = chunk[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)
+        })
+        .collect()
+}
@@ -0,0 +1,214 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{domain::{RequestMessage, Torrent}, storage::FilePriority, streaming::Streaming};
+
+#[derive(Debug, Clone)]
+pub struct OutstandingRequest {
+    pub request: RequestMessage,
+    pub peer_id: String,
+    pub requested_at: Instant,
+}
+
+// Keeps track of which pieces we have, which blocks still need to be requested
+// and which requests are in flight to which peer.
+#[derive(Debug)]
+pub struct PiecePicker {
+    have: Vec<bool>,
+    priorities: Vec<FilePriority>,
+    streaming: Option<Streaming>,
+    queued: HashMap<u32, VecDeque<RequestMessage>>,
+    outstanding: HashMap<(u32, u32), OutstandingRequest>,
+}
+
+impl PiecePicker {
+    pub fn new(torrent: &Torrent) -> Self {
+        PiecePicker {
+            have: vec![false; torrent.get_num_pieces() as usize],
+            priorities: vec![FilePriority::Normal; torrent.get_num_pieces() as usize],
+            streaming: None,
+            queued: HashMap::new(),
+            outstanding: HashMap::new(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.have.len()
+    }
+
+    pub fn has_piece(&self, piece_index: u32) -> bool {
+        self.have.get(piece_index as usize).copied().unwrap_or(false)
+    }
+
+    pub fn set_piece_priorities(&mut self, priorities: Vec<FilePriority>) {
+        if priorities.len() == self.have.len() {
+            self.priorities = priorities;
+        }
+    }
+
+    pub fn piece_priority(&self, piece_index: u32) -> FilePriority {
+        self.priorities.get(piece_index as usize).copied().unwrap_or_default()
+    }
+
+    // Pieces we still want, i.e. that we don't have and aren't skipped.
+    pub fn missing_pieces(&self) -> Vec<u32> {
+        (0..self.have.len() as u32)
+            .filter(|piece_index| !self.has_piece(*piece_index) && self.piece_priority(*piece_index) != FilePriority::Skip)
+            .collect()
+    }
+
+    // The missing pieces in the order to download them: highest priority
+    // first, and in order within the same priority.
+    pub fn wanted_pieces(&self) -> Vec<u32> {
+        let mut pieces = self.missing_pieces();
+        pieces.sort_by_key(|piece_index| Reverse(self.piece_priority(*piece_index)));
+
+        pieces
+    }
+
+    // In streaming mode pieces just ahead of the playback cursor come first.
+    pub fn set_streaming(&mut self, streaming: Option<Streaming>) {
+        self.streaming = streaming;
+    }
+
+    pub fn deadline(&self, piece_index: u32) -> Option<Instant> {
+        self.streaming.as_ref().and_then(|streaming| streaming.deadline(piece_index))
+    }
+
+    // Picks the next missing piece to download, leaving out `exclude`. When
+    // streaming, the pieces in the read-ahead window come first and in order.
+    // Then come the pieces peers suggested (BEP 6), in the order they did,
+    // unless something of higher priority is missing. Everything else goes by
+    // priority, then rarest first, given how many peers have each piece.
+    pub fn pick_piece(&self, availability: &[u32], suggested: &[u32], exclude: &HashSet<u32>) -> Option<u32> {
+        let candidates: Vec<u32> = self.missing_pieces()
+            .into_iter()
+            .filter(|piece_index| !exclude.contains(piece_index))
+            .collect();
+
+        if let Some(streaming) = &self.streaming {
+            let window = streaming.window();
+            if let Some(piece_index) = candidates.iter().find(|piece_index| window.contains(piece_index)) {
+                return Some(*piece_index);
+            }
+        }
+
+        let top_priority = candidates.iter().map(|piece_index| self.piece_priority(*piece_index)).max()?;
+        let suggestion = suggested
+            .iter()
+            .find(|piece_index| candidates.contains(piece_index) && self.piece_priority(**piece_index) == top_priority);
+        if let Some(piece_index) = suggestion {
+            return Some(*piece_index);
+        }
+
+        candidates.into_iter().min_by_key(|piece_index| {
+            let peers = availability.get(*piece_index as usize).copied().unwrap_or(0);
+            // Pieces nobody has (as far as we know) go last.
+            (Reverse(self.piece_priority(*piece_index)), peers == 0, peers, *piece_index)
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing_pieces().is_empty()
+    }
+
+    // Queues every block of the piece, dropping any earlier progress on it.
+    pub fn start_piece(&mut self, torrent: &Torrent, piece_index: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let blocks = (0..torrent.get_num_blocks(piece_index as usize)?)
+            .map(|block_offset| {
+                Ok(RequestMessage {
+                    index: piece_index,
+                    begin: (block_offset * Torrent::BLOCK_SIZE) as u32,
+                    length: torrent.get_block_length(piece_index as usize, block_offset)? as u32,
+                })
+            })
+            .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+
+        self.outstanding.retain(|(index, _), _| *index != piece_index);
+        self.queued.insert(piece_index, blocks);
+
+        Ok(())
+    }
+
+    pub fn next_request(&mut self, piece_index: u32, peer_id: &str) -> Option<RequestMessage> {
+        let request = self.queued.get_mut(&piece_index)?.pop_front()?;
+
+        self.outstanding.insert((request.index, request.begin), OutstandingRequest {
+            request,
+            peer_id: peer_id.to_string(),
+            requested_at: Instant::now(),
+        });
+
+        Some(request)
+    }
+
+    // Returns false for blocks we didn't ask this peer for (e.g. after a cancel).
+    pub fn block_received(&mut self, piece_index: u32, begin: u32, peer_id: &str) -> bool {
+        match self.outstanding.get(&(piece_index, begin)) {
+            Some(outstanding) if outstanding.peer_id == peer_id => {
+                self.outstanding.remove(&(piece_index, begin));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Puts a request back at the front of its piece's queue so it's requested next.
+    pub fn return_request(&mut self, request: RequestMessage) {
+        if self.outstanding.remove(&(request.index, request.begin)).is_some() {
+            self.queued.entry(request.index).or_default().push_front(request);
+        }
+    }
+
+    // Returns every request in flight to the peer, e.g. when it chokes us or disconnects.
+    pub fn return_peer_requests(&mut self, peer_id: &str) -> Vec<RequestMessage> {
+        let requests: Vec<RequestMessage> = self.outstanding
+            .values()
+            .filter(|outstanding| outstanding.peer_id == peer_id)
+            .map(|outstanding| outstanding.request)
+            .collect();
+
+        for request in &requests {
+            self.return_request(*request);
+        }
+
+        requests
+    }
+
+    pub fn outstanding_requests(&self, peer_id: &str) -> usize {
+        self.outstanding.values().filter(|outstanding| outstanding.peer_id == peer_id).count()
+    }
+
+    pub fn queued_blocks(&self, piece_index: u32) -> usize {
+        self.queued.get(&piece_index).map_or(0, |queue| queue.len())
+    }
+
+    // The earliest time one of the peer's requests will time out.
+    pub fn next_deadline(&self, peer_id: &str, timeout: Duration) -> Option<Instant> {
+        self.outstanding
+            .values()
+            .filter(|outstanding| outstanding.peer_id == peer_id)
+            .map(|outstanding| outstanding.requested_at + timeout)
+            .min()
+    }
+
+    pub fn timed_out_requests(&self, peer_id: &str, timeout: Duration) -> Vec<RequestMessage> {
+        self.outstanding
+            .values()
+            .filter(|outstanding| outstanding.peer_id == peer_id && outstanding.requested_at.elapsed() >= timeout)
+            .map(|outstanding| outstanding.request)
+            .collect()
+    }
+
+    pub fn piece_done(&mut self, piece_index: u32) {
+        self.queued.remove(&piece_index);
+        self.outstanding.retain(|(index, _), _| *index != piece_index);
+
+        if let Some(have) = self.have.get_mut(piece_index as usize) {
+            *have = true;
+        }
+    }
+}
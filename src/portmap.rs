@@ -0,0 +1,256 @@
+// Maps our listen port on the home router so peers outside can connect to
+// us. NAT-PMP/PCP is asked first since it's quick to answer, then UPnP. The
+// mappings are renewed halfway through their lease and removed on shutdown.
+
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::watch, task::JoinHandle, time::sleep};
+
+use crate::{
+    info, warn,
+    natpmp::{NatPmpGateway, NAT_PMP_PORT},
+    upnp::{UpnpGateway, SSDP_ADDR},
+};
+
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60 * 60);
+const SSDP_WAIT: Duration = Duration::from_secs(2);
+const MIN_RENEWAL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+
+    // IANA protocol number.
+    pub fn number(&self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    // Where peers outside reach us.
+    pub external: SocketAddr,
+    // Zero for a mapping that never expires.
+    pub lifetime: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapperConfig {
+    // The NAT-PMP/PCP server, by default port 5351 on the default gateway.
+    pub gateway: Option<SocketAddr>,
+    pub nat_pmp: bool,
+    // Where the SSDP search for a UPnP gateway goes.
+    pub ssdp_addr: SocketAddr,
+    pub ssdp_wait: Duration,
+    pub upnp: bool,
+    pub lease: Duration,
+    // Don't hammer gateways that hand out very short leases.
+    pub min_renewal_interval: Duration,
+}
+
+impl Default for PortMapperConfig {
+    fn default() -> Self {
+        PortMapperConfig {
+            gateway: None,
+            nat_pmp: true,
+            ssdp_addr: SocketAddr::V4(SSDP_ADDR),
+            ssdp_wait: SSDP_WAIT,
+            upnp: true,
+            lease: DEFAULT_LEASE,
+            min_renewal_interval: MIN_RENEWAL_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Gateway {
+    NatPmp(NatPmpGateway),
+    Upnp(UpnpGateway),
+}
+
+impl Gateway {
+    async fn map(&mut self, protocol: Protocol, port: u16, lease: Duration) -> Result<PortMapping, Box<dyn std::error::Error>> {
+        match self {
+            Gateway::NatPmp(gateway) => gateway.map(protocol, port, lease).await,
+            Gateway::Upnp(gateway) => gateway.map(protocol, port, lease).await,
+        }
+    }
+
+    async fn unmap(&mut self, mapping: &PortMapping) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Gateway::NatPmp(gateway) => gateway.unmap(mapping.protocol, mapping.internal_port).await,
+            Gateway::Upnp(gateway) => gateway.unmap(mapping.protocol, mapping.external.port()).await,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Gateway::NatPmp(gateway) if gateway.is_pcp() => format!("PCP gateway {}", gateway.addr()),
+            Gateway::NatPmp(gateway) => format!("NAT-PMP gateway {}", gateway.addr()),
+            Gateway::Upnp(gateway) => format!("UPnP gateway {}", gateway.control_url),
+        }
+    }
+}
+
+type Mappings = Arc<Mutex<Vec<PortMapping>>>;
+
+// Keeps our listen port mapped for TCP and UDP (for uTP) until shut down.
+pub struct PortMapper {
+    gateway: Arc<tokio::sync::Mutex<Gateway>>,
+    mappings: Mappings,
+    // The TCP mapping's external address, updated when a renewal moves it.
+    external_addr: watch::Receiver<Option<SocketAddr>>,
+    renewal: JoinHandle<()>,
+}
+
+impl PortMapper {
+    pub async fn map(port: u16, config: &PortMapperConfig) -> Result<PortMapper, Box<dyn std::error::Error>> {
+        let mut errors = vec![];
+
+        if config.nat_pmp {
+            match config.gateway.or_else(|| default_gateway().map(|ip| SocketAddr::new(IpAddr::V4(ip), NAT_PMP_PORT))) {
+                Some(addr) => match Self::start(Gateway::NatPmp(NatPmpGateway::new(addr)), port, config).await {
+                    Ok(mapper) => return Ok(mapper),
+                    Err(err) => errors.push(err),
+                },
+                None => errors.push("Could not find the default gateway for NAT-PMP".to_string()),
+            }
+        }
+
+        // Searching for a UPnP gateway takes a while, so only do it when needed.
+        if config.upnp {
+            match UpnpGateway::discover(config.ssdp_addr, config.ssdp_wait).await {
+                Ok(gateway) => match Self::start(Gateway::Upnp(gateway), port, config).await {
+                    Ok(mapper) => return Ok(mapper),
+                    Err(err) => errors.push(err),
+                },
+                Err(err) => errors.push(format!("Could not find a UPnP gateway: {}", err)),
+            }
+        }
+
+        if errors.is_empty() {
+            errors.push("No port mapping protocol enabled".to_string());
+        }
+        Err(errors.join("; ").into())
+    }
+
+    async fn start(mut gateway: Gateway, port: u16, config: &PortMapperConfig) -> Result<PortMapper, String> {
+        let mappings = map_all(&mut gateway, port, config.lease).await.map_err(|err| format!("{}: {}", gateway.describe(), err))?;
+        info!("Mapped port {} to {} with {}", port, mappings[0].external, gateway.describe());
+
+        let (external_tx, external_addr) = watch::channel(tcp_external_addr(&mappings));
+        let gateway = Arc::new(tokio::sync::Mutex::new(gateway));
+        let mappings = Arc::new(Mutex::new(mappings));
+        let renewal = tokio::spawn(renew_loop(gateway.clone(), mappings.clone(), external_tx, port, config.clone()));
+
+        Ok(PortMapper { gateway, mappings, external_addr, renewal })
+    }
+
+    pub fn mappings(&self) -> Vec<PortMapping> {
+        self.mappings.lock().unwrap().clone()
+    }
+
+    // Where peers outside reach our TCP listener.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        tcp_external_addr(&self.mappings.lock().unwrap())
+    }
+
+    // The external address now and whenever a renewal changes it, e.g. for
+    // `Client::follow_external_addr`.
+    pub fn subscribe_external_addr(&self) -> watch::Receiver<Option<SocketAddr>> {
+        self.external_addr.clone()
+    }
+
+    // Stops renewing the mappings and removes them from the gateway.
+    pub async fn shutdown(self) {
+        self.renewal.abort();
+
+        let mappings = self.mappings();
+        let mut gateway = self.gateway.lock().await;
+        for mapping in &mappings {
+            if let Err(err) = gateway.unmap(mapping).await {
+                warn!("Could not remove {} port mapping for {}: {}", mapping.protocol.name(), mapping.external, err);
+            }
+        }
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.renewal.abort();
+    }
+}
+
+async fn map_all(gateway: &mut Gateway, port: u16, lease: Duration) -> Result<Vec<PortMapping>, Box<dyn std::error::Error>> {
+    let mut mappings = vec![];
+    for protocol in [Protocol::Tcp, Protocol::Udp] {
+        mappings.push(gateway.map(protocol, port, lease).await?);
+    }
+
+    Ok(mappings)
+}
+
+fn tcp_external_addr(mappings: &[PortMapping]) -> Option<SocketAddr> {
+    mappings.iter().find(|mapping| mapping.protocol == Protocol::Tcp).map(|mapping| mapping.external)
+}
+
+async fn renew_loop(gateway: Arc<tokio::sync::Mutex<Gateway>>, mappings: Mappings, external_addr: watch::Sender<Option<SocketAddr>>, port: u16, config: PortMapperConfig) {
+    loop {
+        // Permanent mappings don't need renewing.
+        let shortest = mappings.lock().unwrap().iter().map(|mapping| mapping.lifetime).filter(|lifetime| !lifetime.is_zero()).min();
+        let lifetime = match shortest {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        sleep((lifetime / 2).max(config.min_renewal_interval)).await;
+
+        let mut gateway = gateway.lock().await;
+        let renewed = match map_all(&mut gateway, port, config.lease).await {
+            Ok(renewed) => renewed,
+            Err(err) => {
+                warn!("Could not renew port mappings with {}: {}", gateway.describe(), err);
+                continue;
+            },
+        };
+        if renewed[0].external != mappings.lock().unwrap()[0].external {
+            info!("Port {} is now mapped to {}", port, renewed[0].external);
+        }
+        external_addr.send_replace(tcp_external_addr(&renewed));
+        *mappings.lock().unwrap() = renewed;
+    }
+}
+
+// The IPv4 default gateway, from the kernel's routing table.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        // The address bytes are printed as a native integer.
+        Some(Ipv4Addr::from(gateway.to_ne_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
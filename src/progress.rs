@@ -0,0 +1,184 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{
+    domain::Torrent,
+    stats::{StatsSnapshot, TransferStats},
+};
+
+// How often the status line is redrawn on a terminal.
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+// How often a plain progress line is printed when stderr isn't a terminal.
+pub const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+// Weight of the latest sample in the smoothed transfer rates.
+const RATE_SMOOTHING: f64 = 0.3;
+
+// Turns samples of the transfer counters into a one-line summary of the download.
+#[derive(Debug)]
+pub struct Progress {
+    total_length: u64,
+    num_pieces: u64,
+    last_sample: Option<(Instant, StatsSnapshot)>,
+    down_rate: f64,
+    up_rate: f64,
+}
+
+impl Progress {
+    pub fn new(torrent: &Torrent) -> Self {
+        Progress {
+            total_length: torrent.total_length() as u64,
+            num_pieces: torrent.get_num_pieces() as u64,
+            last_sample: None,
+            down_rate: 0.0,
+            up_rate: 0.0,
+        }
+    }
+
+    // Only counts what we're actually downloading, e.g. when some files are skipped.
+    pub fn set_wanted(&mut self, length: u64, num_pieces: u64) {
+        self.total_length = length;
+        self.num_pieces = num_pieces;
+    }
+
+    pub fn update(&mut self, now: Instant, snapshot: StatsSnapshot) -> String {
+        if let Some((last_time, last)) = self.last_sample {
+            let elapsed = now.duration_since(last_time).as_secs_f64();
+
+            if elapsed > 0.0 {
+                let down = (snapshot.downloaded - last.downloaded) as f64 / elapsed;
+                let up = (snapshot.uploaded - last.uploaded) as f64 / elapsed;
+
+                self.down_rate += RATE_SMOOTHING * (down - self.down_rate);
+                self.up_rate += RATE_SMOOTHING * (up - self.up_rate);
+            }
+        }
+        self.last_sample = Some((now, snapshot));
+
+        format!(
+            "{:5.1}% {} / {}  down {}/s  up {}/s  peers {}  pieces {}/{}  eta {}",
+            self.percent_complete(&snapshot),
+            format_bytes(snapshot.verified_bytes),
+            format_bytes(self.total_length),
+            format_bytes(self.down_rate as u64),
+            format_bytes(self.up_rate as u64),
+            snapshot.connected_peers,
+            snapshot.pieces_verified,
+            self.num_pieces,
+            format_eta(self.eta(&snapshot)),
+        )
+    }
+
+    pub fn percent_complete(&self, snapshot: &StatsSnapshot) -> f64 {
+        if self.total_length == 0 {
+            return 100.0;
+        }
+
+        (snapshot.verified_bytes.min(self.total_length) as f64 / self.total_length as f64) * 100.0
+    }
+
+    pub fn eta(&self, snapshot: &StatsSnapshot) -> Option<Duration> {
+        let remaining = self.total_length.saturating_sub(snapshot.verified_bytes);
+
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        if self.down_rate < 1.0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(remaining as f64 / self.down_rate))
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_eta(eta: Option<Duration>) -> String {
+    let secs = match eta {
+        Some(eta) => eta.as_secs(),
+        None => return "--:--".to_string(),
+    };
+
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+// A progress display running in the background until `finish` is called.
+pub struct ProgressDisplay {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ProgressDisplay {
+    // Redraws a status line on stderr if it's a terminal, and prints plain lines
+    // every few seconds otherwise.
+    pub fn spawn(stats: Arc<TransferStats>, mut progress: Progress) -> Self {
+        let interactive = io::stderr().is_terminal();
+        let interval = if interactive { REDRAW_INTERVAL } else { PLAIN_INTERVAL };
+        let (stop, mut stopped) = oneshot::channel();
+
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {},
+                    _ = &mut stopped => break,
+                }
+
+                let line = progress.update(Instant::now(), stats.snapshot());
+                draw(&line, interactive, false);
+            }
+
+            let line = progress.update(Instant::now(), stats.snapshot());
+            draw(&line, interactive, true);
+        });
+
+        ProgressDisplay { stop, task }
+    }
+
+    // Stops the display after drawing the final state.
+    pub async fn finish(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+fn draw(line: &str, interactive: bool, last: bool) {
+    let output = io::stderr();
+    let mut handle = output.lock();
+
+    // Failing to draw progress isn't worth stopping the download for.
+    let _ = if !interactive {
+        writeln!(handle, "{}", line)
+    } else if last {
+        writeln!(handle, "\r\x1b[2K{}", line)
+    } else {
+        write!(handle, "\r\x1b[2K{}", line).and_then(|_| handle.flush())
+    };
+}
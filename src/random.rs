@@ -0,0 +1,54 @@
+use std::{
+    fs,
+    io::{self, Read},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha1::{Digest, Sha1};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// NOT cryptographically secure: SHA-1 over the time, a counter, the pid and
+// a pointer, all of which can be guessed. Fine for ids, nonces and shuffling
+// that only need to differ between runs. Keys and secrets that stop others
+// from forging something must use `os_random_bytes`.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len);
+
+    while bytes.len() < len {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+        let mut hasher = Sha1::new();
+        hasher.update(nanos.to_be_bytes());
+        hasher.update(counter.to_be_bytes());
+        hasher.update(process::id().to_be_bytes());
+        hasher.update((&bytes as *const Vec<u8> as usize).to_be_bytes());
+
+        let digest = hasher.finalize();
+        let remaining = len - bytes.len();
+        bytes.extend_from_slice(&digest[..remaining.min(digest.len())]);
+    }
+
+    bytes
+}
+
+// Not cryptographically secure, see `random_bytes`.
+pub fn random_u64() -> u64 {
+    let bytes: [u8; 8] = random_bytes(8).try_into().unwrap();
+    u64::from_be_bytes(bytes)
+}
+
+// Bytes from the operating system, for keys. Fails where there is no
+// /dev/urandom rather than fall back to `random_bytes`.
+pub fn os_random_bytes(len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
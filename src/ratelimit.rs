@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Bytes per second, or None for no limit.
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            // Allows bursts of up to a second's worth of data.
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last_refill = now;
+    }
+}
+
+// A token bucket whose rate can be changed while it's in use.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|rate| *rate > 0);
+
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or_default() as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    // A rate of zero means no limit.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+
+        bucket.rate = rate.filter(|rate| *rate > 0);
+        if let Some(rate) = bucket.rate {
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        }
+    }
+
+    // Takes `bytes` out of the bucket, going into debt if needed, and returns
+    // how long to wait until the transfer fits within the rate.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+
+        let rate = match bucket.rate {
+            Some(rate) => rate,
+            None => return Duration::ZERO,
+        };
+
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LimiterPair {
+    pub download: Arc<RateLimiter>,
+    pub upload: Arc<RateLimiter>,
+}
+
+impl LimiterPair {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        LimiterPair {
+            download: Arc::new(RateLimiter::new(download)),
+            upload: Arc::new(RateLimiter::new(upload)),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn set_rates(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+
+    fn get(&self, direction: Direction) -> &RateLimiter {
+        match direction {
+            Direction::Download => &self.download,
+            Direction::Upload => &self.upload,
+        }
+    }
+}
+
+// The limits that apply to one torrent's traffic: a global pair that can be
+// shared with other torrents, one for the torrent and one per peer. Cloning
+// gives another handle to the same limiters, so they can be changed at
+// runtime from outside the client.
+#[derive(Debug, Clone)]
+pub struct BandwidthLimits {
+    global: LimiterPair,
+    torrent: LimiterPair,
+    peer_rates: Arc<Mutex<(Option<u64>, Option<u64>)>>,
+    peers: Arc<Mutex<HashMap<String, LimiterPair>>>,
+    include_overhead: Arc<AtomicBool>,
+}
+
+impl Default for BandwidthLimits {
+    fn default() -> Self {
+        Self::new(LimiterPair::unlimited())
+    }
+}
+
+impl BandwidthLimits {
+    pub fn new(global: LimiterPair) -> Self {
+        BandwidthLimits {
+            global,
+            torrent: LimiterPair::unlimited(),
+            peer_rates: Arc::new(Mutex::new((None, None))),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            include_overhead: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn global(&self) -> &LimiterPair {
+        &self.global
+    }
+
+    pub fn set_global(&mut self, global: LimiterPair) {
+        self.global = global;
+    }
+
+    pub fn torrent(&self) -> &LimiterPair {
+        &self.torrent
+    }
+
+    // Applies to every peer, including the ones already connected.
+    pub fn set_peer_rates(&self, download: Option<u64>, upload: Option<u64>) {
+        *self.peer_rates.lock().unwrap() = (download, upload);
+
+        for limiters in self.peers.lock().unwrap().values() {
+            limiters.set_rates(download, upload);
+        }
+    }
+
+    pub fn peer_rates(&self) -> (Option<u64>, Option<u64>) {
+        *self.peer_rates.lock().unwrap()
+    }
+
+    pub fn remove_peer(&self, peer_id: &str) {
+        self.peers.lock().unwrap().remove(peer_id);
+    }
+
+    // Whether message headers and other protocol overhead count towards the
+    // limits, rather than just piece data.
+    pub fn set_include_overhead(&self, include_overhead: bool) {
+        self.include_overhead.store(include_overhead, Ordering::Relaxed);
+    }
+
+    fn peer(&self, peer_id: &str) -> LimiterPair {
+        let (download, upload) = self.peer_rates();
+
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(peer_id.to_string())
+            .or_insert_with(|| LimiterPair::new(download, upload))
+            .clone()
+    }
+
+    // Waits until a transfer to or from the peer fits within all of the limits.
+    pub async fn throttle(&self, peer_id: &str, direction: Direction, payload: u64, overhead: u64) {
+        let bytes = if self.include_overhead.load(Ordering::Relaxed) { payload + overhead } else { payload };
+        if bytes == 0 {
+            return;
+        }
+
+        let peer = self.peer(peer_id);
+        let wait = [&self.global, &self.torrent, &peer]
+            .iter()
+            .map(|limiters| limiters.get(direction).reserve(bytes))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// Parses rates like "500K" or "2M" (binary multiples) into bytes per second.
+// "0" and "unlimited" mean no limit.
+pub fn parse_rate(input: &str) -> Result<Option<u64>, String> {
+    let input = input.trim();
+    if input.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+
+    let (number, multiplier) = match input.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&input[..i], 1024),
+        Some((i, 'm' | 'M')) => (&input[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&input[..i], 1024 * 1024 * 1024),
+        _ => (input, 1),
+    };
+
+    let number: f64 = number.trim().parse().map_err(|_| format!("Invalid rate: {}", input))?;
+    if number < 0.0 {
+        return Err(format!("Invalid rate: {}", input));
+    }
+
+    let rate = (number * multiplier as f64) as u64;
+    Ok(Some(rate).filter(|rate| *rate > 0))
+}
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+// How hard `download_file` tries before giving up on a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts_per_piece: u32,
+    pub max_attempts_per_peer: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts_per_piece: 10,
+            max_attempts_per_peer: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff before the given (1-based) retry.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
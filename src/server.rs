@@ -0,0 +1,308 @@
+use std::{net::SocketAddr, ops::Range, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{storage::StorageReader, streaming::PlaybackCursor, debug, warn};
+
+type ServerError = Box<dyn std::error::Error + Send + Sync>;
+
+// Longest request head we accept.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+// How long an idle keep-alive connection is kept open.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+// Parses a Range header for a resource of `length` bytes. Only single byte
+// ranges are supported, anything else gets the whole resource.
+pub fn parse_range(header: Option<&str>, length: u64) -> ByteRange {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // The last `end` bytes.
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            length.saturating_sub(suffix)..length
+        },
+        (Ok(start), Err(_)) if end.is_empty() => start..length,
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(length),
+        _ => return ByteRange::Full,
+    };
+
+    if range.start >= length {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+// Serves the files of a torrent over HTTP while it downloads, at
+// `/<file index>/<file name>`. Ranges that aren't downloaded yet are waited
+// for, and the playback cursor is moved to them so they're fetched next.
+pub struct ContentServer {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl ContentServer {
+    pub async fn bind(addr: &str, reader: StorageReader, cursor: Option<PlaybackCursor>) -> Result<Self, Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Could not accept HTTP connection: {}", err);
+                        continue;
+                    },
+                };
+
+                let reader = reader.clone();
+                let cursor = cursor.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve_connection(stream, reader, cursor).await {
+                        debug!("HTTP connection from {} ended: {}", addr, err);
+                    }
+                });
+            }
+        });
+
+        Ok(ContentServer { local_addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn url(&self, file_index: usize, name: &str) -> String {
+        format!("http://{}{}", self.local_addr, file_path(file_index, name))
+    }
+}
+
+impl Drop for ContentServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn file_path(file_index: usize, name: &str) -> String {
+    let mut path = format!("/{}/", file_index);
+
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => path.push(byte as char),
+            _ => path.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    path
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+    keep_alive: bool,
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Option<Request>, ServerError> {
+    let mut head = vec![];
+
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        if line.trim_end().is_empty() {
+            if head.is_empty() {
+                // Stray blank lines between requests are allowed.
+                continue;
+            }
+            break;
+        }
+
+        head.push(line.trim_end().to_string());
+        if head.iter().map(|line| line.len()).sum::<usize>() > MAX_REQUEST_HEAD {
+            return Err("Request head is too long".into());
+        }
+    }
+
+    let mut request_line = head[0].split_whitespace();
+    let (method, path, version) = match (request_line.next(), request_line.next(), request_line.next()) {
+        (Some(method), Some(path), Some(version)) => (method, path, version),
+        _ => return Err(format!("Invalid request line: {}", head[0]).into()),
+    };
+
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        range: None,
+        keep_alive: version == "HTTP/1.1",
+    };
+
+    for header in &head[1..] {
+        let (name, value) = match header.split_once(':') {
+            Some(header) => header,
+            None => continue,
+        };
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "range" => request.range = Some(value.trim().to_string()),
+            "connection" => request.keep_alive = !value.trim().eq_ignore_ascii_case("close"),
+            _ => {},
+        }
+    }
+
+    Ok(Some(request))
+}
+
+async fn serve_connection(stream: TcpStream, mut reader: StorageReader, cursor: Option<PlaybackCursor>) -> Result<(), ServerError> {
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        let request = match timeout(IDLE_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(err)) => return Err(err),
+        };
+
+        debug!("HTTP {} {}", request.method, request.path);
+        respond(stream.get_mut(), &request, &mut reader, cursor.as_ref()).await?;
+
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+async fn respond(stream: &mut TcpStream, request: &Request, reader: &mut StorageReader, cursor: Option<&PlaybackCursor>) -> Result<(), ServerError> {
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return write_error(stream, "405 Method Not Allowed").await,
+    };
+
+    if request.path == "/" {
+        return write_index(stream, reader, head_only).await;
+    }
+
+    // Files are looked up by index, the name is only there for players and browsers.
+    let file_index = match request.path.trim_start_matches('/').split('/').next().and_then(|index| index.parse::<usize>().ok()) {
+        Some(file_index) if file_index < reader.files().len() => file_index,
+        _ => return write_error(stream, "404 Not Found").await,
+    };
+    let file = reader.files()[file_index].clone();
+
+    let (status, range) = match parse_range(request.range.as_deref(), file.length) {
+        ByteRange::Full => ("200 OK", 0..file.length),
+        ByteRange::Partial(range) => ("206 Partial Content", range),
+        ByteRange::Unsatisfiable => {
+            let head = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\n\r\n",
+                file.length,
+            );
+            stream.write_all(head.as_bytes()).await?;
+            return Ok(());
+        },
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n",
+        status,
+        content_type(&file.name),
+        range.end - range.start,
+    );
+    if status.starts_with("206") {
+        head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n", range.start, range.end - 1, file.length));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+
+    if head_only {
+        return Ok(());
+    }
+
+    for piece_index in reader.file_pieces(file_index, range.clone()) {
+        if !reader.has_piece(piece_index) {
+            debug!("Waiting for piece {} of {}", piece_index, file.name);
+            if let Some(cursor) = cursor {
+                cursor.set_piece(piece_index);
+            }
+            reader.wait_for_piece(piece_index).await?;
+        }
+
+        let piece_range = reader.piece_file_range(file_index, piece_index);
+        let chunk = piece_range.start.max(range.start)..piece_range.end.min(range.end);
+        let data = reader.read(file_index, chunk)?;
+        stream.write_all(&data).await?;
+    }
+
+    Ok(())
+}
+
+async fn write_index(stream: &mut TcpStream, reader: &StorageReader, head_only: bool) -> Result<(), ServerError> {
+    let mut body = String::from("<!DOCTYPE html>\n<ul>\n");
+    for (file_index, file) in reader.files().iter().enumerate().filter(|(_, file)| !file.padding) {
+        let name = file.name.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        body.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", file_path(file_index, &file.name), name));
+    }
+    body.push_str("</ul>\n");
+
+    let head = format!("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n", body.len());
+    stream.write_all(head.as_bytes()).await?;
+    if !head_only {
+        stream.write_all(body.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn write_error(stream: &mut TcpStream, status: &str) -> Result<(), ServerError> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}", status, status.len(), status);
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).unwrap_or_default();
+
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        _ => "application/octet-stream",
+    }
+}
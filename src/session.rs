@@ -0,0 +1,609 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::{mpsc, Mutex as AsyncMutex, Semaphore},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    client::{dht_handshake_message, Client, IncomingPeer, SharedSettings},
+    dht::{DhtNode, BOOTSTRAP_NODES, K},
+    domain::{PeerInfo, Torrent},
+    listen::ListenConfig,
+    lsd::{LocalDiscovery, LSD_GROUP},
+    mse::{self, EncryptionPolicy, PeerStream},
+    proxy::Proxy,
+    transport::Transport,
+    utp::UtpSocket,
+    ratelimit::{BandwidthLimits, LimiterPair},
+    stats::TransferStats,
+    storage::{FileEntry, FilePriority, Storage},
+    debug, info, warn,
+};
+
+// Default cap on open peer connections across all torrents in a session.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+// How long a peer that connects to us gets to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    Downloading,
+    Paused,
+    Completed,
+    Failed(String),
+}
+
+struct ManagedTorrent {
+    torrent: Arc<Torrent>,
+    storage: Arc<AsyncMutex<Storage>>,
+    // The storage's files, readable while the download holds the storage.
+    files: Vec<FileEntry>,
+    client: Arc<AsyncMutex<Client>>,
+    stats: Arc<TransferStats>,
+    limits: BandwidthLimits,
+    state: Arc<Mutex<TorrentState>>,
+    task: Option<JoinHandle<()>>,
+}
+
+// Incoming connections are handed to torrents by info hash.
+type IncomingRoutes = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<IncomingPeer>>>>;
+
+// Downloads many torrents at once. Each torrent gets its own `Client` (and so
+// its own peers, picker and output file), while the listen port, the DHT node,
+// the connection limit and the global bandwidth limits are shared between them.
+pub struct Session {
+    peer_id: String,
+    listener: PeerListener,
+    // Shared with every torrent's client, so changing them doesn't have to
+    // wait for the client's download.
+    settings: SharedSettings,
+    connection_limit: Arc<Semaphore>,
+    global_limits: LimiterPair,
+    torrents: HashMap<String, ManagedTorrent>,
+}
+
+impl Session {
+    pub async fn bind(peer_id: String, listen_addr: SocketAddr, max_connections: usize) -> Result<Session, Box<dyn std::error::Error>> {
+        Self::listen(peer_id, &ListenConfig::from(listen_addr), max_connections).await
+    }
+
+    // Listens on every address in `config`, all on the same port.
+    pub async fn listen(peer_id: String, config: &ListenConfig, max_connections: usize) -> Result<Session, Box<dyn std::error::Error>> {
+        let connection_limit = Arc::new(Semaphore::new(max_connections));
+        let listener = PeerListener::bind(peer_id.clone(), config, connection_limit.clone()).await?;
+
+        Ok(Session {
+            peer_id,
+            listener,
+            settings: SharedSettings::default(),
+            connection_limit,
+            global_limits: LimiterPair::unlimited(),
+            torrents: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addrs()[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        self.listener.local_addrs()
+    }
+
+    // Applies to connections in both directions, for torrents already in the
+    // session too once they reconnect.
+    pub fn set_encryption_policy(&mut self, policy: EncryptionPolicy) {
+        self.listener.set_encryption_policy(policy);
+        self.settings.set_encryption_policy(policy);
+    }
+
+    // Connects to peers over uTP first, falling back to TCP. Incoming uTP
+    // connections are accepted either way.
+    pub fn enable_utp(&mut self) {
+        self.settings.set_utp_enabled(true);
+    }
+
+    // Starts the DHT node every torrent in the session uses, on `port` or the
+    // one it had last time. See `DhtNode::start`.
+    pub async fn start_dht(&mut self, port: Option<u16>, state_path: &Path) -> Result<Arc<DhtNode>, Box<dyn std::error::Error>> {
+        let dht = DhtNode::start(port, state_path).await?;
+
+        if dht.num_nodes() < K {
+            let bootstrap_nodes: Vec<String> = BOOTSTRAP_NODES.iter().map(|node| node.to_string()).collect();
+            dht.bootstrap(&bootstrap_nodes).await?;
+        }
+
+        self.set_dht(dht.clone());
+        Ok(dht)
+    }
+
+    pub fn set_dht(&mut self, dht: Arc<DhtNode>) {
+        self.settings.set_dht(dht);
+        self.listener.set_dht_enabled(true);
+    }
+
+    // Announces the session's public torrents on the local network, and
+    // connects to the peers announcing them there.
+    pub async fn start_lsd(&mut self) -> Result<Arc<LocalDiscovery>, Box<dyn std::error::Error>> {
+        let lsd = LocalDiscovery::bind(LSD_GROUP).await?;
+
+        self.set_lsd(lsd.clone());
+        Ok(lsd)
+    }
+
+    pub fn set_lsd(&mut self, lsd: Arc<LocalDiscovery>) {
+        self.settings.set_lsd(lsd);
+    }
+
+    // Outgoing peer connections, and trackers and web seeds where the proxy
+    // can reach them, go through the proxy for every torrent in the session.
+    pub fn set_proxy(&mut self, proxy: Proxy) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Connecting through proxy {}", proxy);
+        self.settings.set_proxy(proxy.clone())?;
+        self.listener.set_proxy(proxy);
+
+        Ok(())
+    }
+
+    // Refuses incoming peers and stops using the DHT and local service
+    // discovery, along with anything else the proxy can't carry.
+    pub fn force_proxy(&mut self) {
+        self.settings.set_force_proxy();
+        self.listener.force_proxy();
+    }
+
+    // Adds a torrent and starts downloading it into `output_path` (a directory
+    // for multi-file torrents). Peers we
+    // already know about can be passed in, otherwise they're looked up through
+    // the tracker and the DHT. Returns the torrent's info hash.
+    pub fn add_torrent(&mut self, torrent: Torrent, output_path: PathBuf, peers: &[String]) -> Result<String, Box<dyn std::error::Error>> {
+        let info_hash = torrent.info_hash()?;
+        if self.torrents.contains_key(&info_hash) {
+            return Err(format!("Torrent {} is already in the session", info_hash).into());
+        }
+
+        let mut client = Client::new(self.peer_id.clone());
+        client.set_listen_port(self.listener.port());
+        client.set_connection_limit(self.connection_limit.clone());
+        client.set_global_limits(self.global_limits.clone());
+        client.set_incoming(self.listener.add_torrent(&info_hash));
+        client.set_shared_settings(self.settings.clone());
+        if let Some(utp) = self.listener.utp_socket() {
+            client.set_utp_socket(utp);
+        }
+        client.add_peer_candidates(peers);
+
+        let stats = client.stats();
+        let limits = client.bandwidth_limits();
+
+        let storage = Storage::new(&torrent, &output_path);
+
+        self.torrents.insert(info_hash.clone(), ManagedTorrent {
+            files: storage.files().to_vec(),
+            storage: Arc::new(AsyncMutex::new(storage)),
+            torrent: Arc::new(torrent),
+            client: Arc::new(AsyncMutex::new(client)),
+            stats,
+            limits,
+            state: Arc::new(Mutex::new(TorrentState::Paused)),
+            task: None,
+        });
+
+        self.resume(&info_hash)?;
+        info!("Added torrent {}", info_hash);
+
+        Ok(info_hash)
+    }
+
+    // Stops downloading and drops all of the torrent's connections, keeping
+    // the pieces downloaded so far.
+    pub async fn pause(&mut self, info_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let managed = self.managed(info_hash)?;
+
+        if let Some(task) = managed.task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+
+        managed.client.lock().await.disconnect_all();
+
+        let mut state = managed.state.lock().unwrap();
+        if *state == TorrentState::Downloading {
+            *state = TorrentState::Paused;
+        }
+
+        Ok(())
+    }
+
+    // Continues a paused (or failed) torrent from where it left off.
+    pub fn resume(&mut self, info_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let managed = self.managed(info_hash)?;
+
+        if managed.task.as_ref().is_some_and(|task| !task.is_finished()) {
+            return Ok(());
+        }
+        if *managed.state.lock().unwrap() == TorrentState::Completed {
+            return Ok(());
+        }
+
+        *managed.state.lock().unwrap() = TorrentState::Downloading;
+        managed.task = Some(tokio::spawn(run_torrent(
+            managed.client.clone(),
+            managed.torrent.clone(),
+            managed.storage.clone(),
+            managed.state.clone(),
+        )));
+
+        Ok(())
+    }
+
+    pub fn files(&mut self, info_hash: &str) -> Result<Vec<FileEntry>, Box<dyn std::error::Error>> {
+        Ok(self.managed(info_hash)?.files.clone())
+    }
+
+    // Changes which files get downloaded, and in what order. A running torrent
+    // is restarted to pick up the new priorities.
+    pub async fn set_file_priorities(&mut self, info_hash: &str, priorities: &[FilePriority]) -> Result<(), Box<dyn std::error::Error>> {
+        let num_files = self.managed(info_hash)?.files.len();
+        if priorities.len() != num_files {
+            return Err(format!("Expected {} file priorities, got {}", num_files, priorities.len()).into());
+        }
+
+        let was_running = self.state(info_hash) == Some(TorrentState::Downloading);
+        self.pause(info_hash).await?;
+
+        // The download is stopped, so the storage isn't held any more.
+        let managed = self.managed(info_hash)?;
+        let mut storage = managed.storage.lock().await;
+        storage.set_file_priorities(priorities)?;
+        managed.files = storage.files().to_vec();
+        drop(storage);
+
+        // Newly wanted files may need pieces we haven't downloaded.
+        let mut state = managed.state.lock().unwrap();
+        if *state == TorrentState::Completed {
+            *state = TorrentState::Paused;
+        }
+        drop(state);
+
+        if was_running {
+            self.resume(info_hash)?;
+        }
+
+        Ok(())
+    }
+
+    // Stops the torrent and forgets about it. Downloaded data is left in place.
+    pub async fn remove(&mut self, info_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.pause(info_hash).await?;
+
+        self.listener.remove_torrent(info_hash);
+        self.torrents.remove(info_hash);
+        info!("Removed torrent {}", info_hash);
+
+        Ok(())
+    }
+
+    // Waits until the torrent has finished downloading, failed or been paused.
+    pub async fn wait(&mut self, info_hash: &str) -> Result<TorrentState, Box<dyn std::error::Error>> {
+        let managed = self.managed(info_hash)?;
+
+        if let Some(task) = managed.task.take() {
+            let _ = task.await;
+        }
+
+        let state = managed.state.lock().unwrap().clone();
+        Ok(state)
+    }
+
+    pub fn torrents(&self) -> Vec<String> {
+        let mut info_hashes: Vec<String> = self.torrents.keys().cloned().collect();
+        info_hashes.sort();
+
+        info_hashes
+    }
+
+    pub fn state(&self, info_hash: &str) -> Option<TorrentState> {
+        self.torrents.get(info_hash).map(|managed| managed.state.lock().unwrap().clone())
+    }
+
+    pub fn stats(&self, info_hash: &str) -> Option<Arc<TransferStats>> {
+        self.torrents.get(info_hash).map(|managed| managed.stats.clone())
+    }
+
+    // Rates are in bytes per second, None meaning no limit. All of them can be
+    // changed while torrents are downloading.
+    pub fn set_global_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.global_limits.set_rates(download, upload);
+    }
+
+    pub fn set_torrent_limits(&mut self, info_hash: &str, download: Option<u64>, upload: Option<u64>) -> Result<(), Box<dyn std::error::Error>> {
+        self.managed(info_hash)?.limits.torrent().set_rates(download, upload);
+        Ok(())
+    }
+
+    pub fn set_peer_limits(&mut self, info_hash: &str, download: Option<u64>, upload: Option<u64>) -> Result<(), Box<dyn std::error::Error>> {
+        self.managed(info_hash)?.limits.set_peer_rates(download, upload);
+        Ok(())
+    }
+
+    pub fn available_connections(&self) -> usize {
+        self.connection_limit.available_permits()
+    }
+
+    fn managed(&mut self, info_hash: &str) -> Result<&mut ManagedTorrent, Box<dyn std::error::Error>> {
+        self.torrents
+            .get_mut(info_hash)
+            .ok_or_else(|| format!("Torrent {} is not in the session", info_hash).into())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for managed in self.torrents.values() {
+            if let Some(task) = &managed.task {
+                task.abort();
+            }
+        }
+    }
+}
+
+async fn run_torrent(client: Arc<AsyncMutex<Client>>, torrent: Arc<Torrent>, storage: Arc<AsyncMutex<Storage>>, state: Arc<Mutex<TorrentState>>) {
+    let mut client = client.lock().await;
+    let mut storage = storage.lock().await;
+
+    let new_state = match download_torrent(&mut client, &torrent, &mut storage).await {
+        Ok(()) => TorrentState::Completed,
+        Err(err) => {
+            warn!("Download of {} failed: {}", torrent.info.name, err);
+            TorrentState::Failed(err)
+        },
+    };
+
+    *state.lock().unwrap() = new_state;
+}
+
+async fn download_torrent(client: &mut Client, torrent: &Torrent, storage: &mut Storage) -> Result<(), String> {
+    if client.peer_candidates().is_empty() {
+        client.find_peers(torrent).await.map_err(|err| err.to_string())?;
+    }
+
+    client.download_files(torrent, storage).await.map_err(|err| err.to_string())
+}
+
+// Accepts peer connections on every listen address, over TCP and, where the
+// port is free for it too, over uTP. Connections are handed to torrents by
+// info hash once their handshake is answered.
+pub struct PeerListener {
+    local_addrs: Vec<SocketAddr>,
+    handler: IncomingHandler,
+    // Outgoing uTP connections go out from our listen port too.
+    utp: Option<Arc<UtpSocket>>,
+    proxy: Option<Proxy>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PeerListener {
+    pub async fn bind(peer_id: String, config: &ListenConfig, connection_limit: Arc<Semaphore>) -> Result<PeerListener, Box<dyn std::error::Error>> {
+        let handler = IncomingHandler {
+            peer_id,
+            connection_limit,
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            encryption: Arc::new(Mutex::new(EncryptionPolicy::default())),
+            dht_enabled: Arc::new(AtomicBool::new(false)),
+            force_proxy: Arc::new(AtomicBool::new(false)),
+        };
+
+        let mut local_addrs = vec![];
+        let mut utp = None;
+        let mut tasks = vec![];
+        for listener in config.bind().await? {
+            let local_addr = listener.local_addr()?;
+            info!("Listening for peers on {}", local_addr);
+
+            match UtpSocket::listen(local_addr).await {
+                Ok(socket) => {
+                    utp.get_or_insert_with(|| socket.clone());
+                    tasks.push(tokio::spawn(accept_utp_loop(socket, handler.clone())));
+                },
+                Err(err) => {
+                    warn!("Could not listen for uTP connections on {}: {}", local_addr, err);
+                },
+            }
+
+            tasks.push(tokio::spawn(accept_loop(listener, handler.clone())));
+            local_addrs.push(local_addr);
+        }
+
+        Ok(PeerListener { local_addrs, handler, utp, proxy: None, tasks })
+    }
+
+    // The port we listen on, on every address, and so the one to announce.
+    pub fn port(&self) -> u16 {
+        self.local_addrs[0].port()
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn utp_socket(&self) -> Option<Arc<UtpSocket>> {
+        self.utp.clone()
+    }
+
+    pub fn encryption_policy(&self) -> EncryptionPolicy {
+        *self.handler.encryption.lock().unwrap()
+    }
+
+    pub fn set_encryption_policy(&self, policy: EncryptionPolicy) {
+        *self.handler.encryption.lock().unwrap() = policy;
+    }
+
+    // Peers that connect to us are told we run a DHT node, as the peers we
+    // connect to are.
+    pub fn set_dht_enabled(&self, enabled: bool) {
+        self.handler.dht_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.proxy = Some(proxy);
+    }
+
+    // Peers can't reach us through the proxy, so incoming connections are
+    // dropped from then on.
+    pub fn force_proxy(&self) {
+        self.handler.force_proxy.store(true, Ordering::Relaxed);
+    }
+
+    // Peers connecting for the torrent come out of the returned channel.
+    pub fn add_torrent(&self, info_hash: &str) -> mpsc::UnboundedReceiver<IncomingPeer> {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        self.handler.routes.lock().unwrap().insert(info_hash.to_string(), incoming_tx);
+
+        incoming_rx
+    }
+
+    pub fn remove_torrent(&self, info_hash: &str) {
+        self.handler.routes.lock().unwrap().remove(info_hash);
+    }
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// Answers the handshakes of peers that connect to us, over TCP or uTP.
+#[derive(Clone)]
+struct IncomingHandler {
+    peer_id: String,
+    connection_limit: Arc<Semaphore>,
+    routes: IncomingRoutes,
+    encryption: Arc<Mutex<EncryptionPolicy>>,
+    dht_enabled: Arc<AtomicBool>,
+    force_proxy: Arc<AtomicBool>,
+}
+
+impl IncomingHandler {
+    fn handle(&self, stream: Transport, addr: SocketAddr) {
+        if self.force_proxy.load(Ordering::Relaxed) {
+            debug!("Refusing connection from {}, only connecting through the proxy", addr);
+            return;
+        }
+
+        let permit = match self.connection_limit.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("Connection limit reached, dropping connection from {}", addr);
+                return;
+            },
+        };
+
+        let peer_id = self.peer_id.clone();
+        let routes = self.routes.clone();
+        let policy = *self.encryption.lock().unwrap();
+        let dht_enabled = self.dht_enabled.load(Ordering::Relaxed);
+
+        tokio::spawn(async move {
+            match timeout(HANDSHAKE_TIMEOUT, answer_handshake(stream, &peer_id, &routes, policy, dht_enabled)).await {
+                Ok(Ok((incoming, route))) => {
+                    let _ = route.send(IncomingPeer { permit: Some(permit), ..incoming });
+                },
+                Ok(Err(err)) => {
+                    debug!("Dropping connection from {}: {}", addr, err);
+                },
+                Err(_) => {
+                    debug!("Timed out waiting for handshake from {}", addr);
+                },
+            }
+        });
+    }
+}
+
+async fn accept_loop(listener: TcpListener, handler: IncomingHandler) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => handler.handle(Transport::Tcp(stream), addr),
+            Err(err) => {
+                warn!("Could not accept connection: {}", err);
+            },
+        }
+    }
+}
+
+async fn accept_utp_loop(utp: Arc<UtpSocket>, handler: IncomingHandler) {
+    while let Some(stream) = utp.accept().await {
+        let addr = stream.peer_addr();
+        handler.handle(Transport::Utp(stream), addr);
+    }
+}
+
+// Reads the peer's handshake and, if it's for one of our torrents, answers it.
+// Encrypted connections are told apart from plaintext ones by their first bytes.
+async fn answer_handshake(mut stream: Transport, peer_id: &str, routes: &IncomingRoutes, policy: EncryptionPolicy, dht_enabled: bool) -> Result<(IncomingPeer, mpsc::UnboundedSender<IncomingPeer>), String> {
+    let mut prefix = [0; 20];
+    stream.read_exact(&mut prefix).await.map_err(|err| err.to_string())?;
+
+    let (mut stream, handshake) = if prefix[0] == 19 && &prefix[1..] == b"BitTorrent protocol" {
+        if policy == EncryptionPolicy::Require {
+            return Err("Plaintext connections are disabled".to_string());
+        }
+
+        let mut handshake = [0; PeerInfo::HANDSHAKE_LENGTH];
+        handshake[..prefix.len()].copy_from_slice(&prefix);
+        stream.read_exact(&mut handshake[prefix.len()..]).await.map_err(|err| err.to_string())?;
+        (PeerStream::plaintext(stream), handshake.to_vec())
+    } else {
+        let info_hashes: Vec<Vec<u8>> = routes.lock().unwrap().keys().filter_map(|info_hash| hex::decode(info_hash).ok()).collect();
+        let accepted = mse::accept(stream, &prefix, &info_hashes, policy).await.map_err(|err| err.to_string())?;
+
+        // The initial payload is read like the rest of the stream, so
+        // whatever follows the handshake reaches the message reader.
+        let mut stream = accepted.stream;
+        stream.unread(&accepted.payload);
+        let mut handshake = vec![0; PeerInfo::HANDSHAKE_LENGTH];
+        stream.read_exact(&mut handshake).await.map_err(|err| err.to_string())?;
+
+        if handshake[28..48] != accepted.info_hash[..] {
+            return Err("Handshake doesn't match the encrypted connection's torrent".to_string());
+        }
+        (stream, handshake)
+    };
+
+    if handshake[0] != 19 || &handshake[1..20] != b"BitTorrent protocol" {
+        return Err("Not a BitTorrent handshake".to_string());
+    }
+
+    let info_hash = &handshake[28..48];
+    let route = routes
+        .lock()
+        .unwrap()
+        .get(&hex::encode(info_hash))
+        .cloned()
+        .ok_or(format!("Unknown info hash {}", hex::encode(info_hash)))?;
+
+    stream.write_all(&dht_handshake_message(peer_id, info_hash, dht_enabled)).await.map_err(|err| err.to_string())?;
+
+    Ok((IncomingPeer { stream, handshake: handshake.into(), permit: None }, route))
+}
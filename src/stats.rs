@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// Running totals for a client. They're updated as the client works and can be
+// sampled from another task, e.g. to draw a progress display.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    overhead_downloaded: AtomicU64,
+    overhead_uploaded: AtomicU64,
+    verified_bytes: AtomicU64,
+    pieces_verified: AtomicU64,
+    connected_peers: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatsSnapshot {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // Protocol traffic other than piece data: handshakes, message headers etc.
+    pub overhead_downloaded: u64,
+    pub overhead_uploaded: u64,
+    pub verified_bytes: u64,
+    pub pieces_verified: u64,
+    pub connected_peers: usize,
+}
+
+impl TransferStats {
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_overhead_downloaded(&self, bytes: u64) {
+        self.overhead_downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_overhead_uploaded(&self, bytes: u64) {
+        self.overhead_uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn piece_verified(&self, piece_length: u64) {
+        self.verified_bytes.fetch_add(piece_length, Ordering::Relaxed);
+        self.pieces_verified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_connected_peers(&self, peers: usize) {
+        self.connected_peers.store(peers, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            overhead_downloaded: self.overhead_downloaded.load(Ordering::Relaxed),
+            overhead_uploaded: self.overhead_uploaded.load(Ordering::Relaxed),
+            verified_bytes: self.verified_bytes.load(Ordering::Relaxed),
+            pieces_verified: self.pieces_verified.load(Ordering::Relaxed),
+            connected_peers: self.connected_peers.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use regex::Regex;
+use tokio::sync::watch;
+
+use crate::{debug, domain::Torrent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for FilePriority {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_ascii_lowercase().as_str() {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => Err(format!("Unknown file priority: {}", input)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    // Path inside the torrent, with components joined by '/'.
+    pub name: String,
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+    pub priority: FilePriority,
+    // Padding that aligns the next file to a piece boundary. It's all zeros
+    // and never written.
+    pub padding: bool,
+}
+
+impl FileEntry {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.length > 0 && self.offset < end && start < self.offset + self.length
+    }
+}
+
+// Maps the torrent's pieces onto the files they belong to. Pieces that
+// straddle a wanted and a skipped file are staged whole in a parts directory,
+// so the skipped file isn't created, and are written out later if the file
+// becomes wanted.
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    total_length: u64,
+    parts_dir: PathBuf,
+    // Which pieces we have. Staged pieces count too, since their bytes in
+    // wanted files are written all the same.
+    written: watch::Sender<Vec<bool>>,
+}
+
+impl Storage {
+    // A single-file torrent is written to `output_path` itself, a multi-file
+    // torrent to a directory at `output_path`.
+    pub fn new(torrent: &Torrent, output_path: &Path) -> Self {
+        let mut files = vec![];
+        let mut offset = 0;
+
+        for file in torrent.files() {
+            let path = if torrent.is_multi_file() {
+                output_path.join(sanitize_path(&file.path))
+            } else {
+                output_path.to_path_buf()
+            };
+
+            files.push(FileEntry {
+                name: file.path.join("/"),
+                path,
+                offset,
+                length: file.length as u64,
+                priority: FilePriority::Normal,
+                padding: file.is_padding(),
+            });
+            offset += file.length as u64;
+        }
+
+        let parts_dir = if torrent.is_multi_file() {
+            output_path.join(".parts")
+        } else {
+            let file_name = output_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            output_path.with_file_name(format!(".{}.parts", file_name))
+        };
+
+        let (written, _) = watch::channel(vec![false; torrent.get_num_pieces() as usize]);
+
+        Storage {
+            files,
+            piece_length: torrent.info.piece_length as u64,
+            total_length: offset,
+            parts_dir,
+            written,
+        }
+    }
+
+    // Reads what has been written so far, e.g. to serve files while they're
+    // still downloading.
+    pub fn reader(&self) -> StorageReader {
+        StorageReader {
+            files: self.files.clone(),
+            piece_length: self.piece_length,
+            written: self.written.subscribe(),
+        }
+    }
+
+    fn mark_written(&self, piece_index: u32) {
+        self.written.send_modify(|written| {
+            if let Some(written) = written.get_mut(piece_index as usize) {
+                *written = true;
+            }
+        });
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.total_length.div_ceil(self.piece_length) as u32
+    }
+
+    pub fn set_file_priority(&mut self, file_index: usize, priority: FilePriority) -> Result<(), Box<dyn std::error::Error>> {
+        let file = self.files
+            .get_mut(file_index)
+            .ok_or(format!("No file with index {}", file_index))?;
+        file.priority = priority;
+
+        self.unstage()
+    }
+
+    // Sets every file's priority, e.g. from `select_files`.
+    pub fn set_file_priorities(&mut self, priorities: &[FilePriority]) -> Result<(), Box<dyn std::error::Error>> {
+        if priorities.len() != self.files.len() {
+            return Err(format!("Expected {} file priorities, got {}", self.files.len(), priorities.len()).into());
+        }
+
+        for (file, priority) in self.files.iter_mut().zip(priorities) {
+            file.priority = *priority;
+        }
+
+        self.unstage()
+    }
+
+    fn piece_range(&self, piece_index: u32) -> (u64, u64) {
+        let start = piece_index as u64 * self.piece_length;
+        (start, (start + self.piece_length).min(self.total_length))
+    }
+
+    fn piece_files(&self, piece_index: u32) -> impl Iterator<Item = &FileEntry> {
+        let (start, end) = self.piece_range(piece_index);
+        self.files.iter().filter(move |file| !file.padding && file.overlaps(start, end))
+    }
+
+    // A piece is as important as the most important file it's part of.
+    pub fn piece_priorities(&self) -> Vec<FilePriority> {
+        (0..self.num_pieces())
+            .map(|piece_index| self.piece_files(piece_index).map(|file| file.priority).max().unwrap_or(FilePriority::Skip))
+            .collect()
+    }
+
+    // Bytes in the pieces we're going to download.
+    pub fn wanted_length(&self) -> u64 {
+        self.piece_priorities()
+            .iter()
+            .enumerate()
+            .filter(|(_, priority)| **priority != FilePriority::Skip)
+            .map(|(piece_index, _)| {
+                let (start, end) = self.piece_range(piece_index as u32);
+                end - start
+            })
+            .sum()
+    }
+
+    // Creates the wanted files that are empty, as no piece will ever do that.
+    pub fn create_empty_files(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for file in &self.files {
+            if file.length == 0 && !file.padding && file.priority != FilePriority::Skip {
+                create_parent_dir(&file.path)?;
+                OpenOptions::new().create(true).write(true).truncate(false).open(&file.path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write_piece(&mut self, piece_index: u32, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let (start, end) = self.piece_range(piece_index);
+        if data.len() as u64 != end - start {
+            return Err(format!("Piece {} should be {} bytes, got {}", piece_index, end - start, data.len()).into());
+        }
+
+        let mut skipped = false;
+
+        for file in self.piece_files(piece_index) {
+            if file.priority == FilePriority::Skip {
+                skipped = true;
+                continue;
+            }
+
+            write_segment(file, start, data)?;
+        }
+
+        if skipped {
+            debug!("Staging piece {} as it's partly in skipped files", piece_index);
+            fs::create_dir_all(&self.parts_dir)?;
+            fs::write(self.part_path(piece_index), data)?;
+        }
+        self.mark_written(piece_index);
+
+        Ok(())
+    }
+
+    // Reads a piece back from the files, for checking data already on disk.
+    // Padding files are never written, so their bytes read as zeros.
+    pub fn read_piece(&self, piece_index: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let part_path = self.part_path(piece_index);
+        if part_path.exists() {
+            return Ok(fs::read(part_path)?);
+        }
+
+        let (start, end) = self.piece_range(piece_index);
+        let mut data = vec![0; (end - start) as usize];
+
+        for file in self.piece_files(piece_index) {
+            let segment_start = start.max(file.offset);
+            let segment_end = end.min(file.offset + file.length);
+
+            let mut input = File::open(&file.path)?;
+            input.seek(SeekFrom::Start(segment_start - file.offset))?;
+            input.read_exact(&mut data[(segment_start - start) as usize..(segment_end - start) as usize])?;
+        }
+
+        Ok(data)
+    }
+
+    // Whether the piece on disk matches the torrent's hashes.
+    pub fn verify_piece(&self, torrent: &Torrent, piece_index: u32) -> bool {
+        match self.read_piece(piece_index) {
+            Ok(data) => torrent.verify_piece(piece_index, &data, &BTreeMap::new()),
+            Err(_) => false,
+        }
+    }
+
+    fn part_path(&self, piece_index: u32) -> PathBuf {
+        self.parts_dir.join(format!("{}.part", piece_index))
+    }
+
+    // Writes staged pieces into files that are now wanted, and drops the ones
+    // that no longer touch a skipped file.
+    fn unstage(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.parts_dir.exists() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(&self.parts_dir)? {
+            let path = entry?.path();
+            let piece_index: u32 = match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                Some(piece_index) => piece_index,
+                None => continue,
+            };
+
+            let data = fs::read(&path)?;
+            let (start, _) = self.piece_range(piece_index);
+            let mut still_skipped = false;
+
+            for file in self.piece_files(piece_index) {
+                if file.priority == FilePriority::Skip {
+                    still_skipped = true;
+                } else {
+                    write_segment(file, start, &data)?;
+                }
+            }
+
+            if !still_skipped {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        if fs::read_dir(&self.parts_dir)?.next().is_none() {
+            fs::remove_dir(&self.parts_dir)?;
+        }
+
+        Ok(())
+    }
+}
+
+// A read-only view of a `Storage`, which can be used from another task while
+// the storage itself is busy downloading.
+#[derive(Debug, Clone)]
+pub struct StorageReader {
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    written: watch::Receiver<Vec<bool>>,
+}
+
+impl StorageReader {
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    // The pieces holding bytes `range` of the file.
+    pub fn file_pieces(&self, file_index: usize, range: Range<u64>) -> Range<u32> {
+        let file = &self.files[file_index];
+        if range.start >= range.end {
+            return 0..0;
+        }
+
+        let start = file.offset + range.start;
+        let end = file.offset + range.end;
+        (start / self.piece_length) as u32..end.div_ceil(self.piece_length) as u32
+    }
+
+    // Bytes of the file that are in the piece, relative to the start of the file.
+    pub fn piece_file_range(&self, file_index: usize, piece_index: u32) -> Range<u64> {
+        let file = &self.files[file_index];
+        let start = (piece_index as u64 * self.piece_length).max(file.offset);
+        let end = ((piece_index as u64 + 1) * self.piece_length).min(file.offset + file.length).max(start);
+
+        start - file.offset..end - file.offset
+    }
+
+    pub fn has_piece(&self, piece_index: u32) -> bool {
+        self.written.borrow().get(piece_index as usize).copied().unwrap_or(false)
+    }
+
+    // Waits until the piece has been written. Fails if the storage goes away first.
+    pub async fn wait_for_piece(&mut self, piece_index: u32) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        while !self.has_piece(piece_index) {
+            self.written
+                .changed()
+                .await
+                .map_err(|_| format!("Storage was closed before piece {} was written", piece_index))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&self, file_index: usize, range: Range<u64>) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let file = self.files.get(file_index).ok_or(format!("No file with index {}", file_index))?;
+        if range.end > file.length || range.start > range.end {
+            return Err(format!("Can't read bytes {:?} of {}, which is {} bytes", range, file.name, file.length).into());
+        }
+
+        let mut data = vec![0; (range.end - range.start) as usize];
+        if file.padding {
+            return Ok(data);
+        }
+
+        let mut input = File::open(&file.path)?;
+        input.seek(SeekFrom::Start(range.start))?;
+        input.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+// Writes the part of the piece starting at torrent offset `piece_start` that
+// belongs to `file`.
+fn write_segment(file: &FileEntry, piece_start: u64, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let piece_end = piece_start + data.len() as u64;
+    let start = piece_start.max(file.offset);
+    let end = piece_end.min(file.offset + file.length);
+
+    create_parent_dir(&file.path)?;
+    let mut out = OpenOptions::new().create(true).write(true).truncate(false).open(&file.path)?;
+
+    out.seek(SeekFrom::Start(start - file.offset))?;
+    out.write_all(&data[(start - piece_start) as usize..(end - piece_start) as usize])?;
+
+    Ok(())
+}
+
+fn create_parent_dir(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Keeps paths from the torrent inside the download directory.
+fn sanitize_path(components: &[String]) -> PathBuf {
+    components
+        .iter()
+        .flat_map(|component| Path::new(component).components())
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+// Picks files by index ("3") or glob pattern ("docs/*.txt", "**.mkv"),
+// separated by commas. Patterns are matched against the file's path inside the
+// torrent, where `*` and `?` don't match '/' but `**` does.
+pub fn select_files(files: &[FileEntry], selection: &str) -> Result<HashSet<usize>, String> {
+    let mut selected = HashSet::new();
+
+    for item in selection.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        if let Ok(index) = item.parse::<usize>() {
+            if index >= files.len() {
+                return Err(format!("No file with index {}, the torrent has {} files", index, files.len()));
+            }
+            selected.insert(index);
+            continue;
+        }
+
+        let pattern = glob_to_regex(item)?;
+        let matches: Vec<usize> = files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| pattern.is_match(&file.name))
+            .map(|(index, _)| index)
+            .collect();
+
+        if matches.is_empty() {
+            return Err(format!("No files match {}", item));
+        }
+        selected.extend(matches);
+    }
+
+    Ok(selected)
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, String> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            },
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+
+    Regex::new(&pattern).map_err(|err| format!("Invalid pattern {}: {}", glob, err))
+}
@@ -0,0 +1,83 @@
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamingConfig {
+    // Number of pieces from the playback position on that are fetched in order.
+    pub read_ahead: u32,
+    // How long playback takes per piece. The piece at the playback position is
+    // due after one interval, the next one after two, and so on.
+    pub piece_interval: Duration,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            read_ahead: 8,
+            piece_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+// Where playback is, as a piece index. Clones share the same position, so a
+// player can move it while the download is running.
+#[derive(Debug, Clone)]
+pub struct PlaybackCursor {
+    position: Arc<Mutex<(u32, Instant)>>,
+}
+
+impl Default for PlaybackCursor {
+    fn default() -> Self {
+        PlaybackCursor { position: Arc::new(Mutex::new((0, Instant::now()))) }
+    }
+}
+
+impl PlaybackCursor {
+    pub fn piece(&self) -> u32 {
+        self.position.lock().unwrap().0
+    }
+
+    // Moving the cursor restarts the clock for the read-ahead deadlines.
+    pub fn set_piece(&self, piece_index: u32) {
+        let mut position = self.position.lock().unwrap();
+        if position.0 != piece_index {
+            *position = (piece_index, Instant::now());
+        }
+    }
+
+    fn get(&self) -> (u32, Instant) {
+        *self.position.lock().unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Streaming {
+    pub config: StreamingConfig,
+    pub cursor: PlaybackCursor,
+}
+
+impl Streaming {
+    pub fn new(config: StreamingConfig) -> Self {
+        Streaming { config, cursor: PlaybackCursor::default() }
+    }
+
+    pub fn window(&self) -> Range<u32> {
+        let piece = self.cursor.piece();
+        piece..piece.saturating_add(self.config.read_ahead)
+    }
+
+    // When the piece has to be there for playback not to stall. Only pieces
+    // in the read-ahead window have a deadline.
+    pub fn deadline(&self, piece_index: u32) -> Option<Instant> {
+        let (cursor, moved_at) = self.cursor.get();
+
+        if piece_index < cursor || piece_index >= cursor.saturating_add(self.config.read_ahead) {
+            return None;
+        }
+
+        Some(moved_at + self.config.piece_interval * (piece_index - cursor + 1))
+    }
+}
@@ -0,0 +1,199 @@
+// Multitracker metadata (BEP 12). Tiers are tried in order, and the trackers
+// within a tier in a random order. The first tracker to answer moves to the
+// front of its tier, and only its peers are used. UDP trackers (BEP 15) are
+// announced to here too; HTTP ones are handled by the client.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::Url;
+use tokio::{net::{lookup_host, UdpSocket}, time::timeout};
+
+use crate::{
+    domain::Torrent,
+    pex::decode_compact_peers,
+    proxy::{Proxy, Socks5Udp},
+    random::random_u64,
+};
+
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+const UDP_ATTEMPTS: usize = 2;
+const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut tiers = torrent.trackers();
+        for tier in &mut tiers {
+            shuffle(tier);
+        }
+
+        TrackerTiers { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    // Moves a tracker that answered to the front of its tier, so it's asked
+    // first next time.
+    pub fn promote(&mut self, url: &str) {
+        for tier in &mut self.tiers {
+            if let Some(index) = tier.iter().position(|tracker| tracker == url) {
+                let tracker = tier.remove(index);
+                tier.insert(0, tracker);
+                return;
+            }
+        }
+    }
+}
+
+fn shuffle(items: &mut [String]) {
+    // Spreading load over trackers doesn't need cryptographic randomness.
+    for i in (1..items.len()).rev() {
+        let j = (random_u64() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpAnnounce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    // Only IPv4 addresses can be given to UDP trackers.
+    pub ip: Option<Ipv4Addr>,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpAnnounceResponse {
+    pub interval: u32,
+    pub peers: Vec<SocketAddr>,
+}
+
+enum UdpTrackerSocket {
+    Direct(UdpSocket),
+    Socks5(Socks5Udp),
+}
+
+// Announces to a udp:// tracker, directly or relayed by a SOCKS5 proxy.
+pub async fn announce_udp(tracker: &str, announce: &UdpAnnounce, proxy: Option<&Proxy>) -> Result<UdpAnnounceResponse, Box<dyn std::error::Error>> {
+    let url = Url::parse(tracker)?;
+    let host = url.host_str().ok_or("UDP tracker without a host")?.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port().ok_or("UDP tracker without a port")?;
+
+    let socket = match proxy {
+        Some(proxy) => UdpTrackerSocket::Socks5(proxy.udp_associate().await?),
+        None => {
+            let addr = lookup_host((host.as_str(), port)).await?.next().ok_or(format!("Could not resolve {}", host))?;
+            let local = match addr {
+                SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(addr).await?;
+            UdpTrackerSocket::Direct(socket)
+        },
+    };
+
+    // Transaction ids and the key only match up requests and responses, they
+    // don't need to be secure.
+    let transaction = random_u64() as u32;
+    let mut request = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+    request.extend(ACTION_CONNECT.to_be_bytes());
+    request.extend(transaction.to_be_bytes());
+    let response = socket.exchange(&host, port, &request, ACTION_CONNECT, transaction).await?;
+    let connection_id = &response[8..16];
+
+    let transaction = random_u64() as u32;
+    let mut request = connection_id.to_vec();
+    request.extend(ACTION_ANNOUNCE.to_be_bytes());
+    request.extend(transaction.to_be_bytes());
+    request.extend(announce.info_hash);
+    request.extend(announce.peer_id);
+    request.extend(announce.downloaded.to_be_bytes());
+    request.extend(announce.left.to_be_bytes());
+    request.extend(announce.uploaded.to_be_bytes());
+    // No event, the address (0 for the one we send from), a key and as many
+    // peers as the tracker likes.
+    request.extend(0u32.to_be_bytes());
+    request.extend(announce.ip.unwrap_or(Ipv4Addr::UNSPECIFIED).octets());
+    request.extend((random_u64() as u32).to_be_bytes());
+    request.extend((-1i32).to_be_bytes());
+    request.extend(announce.port.to_be_bytes());
+    let response = socket.exchange(&host, port, &request, ACTION_ANNOUNCE, transaction).await?;
+
+    Ok(UdpAnnounceResponse {
+        interval: u32::from_be_bytes(response[8..12].try_into().unwrap()),
+        peers: decode_compact_peers(&response[20..]),
+    })
+}
+
+impl UdpTrackerSocket {
+    // Sends `request` until an answer to it arrives, and returns the answer.
+    async fn exchange(&self, host: &str, port: u16, request: &[u8], action: u32, transaction: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let minimum_length = if action == ACTION_ANNOUNCE { 20 } else { 16 };
+
+        for _ in 0..UDP_ATTEMPTS {
+            match self {
+                UdpTrackerSocket::Direct(socket) => {
+                    socket.send(request).await?;
+                },
+                UdpTrackerSocket::Socks5(relay) => relay.send_to(request, host, port).await?,
+            }
+
+            let received = timeout(UDP_TIMEOUT, async {
+                loop {
+                    let response = self.recv().await?;
+                    // Anything else is a late answer to an earlier request.
+                    if response.len() >= 8 && response[4..8] == transaction.to_be_bytes() {
+                        return Ok::<Vec<u8>, std::io::Error>(response);
+                    }
+                }
+            });
+            let response = match received.await {
+                Ok(response) => response?,
+                Err(_) => continue,
+            };
+
+            let response_action = u32::from_be_bytes(response[0..4].try_into().unwrap());
+            if response_action == ACTION_ERROR {
+                return Err(format!("UDP tracker {} returned an error: {}", host, String::from_utf8_lossy(&response[8..])).into());
+            }
+            if response_action != action || response.len() < minimum_length {
+                return Err(format!("Invalid response from UDP tracker {}", host).into());
+            }
+            return Ok(response);
+        }
+
+        Err(format!("UDP tracker {} did not answer", host).into())
+    }
+
+    async fn recv(&self) -> std::io::Result<Vec<u8>> {
+        match self {
+            UdpTrackerSocket::Direct(socket) => {
+                let mut buffer = vec![0; 65536];
+                let length = socket.recv(&mut buffer).await?;
+                buffer.truncate(length);
+                Ok(buffer)
+            },
+            UdpTrackerSocket::Socks5(relay) => Ok(relay.recv_from().await?.0),
+        }
+    }
+}
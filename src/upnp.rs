@@ -0,0 +1,204 @@
+// Port mapping with a UPnP Internet Gateway Device: the gateway is found with
+// an SSDP search, and mappings are added through the SOAP control URL of its
+// WANIPConnection (or, on DSL modems, WANPPPConnection) service.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use reqwest::Url;
+use tokio::{net::UdpSocket, time::{timeout_at, Instant}};
+
+use crate::{
+    debug,
+    portmap::{PortMapping, Protocol},
+};
+
+pub const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+const GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const WAN_SERVICES: &[&str] = &["WANIPConnection", "WANPPPConnection"];
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Returned by gateways that can't expire mappings on their own.
+const ONLY_PERMANENT_LEASES: &str = "725";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpnpGateway {
+    pub control_url: String,
+    pub service_type: String,
+    // Our address on the gateway's network, which mappings point at.
+    pub local_ip: IpAddr,
+}
+
+impl UpnpGateway {
+    // Searches for a gateway by sending an SSDP search to `ssdp_addr`,
+    // normally the multicast group, and takes the first one with a WAN
+    // connection service.
+    pub async fn discover(ssdp_addr: SocketAddr, wait: Duration) -> Result<UpnpGateway, Box<dyn std::error::Error>> {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+            SSDP_ADDR, wait.as_secs().max(1), GATEWAY_DEVICE,
+        );
+        socket.send_to(search.as_bytes(), ssdp_addr).await?;
+
+        let deadline = Instant::now() + wait;
+        let mut buffer = vec![0; 2048];
+        loop {
+            let length = match timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+                Ok(received) => received?.0,
+                Err(_) => return Err("No UPnP gateway answered".into()),
+            };
+
+            let location = match search_location(&buffer[..length]) {
+                Some(location) => location,
+                None => continue,
+            };
+            match Self::from_description(&location).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(err) => {
+                    debug!("Ignoring UPnP device at {}: {}", location, err);
+                },
+            }
+        }
+    }
+
+    // Reads the device description at `location` for a WAN connection service.
+    pub async fn from_description(location: &str) -> Result<UpnpGateway, Box<dyn std::error::Error>> {
+        let url = Url::parse(location)?;
+        let http = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let description = http.get(url.clone()).send().await?.error_for_status()?.text().await?;
+
+        let base = match xml_value(&description, "URLBase") {
+            Some(base) => Url::parse(base)?,
+            None => url.clone(),
+        };
+        let (service_type, control_url) = description
+            .split("<service>")
+            .skip(1)
+            .filter_map(|service| Some((xml_value(service, "serviceType")?, xml_value(service, "controlURL")?)))
+            .find(|(service_type, _)| WAN_SERVICES.iter().any(|wan| service_type.contains(wan)))
+            .ok_or("Device has no WAN connection service")?;
+
+        // The address the gateway sees us on, found by routing towards it.
+        let gateway_addr = *url.socket_addrs(|| Some(80))?.first().ok_or("Gateway has no address")?;
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+        socket.connect(gateway_addr).await?;
+
+        Ok(UpnpGateway {
+            control_url: base.join(control_url)?.to_string(),
+            service_type: service_type.to_string(),
+            local_ip: socket.local_addr()?.ip(),
+        })
+    }
+
+    pub async fn external_ip(&self) -> Result<IpAddr, Box<dyn std::error::Error>> {
+        let response = self.soap_request("GetExternalIPAddress", &[]).await?;
+        let ip = xml_value(&response, "NewExternalIPAddress").ok_or("Gateway did not give its external address")?;
+
+        Ok(ip.parse()?)
+    }
+
+    // Maps the same port outside to `internal_port` on our address. Gateways
+    // that only take permanent mappings get one, which we remove ourselves.
+    pub async fn map(&self, protocol: Protocol, internal_port: u16, lifetime: Duration) -> Result<PortMapping, Box<dyn std::error::Error>> {
+        let leased = self.add_port_mapping(protocol, internal_port, lifetime).await?;
+        let lifetime = if leased {
+            lifetime
+        } else {
+            debug!("Gateway only supports permanent port mappings");
+            self.add_port_mapping(protocol, internal_port, Duration::ZERO).await?;
+            Duration::ZERO
+        };
+        let external_ip = self.external_ip().await?;
+
+        Ok(PortMapping { protocol, internal_port, external: SocketAddr::new(external_ip, internal_port), lifetime })
+    }
+
+    pub async fn unmap(&self, protocol: Protocol, external_port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let port = external_port.to_string();
+        let args = [("NewRemoteHost", ""), ("NewExternalPort", port.as_str()), ("NewProtocol", protocol.name())];
+        self.soap_request("DeletePortMapping", &args).await?;
+
+        Ok(())
+    }
+
+    // False when the gateway only supports permanent mappings.
+    async fn add_port_mapping(&self, protocol: Protocol, internal_port: u16, lifetime: Duration) -> Result<bool, Box<dyn std::error::Error>> {
+        let port = internal_port.to_string();
+        let local_ip = self.local_ip.to_string();
+        let lease = lifetime.as_secs().to_string();
+        let description = format!("{} {}", env!("CARGO_PKG_NAME"), protocol.name());
+        let args = [
+            ("NewRemoteHost", ""),
+            ("NewExternalPort", port.as_str()),
+            ("NewProtocol", protocol.name()),
+            ("NewInternalPort", port.as_str()),
+            ("NewInternalClient", local_ip.as_str()),
+            ("NewEnabled", "1"),
+            ("NewPortMappingDescription", description.as_str()),
+            ("NewLeaseDuration", lease.as_str()),
+        ];
+
+        match self.soap_request("AddPortMapping", &args).await {
+            Ok(_) => Ok(true),
+            Err(err) if !lifetime.is_zero() && err.to_string().contains(ONLY_PERMANENT_LEASES) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn soap_request(&self, action: &str, args: &[(&str, &str)]) -> Result<String, Box<dyn std::error::Error>> {
+        let arguments: String = args.iter().map(|(name, value)| format!("<{}>{}</{}>", name, value, name)).collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{} xmlns:u=\"{}\">{}</u:{}></s:Body></s:Envelope>\r\n",
+            action, self.service_type, arguments, action,
+        );
+
+        let http = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let response = http
+            .post(&self.control_url)
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#{}\"", self.service_type, action))
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            let code = xml_value(&text, "errorCode").unwrap_or_default();
+            let description = xml_value(&text, "errorDescription").unwrap_or_default();
+            return Err(format!("UPnP {} failed with {}: {} {}", action, status, code, description).into());
+        }
+
+        Ok(text)
+    }
+}
+
+// The LOCATION header of an SSDP search response.
+fn search_location(response: &[u8]) -> Option<String> {
+    let response = std::str::from_utf8(response).ok()?;
+    if !response.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+
+    response
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+// Text of the first `<tag>`, with or without a namespace prefix.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("{}>", tag);
+    let start = xml.match_indices(&open).find(|(index, _)| {
+        let before = &xml[..*index];
+        before.ends_with('<') || before.rsplit('<').next().is_some_and(|prefix| prefix.ends_with(':') && !prefix.starts_with('/'))
+    })?.0 + open.len();
+    let end = start + xml[start..].find("</")?;
+
+    Some(xml[start..end].trim())
+}
@@ -0,0 +1,921 @@
+// uTP (BEP 29): reliable, ordered streams over UDP. Its LEDBAT congestion
+// control backs off as soon as queueing delay builds up, so uploads yield to
+// other traffic on the link instead of filling its buffers.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::{mpsc, oneshot, Mutex as AsyncMutex, Notify},
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+
+use crate::{debug, random::random_u64};
+
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+const SELECTIVE_ACK_EXTENSION: u8 = 1;
+// Payload per packet, small enough to fit in a typical MTU.
+const MAX_PAYLOAD: usize = 1200;
+
+// LEDBAT: aim for 100ms of queueing delay, and grow the window by at most
+// this many bytes per round trip.
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+const INITIAL_WINDOW: usize = 10 * MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1024 * 1024;
+// How long the lowest delay seen counts as the base delay.
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+
+const RECEIVE_WINDOW: usize = 1024 * 1024;
+const SEND_BUFFER: usize = 1024 * 1024;
+// How far past the next expected packet we keep packets that arrive early.
+const MAX_OUT_OF_ORDER: u16 = 1024;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(16);
+// Timeouts in a row before giving up, while connecting and afterwards.
+const MAX_SYN_TIMEOUTS: u32 = 2;
+const MAX_TIMEOUTS: u32 = 6;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<PacketType> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    // How long ago, by the sender's clock, the last packet it got was sent.
+    pub timestamp_difference: u32,
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // Packets received past ack_nr + 1: bit i stands for ack_nr + 2 + i,
+    // starting from the least significant bit of the first byte.
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Packet {
+        Packet {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: vec![],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.push((self.packet_type as u8) << 4 | VERSION);
+        bytes.push(if self.selective_ack.is_some() { SELECTIVE_ACK_EXTENSION } else { 0 });
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_difference.to_be_bytes());
+        bytes.extend(self.window.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend(mask);
+        }
+
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Packet, String> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(format!("uTP packet of {} bytes is too short", bytes.len()));
+        }
+        if bytes[0] & 0x0F != VERSION {
+            return Err(format!("Unsupported uTP version {}", bytes[0] & 0x0F));
+        }
+
+        let packet_type = PacketType::from_u8(bytes[0] >> 4).ok_or(format!("Unknown uTP packet type {}", bytes[0] >> 4))?;
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        let mut extension = bytes[1];
+        let mut offset = HEADER_LENGTH;
+        let mut selective_ack = None;
+
+        while extension != 0 {
+            if offset + 2 > bytes.len() {
+                return Err("Truncated uTP extension header".to_string());
+            }
+            let next = bytes[offset];
+            let length = bytes[offset + 1] as usize;
+            offset += 2;
+
+            if offset + length > bytes.len() {
+                return Err("Truncated uTP extension".to_string());
+            }
+            if extension == SELECTIVE_ACK_EXTENSION {
+                selective_ack = Some(bytes[offset..offset + length].to_vec());
+            }
+
+            offset += length;
+            extension = next;
+        }
+
+        Ok(Packet {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: bytes[offset..].to_vec(),
+        })
+    }
+}
+
+// Whether sequence number `a` comes after `b`, allowing for wrap around.
+fn seq_after(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+fn timestamp_micros() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u32).unwrap_or_default()
+}
+
+type Routes = Arc<Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>>;
+
+// A UDP socket that uTP connections are multiplexed over, told apart by the
+// remote address and connection id.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+    incoming: Option<AsyncMutex<mpsc::UnboundedReceiver<UtpStream>>>,
+    receiver: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    // A socket for outgoing connections only. Connection attempts from
+    // other peers are reset.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Arc<UtpSocket>> {
+        Self::open(addr, false).await
+    }
+
+    // A socket that also accepts connections.
+    pub async fn listen(addr: SocketAddr) -> io::Result<Arc<UtpSocket>> {
+        Self::open(addr, true).await
+    }
+
+    async fn open(addr: SocketAddr, accept: bool) -> io::Result<Arc<UtpSocket>> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let routes: Routes = Arc::new(Mutex::new(HashMap::new()));
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+        let incoming_tx = Some(incoming_tx).filter(|_| accept);
+        let receiver = tokio::spawn(receive_loop(socket.clone(), routes.clone(), incoming_tx));
+
+        Ok(Arc::new(UtpSocket {
+            socket,
+            routes,
+            incoming: Some(AsyncMutex::new(incoming_rx)).filter(|_| accept),
+            receiver,
+        }))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let (packets_tx, packets_rx) = mpsc::unbounded_channel();
+        let recv_id = {
+            let mut routes = self.routes.lock().unwrap();
+            // Connection ids and sequence numbers aren't secrets, uTP trusts
+            // the sender's address like TCP does.
+            let mut recv_id = random_u64() as u16;
+            while routes.contains_key(&(addr, recv_id)) {
+                recv_id = random_u64() as u16;
+            }
+            routes.insert((addr, recv_id), packets_tx);
+            recv_id
+        };
+
+        let (connected_tx, connected_rx) = oneshot::channel();
+        let mut connection = Connection::new(self.socket.clone(), self.routes.clone(), addr, recv_id.wrapping_add(1), recv_id, packets_rx);
+        connection.seq_nr = 1;
+        connection.connected = false;
+        connection.on_connect = Some(connected_tx);
+        let stream = UtpStream { shared: connection.shared.clone(), peer_addr: addr };
+
+        tokio::spawn(async move {
+            // The SYN carries the id we receive on, unlike every later packet.
+            let syn = Packet::new(PacketType::Syn, connection.recv_id, connection.seq_nr, 0);
+            connection.seq_nr = connection.seq_nr.wrapping_add(1);
+            connection.transmit(syn).await;
+            connection.run().await;
+        });
+
+        match connected_rx.await {
+            Ok(result) => result.map(|_| stream),
+            Err(_) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("uTP connection to {} was dropped", addr))),
+        }
+    }
+
+    // The next connection from another peer, or None if the socket doesn't
+    // accept connections.
+    pub async fn accept(&self) -> Option<UtpStream> {
+        self.incoming.as_ref()?.lock().await.recv().await
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, routes: Routes, incoming: Option<mpsc::UnboundedSender<UtpStream>>) {
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let (length, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                debug!("Could not receive uTP packet: {}", err);
+                continue;
+            },
+        };
+
+        let packet = match Packet::from_bytes(&buffer[..length]) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!("Dropping packet from {}: {}", addr, err);
+                continue;
+            },
+        };
+
+        // A SYN names the id its sender receives on, and we receive on the next one.
+        let recv_id = match packet.packet_type {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+
+        let route = routes.lock().unwrap().get(&(addr, recv_id)).cloned();
+        match (route, &incoming) {
+            (Some(route), _) => {
+                let _ = route.send(packet);
+            },
+            (None, Some(incoming)) if packet.packet_type == PacketType::Syn => {
+                let (packets_tx, packets_rx) = mpsc::unbounded_channel();
+                routes.lock().unwrap().insert((addr, recv_id), packets_tx);
+
+                let mut connection = Connection::new(socket.clone(), routes.clone(), addr, packet.connection_id, recv_id, packets_rx);
+                // Not cryptographic, see above.
+                connection.seq_nr = random_u64() as u16;
+                connection.ack_nr = packet.seq_nr;
+                connection.reply_micro = timestamp_micros().wrapping_sub(packet.timestamp);
+                let _ = incoming.send(UtpStream { shared: connection.shared.clone(), peer_addr: addr });
+
+                tokio::spawn(async move {
+                    connection.send_state().await;
+                    connection.run().await;
+                });
+            },
+            (None, _) if packet.packet_type != PacketType::Reset => {
+                // Any sequence number will do for a reset.
+                let reset = Packet::new(PacketType::Reset, packet.connection_id, random_u64() as u16, packet.seq_nr);
+                let _ = socket.send_to(&reset.to_bytes(), addr).await;
+            },
+            (None, _) => {},
+        }
+    }
+}
+
+// What a `UtpStream` and the task running its connection share.
+#[derive(Default)]
+struct StreamState {
+    // Received data, in order, that hasn't been read yet.
+    read_buffer: VecDeque<u8>,
+    read_waker: Option<Waker>,
+    // Written data that hasn't been put in packets yet.
+    write_buffer: VecDeque<u8>,
+    write_waker: Option<Waker>,
+    eof: bool,
+    error: Option<io::ErrorKind>,
+    // Our side is done writing.
+    closed: bool,
+    // Nobody reads from the stream any more.
+    dropped: bool,
+}
+
+impl StreamState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<StreamState>,
+    // Tells the connection there's something to send.
+    notify: Notify,
+}
+
+impl Shared {
+    fn fail(&self, kind: io::ErrorKind) {
+        let mut state = self.state.lock().unwrap();
+        state.error.get_or_insert(kind);
+        state.wake();
+    }
+}
+
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl std::fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UtpStream").field("peer_addr", &self.peer_addr).finish()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if !state.read_buffer.is_empty() {
+            let length = buf.remaining().min(state.read_buffer.len());
+            let data: Vec<u8> = state.read_buffer.drain(..length).collect();
+            buf.put_slice(&data);
+            drop(state);
+
+            // Our receive window may have opened up again.
+            self.shared.notify.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Some(kind) = state.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if state.eof {
+            return Poll::Ready(Ok(()));
+        }
+
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(kind) = state.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let space = SEND_BUFFER.saturating_sub(state.write_buffer.len());
+        if space == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let length = space.min(buf.len());
+        state.write_buffer.extend(&buf[..length]);
+        drop(state);
+
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(length))
+    }
+
+    // Done once everything written has been sent, not necessarily acked.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(kind) = state.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if state.write_buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        state.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.dropped = true;
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+}
+
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    resend: bool,
+}
+
+// Tracks the lowest one-way delay seen lately. Delays above it are queueing
+// delay, which is what LEDBAT keeps at its target.
+struct DelayHistory {
+    base: u32,
+    current_min: u32,
+    rotated_at: Instant,
+}
+
+impl DelayHistory {
+    fn new() -> Self {
+        DelayHistory { base: u32::MAX, current_min: u32::MAX, rotated_at: Instant::now() }
+    }
+
+    // Records a sample and returns the queueing delay in it.
+    fn add(&mut self, sample: u32) -> u32 {
+        if self.rotated_at.elapsed() > BASE_DELAY_WINDOW {
+            self.base = self.current_min;
+            self.current_min = u32::MAX;
+            self.rotated_at = Instant::now();
+        }
+
+        self.current_min = self.current_min.min(sample);
+        self.base = self.base.min(sample);
+        sample - self.base
+    }
+}
+
+// One end of a connection, run by its own task.
+struct Connection {
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+    addr: SocketAddr,
+    send_id: u16,
+    recv_id: u16,
+    shared: Arc<Shared>,
+    packets: mpsc::UnboundedReceiver<Packet>,
+    connected: bool,
+    on_connect: Option<oneshot::Sender<io::Result<()>>>,
+    // The sequence number of the next packet we send, and the last one we
+    // received in order.
+    seq_nr: u16,
+    ack_nr: u16,
+    in_flight: VecDeque<SentPacket>,
+    out_of_order: HashMap<u16, Packet>,
+    reply_micro: u32,
+    peer_window: usize,
+    cwnd: usize,
+    delays: DelayHistory,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    deadline: Option<Instant>,
+    timeouts: u32,
+    last_ack: u16,
+    duplicate_acks: u32,
+    fin_sent: bool,
+    advertised_window: usize,
+}
+
+impl Connection {
+    fn new(socket: Arc<UdpSocket>, routes: Routes, addr: SocketAddr, send_id: u16, recv_id: u16, packets: mpsc::UnboundedReceiver<Packet>) -> Self {
+        Connection {
+            socket,
+            routes,
+            addr,
+            send_id,
+            recv_id,
+            shared: Arc::new(Shared::default()),
+            packets,
+            connected: true,
+            on_connect: None,
+            seq_nr: 0,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            reply_micro: 0,
+            peer_window: RECEIVE_WINDOW,
+            cwnd: INITIAL_WINDOW,
+            delays: DelayHistory::new(),
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            deadline: None,
+            timeouts: 0,
+            last_ack: 0,
+            duplicate_acks: 0,
+            fin_sent: false,
+            advertised_window: RECEIVE_WINDOW,
+        }
+    }
+
+    async fn run(mut self) {
+        loop {
+            let deadline = self.deadline;
+            tokio::select! {
+                packet = self.packets.recv() => match packet {
+                    Some(packet) => self.handle_packet(packet).await,
+                    None => break,
+                },
+                _ = self.shared.notify.notified() => {},
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => self.on_timeout().await,
+            }
+
+            if self.finished() {
+                break;
+            }
+            self.flush().await;
+        }
+
+        self.routes.lock().unwrap().remove(&(self.addr, self.recv_id));
+    }
+
+    fn finished(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+
+        // Failed, closed before connecting, or our FIN is acked and there's
+        // nothing more to read.
+        let done_writing = self.fin_sent && self.in_flight.is_empty();
+        state.error.is_some() || (state.closed && !self.connected) || (done_writing && (state.eof || state.dropped))
+    }
+
+    fn receive_window(&self) -> usize {
+        RECEIVE_WINDOW.saturating_sub(self.shared.state.lock().unwrap().read_buffer.len())
+    }
+
+    async fn handle_packet(&mut self, packet: Packet) {
+        self.reply_micro = timestamp_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window as usize;
+
+        match packet.packet_type {
+            PacketType::Reset => {
+                let kind = if self.connected { io::ErrorKind::ConnectionReset } else { io::ErrorKind::ConnectionRefused };
+                self.connect_result(Err(kind.into()));
+                self.shared.fail(kind);
+                return;
+            },
+            // Our answer to the SYN got lost.
+            PacketType::Syn => {
+                self.send_state().await;
+                return;
+            },
+            _ => {},
+        }
+
+        if !self.connected {
+            if packet.packet_type != PacketType::State {
+                return;
+            }
+            self.connected = true;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            self.connect_result(Ok(()));
+        }
+
+        self.process_acks(&packet);
+
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.receive(packet);
+            self.send_state().await;
+        }
+    }
+
+    fn connect_result(&mut self, result: io::Result<()>) {
+        if let Some(on_connect) = self.on_connect.take() {
+            let _ = on_connect.send(result);
+        }
+    }
+
+    fn process_acks(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut acked_bytes = 0;
+        let mut progressed = false;
+
+        while self.in_flight.front().is_some_and(|sent| !seq_after(sent.packet.seq_nr, packet.ack_nr)) {
+            let sent = self.in_flight.pop_front().unwrap();
+            acked_bytes += sent.packet.payload.len();
+            self.sample_rtt(&sent, now);
+            progressed = true;
+        }
+
+        if let Some(mask) = &packet.selective_ack {
+            let is_acked = |seq_nr: u16| {
+                let offset = seq_nr.wrapping_sub(packet.ack_nr).wrapping_sub(2) as usize;
+                offset < mask.len() * 8 && mask[offset / 8] & (1 << (offset % 8)) != 0
+            };
+
+            let mut index = 0;
+            while index < self.in_flight.len() {
+                if is_acked(self.in_flight[index].packet.seq_nr) {
+                    let sent = self.in_flight.remove(index).unwrap();
+                    acked_bytes += sent.packet.payload.len();
+                    self.sample_rtt(&sent, now);
+                } else {
+                    index += 1;
+                }
+            }
+
+            // A packet is lost once three packets sent after it have arrived.
+            let acked_after = |seq_nr: u16| {
+                let offset = seq_nr.wrapping_sub(packet.ack_nr).wrapping_sub(2) as usize;
+                (offset + 1..mask.len() * 8).filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0).count()
+            };
+            let mut lost = false;
+            for sent in self.in_flight.iter_mut() {
+                if sent.transmissions == 1 && !sent.resend && acked_after(sent.packet.seq_nr) >= 3 {
+                    sent.resend = true;
+                    lost = true;
+                }
+            }
+            if lost {
+                self.cwnd = (self.cwnd / 2).max(MIN_WINDOW);
+            }
+        }
+
+        if progressed {
+            self.timeouts = 0;
+            self.duplicate_acks = 0;
+        } else if packet.packet_type == PacketType::State && packet.ack_nr == self.last_ack && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == 3 {
+                self.packet_lost();
+            }
+        }
+        self.last_ack = packet.ack_nr;
+
+        if acked_bytes > 0 && packet.timestamp_difference != 0 {
+            self.update_window(acked_bytes, packet.timestamp_difference);
+        }
+
+        if self.in_flight.is_empty() {
+            self.deadline = None;
+        } else if progressed {
+            self.deadline = Some(now + self.timeout);
+        }
+    }
+
+    // Resends the oldest packet in flight and halves the window.
+    fn packet_lost(&mut self) {
+        if let Some(sent) = self.in_flight.front_mut() {
+            if sent.transmissions == 1 {
+                sent.resend = true;
+                self.cwnd = (self.cwnd / 2).max(MIN_WINDOW);
+            }
+        }
+    }
+
+    fn sample_rtt(&mut self, sent: &SentPacket, now: Instant) {
+        // Only packets sent once tell us how long the round trip took.
+        if sent.transmissions != 1 {
+            return;
+        }
+
+        let sample = now - sent.sent_at;
+        match self.rtt {
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            },
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            },
+        }
+
+        self.timeout = (self.rtt.unwrap() + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    // LEDBAT: grow the window while the queueing delay is under target, and
+    // shrink it when over.
+    fn update_window(&mut self, acked_bytes: usize, delay: u32) {
+        let queueing_delay = self.delays.add(delay) as f64;
+        let off_target = (TARGET_DELAY_MICROS - queueing_delay) / TARGET_DELAY_MICROS;
+        let window_factor = acked_bytes.min(self.cwnd) as f64 / self.cwnd.max(acked_bytes) as f64;
+        let gain = MAX_CWND_INCREASE * off_target * window_factor;
+
+        self.cwnd = (self.cwnd as f64 + gain).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        let expected = self.ack_nr.wrapping_add(1);
+
+        if packet.seq_nr == expected {
+            self.deliver(packet);
+            while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.deliver(next);
+            }
+        } else if seq_after(packet.seq_nr, expected) && packet.seq_nr.wrapping_sub(expected) < MAX_OUT_OF_ORDER {
+            self.out_of_order.insert(packet.seq_nr, packet);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+
+        let mut state = self.shared.state.lock().unwrap();
+        match packet.packet_type {
+            PacketType::Fin => state.eof = true,
+            _ => state.read_buffer.extend(packet.payload),
+        }
+        state.wake();
+    }
+
+    async fn on_timeout(&mut self) {
+        self.deadline = None;
+        if self.in_flight.is_empty() {
+            return;
+        }
+
+        self.timeouts += 1;
+        let limit = if self.connected { MAX_TIMEOUTS } else { MAX_SYN_TIMEOUTS };
+        if self.timeouts > limit {
+            debug!("uTP connection to {} timed out", self.addr);
+            self.connect_result(Err(io::ErrorKind::TimedOut.into()));
+            self.shared.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+
+        self.cwnd = MIN_WINDOW;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.resend(0).await;
+        self.deadline = Some(Instant::now() + self.timeout);
+    }
+
+    // Sends what we can: retransmissions, then new data as far as the
+    // congestion window and the peer's receive window allow, then our FIN.
+    async fn flush(&mut self) {
+        if !self.connected {
+            return;
+        }
+
+        for index in 0..self.in_flight.len() {
+            if self.in_flight[index].resend {
+                self.resend(index).await;
+            }
+        }
+
+        loop {
+            let in_flight: usize = self.in_flight.iter().map(|sent| sent.packet.payload.len()).sum();
+            let window = self.cwnd.min(self.peer_window);
+
+            let payload: Vec<u8> = {
+                let mut state = self.shared.state.lock().unwrap();
+                let length = state.write_buffer.len().min(MAX_PAYLOAD);
+                // An empty window still lets one packet through, to probe it.
+                if length == 0 || (!self.in_flight.is_empty() && in_flight + length > window) {
+                    break;
+                }
+                let payload = state.write_buffer.drain(..length).collect();
+                if let Some(waker) = state.write_waker.take() {
+                    waker.wake();
+                }
+                payload
+            };
+
+            let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, self.ack_nr);
+            packet.payload = payload;
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.transmit(packet).await;
+        }
+
+        let closing = {
+            let state = self.shared.state.lock().unwrap();
+            state.closed && state.write_buffer.is_empty()
+        };
+        if closing && !self.fin_sent {
+            let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, self.ack_nr);
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.fin_sent = true;
+            self.transmit(fin).await;
+        }
+
+        // Let the peer know once reading has made room again.
+        let window = self.receive_window();
+        if self.advertised_window < RECEIVE_WINDOW / 2 && window >= self.advertised_window + MAX_PAYLOAD {
+            self.send_state().await;
+        }
+    }
+
+    async fn transmit(&mut self, packet: Packet) {
+        let mut sent = SentPacket { packet, sent_at: Instant::now(), transmissions: 1, resend: false };
+        self.send(&mut sent.packet).await;
+        self.in_flight.push_back(sent);
+
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.timeout);
+        }
+    }
+
+    async fn resend(&mut self, index: usize) {
+        let mut packet = self.in_flight[index].packet.clone();
+        self.send(&mut packet).await;
+
+        let sent = &mut self.in_flight[index];
+        sent.packet = packet;
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        sent.resend = false;
+    }
+
+    async fn send_state(&mut self) {
+        let mut state = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        self.send(&mut state).await;
+    }
+
+    // Fills in the fields that describe our side right now, and sends.
+    async fn send(&mut self, packet: &mut Packet) {
+        packet.timestamp = timestamp_micros();
+        packet.timestamp_difference = self.reply_micro;
+        packet.ack_nr = self.ack_nr;
+        packet.selective_ack = self.selective_ack();
+
+        self.advertised_window = self.receive_window();
+        packet.window = self.advertised_window as u32;
+
+        // A lost datagram looks like any other loss, so errors are left to the timeouts.
+        if let Err(err) = self.socket.send_to(&packet.to_bytes(), self.addr).await {
+            debug!("Could not send uTP packet to {}: {}", self.addr, err);
+        }
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+
+        let offsets: Vec<usize> = self.out_of_order.keys().map(|seq_nr| seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize).collect();
+        // The mask is a multiple of 32 bits long.
+        let length = ((offsets.iter().max().unwrap() / 32 + 1) * 4).min(128);
+
+        let mut mask = vec![0u8; length];
+        for offset in offsets.into_iter().filter(|offset| *offset < length * 8) {
+            mask[offset / 8] |= 1 << (offset % 8);
+        }
+
+        Some(mask)
+    }
+}
@@ -0,0 +1,197 @@
+use std::{ops::Range, time::Duration};
+
+use reqwest::{header::RANGE, StatusCode};
+
+use crate::domain::Torrent;
+
+type WebSeedError = Box<dyn std::error::Error + Send + Sync>;
+// Part of a piece: the URL of the file it's in, if it's not padding, and
+// where in the file.
+type PieceRange = (Option<String>, Range<u64>);
+
+// The server answered a Range request with the whole file. Getting pieces
+// from it would download the file again for every piece, so it's not used.
+#[derive(Debug, thiserror::Error)]
+#[error("Web seed {0} ignores Range requests")]
+pub struct RangesIgnored(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    // BEP 19: the torrent's files on a plain HTTP server, fetched with Range requests.
+    GetRight,
+    // BEP 17: a script that hands out whole pieces by index.
+    Hoffman,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+}
+
+impl WebSeed {
+    // The torrent's `url-list` and `httpseeds`.
+    pub fn from_torrent(torrent: &Torrent) -> Vec<WebSeed> {
+        let get_right = torrent.url_list.iter().flatten().map(|url| WebSeed { url: url.clone(), kind: WebSeedKind::GetRight });
+        let hoffman = torrent.httpseeds.iter().flatten().map(|url| WebSeed { url: url.clone(), kind: WebSeedKind::Hoffman });
+
+        get_right.chain(hoffman).collect()
+    }
+
+    // Where a GetRight seed keeps a file. A URL ending in '/' is a directory
+    // the torrent's name is appended to, for single-file torrents too.
+    pub fn file_url(&self, torrent: &Torrent, path: &[String]) -> String {
+        let mut url = self.url.clone();
+
+        if torrent.is_multi_file() {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str(&encode_component(&torrent.info.name));
+            for component in path {
+                url.push('/');
+                url.push_str(&encode_component(component));
+            }
+        } else if url.ends_with('/') {
+            url.push_str(&encode_component(&torrent.info.name));
+        }
+
+        url
+    }
+
+    // The byte ranges of files that make up the piece, and the URLs to get
+    // them from. Padding files aren't on the server, so they have no URL.
+    pub fn piece_ranges(&self, torrent: &Torrent, piece_index: u32) -> Result<Vec<PieceRange>, WebSeedError> {
+        let piece_start = piece_index as u64 * torrent.info.piece_length as u64;
+        let piece_end = piece_start + torrent.get_piece_length(piece_index as usize).map_err(|err| err.to_string())? as u64;
+
+        let mut ranges = vec![];
+        let mut offset = 0;
+
+        for file in torrent.files() {
+            let file_start = offset;
+            let file_end = offset + file.length as u64;
+            offset = file_end;
+
+            if file_end <= piece_start || file_start >= piece_end {
+                continue;
+            }
+
+            let start = piece_start.max(file_start) - file_start;
+            let end = piece_end.min(file_end) - file_start;
+            let url = Some(self.file_url(torrent, &file.path)).filter(|_| !file.is_padding());
+            ranges.push((url, start..end));
+        }
+
+        Ok(ranges)
+    }
+
+    // Fetches the piece. It isn't verified here.
+    pub async fn fetch_piece(&self, http: &reqwest::Client, torrent: &Torrent, piece_index: u32, timeout: Duration) -> Result<Vec<u8>, WebSeedError> {
+        let data = match self.kind {
+            WebSeedKind::GetRight => {
+                let mut data = vec![];
+                for (url, range) in self.piece_ranges(torrent, piece_index)? {
+                    match url {
+                        Some(url) => data.extend(fetch_range(http, &url, range, timeout).await?),
+                        None => data.resize(data.len() + (range.end - range.start) as usize, 0),
+                    }
+                }
+                data
+            },
+            WebSeedKind::Hoffman => self.fetch_hoffman_piece(http, torrent, piece_index, timeout).await?,
+        };
+
+        let expected = torrent.get_piece_length(piece_index as usize).map_err(|err| err.to_string())?;
+        if data.len() != expected {
+            return Err(format!("Web seed {} sent {} bytes for piece {}, expected {}", self.url, data.len(), piece_index, expected).into());
+        }
+
+        Ok(data)
+    }
+
+    async fn fetch_hoffman_piece(&self, http: &reqwest::Client, torrent: &Torrent, piece_index: u32, timeout: Duration) -> Result<Vec<u8>, WebSeedError> {
+        let info_hash = hex::decode(torrent.info_hash().map_err(|err| err.to_string())?)?;
+        let encoded_info_hash: String = info_hash.iter().map(|byte| format!("%{:02X}", byte)).collect();
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}info_hash={}&piece={}", self.url, separator, encoded_info_hash, piece_index);
+
+        let response = http.get(&url).timeout(timeout).send().await?;
+
+        // A busy seed answers with the number of seconds to wait instead.
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = response.text().await.unwrap_or_default();
+            return Err(format!("HTTP seed {} is busy, retry after {} seconds", self.url, retry_after.trim()).into());
+        }
+
+        Ok(response.error_for_status()?.bytes().await?.to_vec())
+    }
+}
+
+async fn fetch_range(http: &reqwest::Client, url: &str, range: Range<u64>, timeout: Duration) -> Result<Vec<u8>, WebSeedError> {
+    if range.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let response = http
+        .get(url)
+        .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let status = response.status();
+    let whole_file = range.start == 0 && response.content_length() == Some(range.end);
+
+    // Servers that ignore ranges send the whole file. That's only what we
+    // asked for if the range covers all of it, otherwise the body is dropped
+    // unread.
+    if status == StatusCode::OK && !whole_file {
+        return Err(Box::new(RangesIgnored(url.to_string())));
+    }
+
+    let body = response.bytes().await?;
+    match status {
+        StatusCode::PARTIAL_CONTENT | StatusCode::OK if body.len() as u64 == range.end - range.start => Ok(body.to_vec()),
+        _ => Err(format!("Unexpected response from {}: {} with {} bytes", url, status, body.len()).into()),
+    }
+}
+
+fn encode_component(component: &str) -> String {
+    component
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Tracks how fast a web seed has been, so it can be compared with peers.
+#[derive(Debug)]
+pub struct WebSeedPeer {
+    pub seed: WebSeed,
+    downloaded: u64,
+    busy: Duration,
+}
+
+impl WebSeedPeer {
+    pub fn new(seed: WebSeed) -> Self {
+        WebSeedPeer { seed, downloaded: 0, busy: Duration::ZERO }
+    }
+
+    pub fn data_received(&mut self, bytes: u64, elapsed: Duration) {
+        self.downloaded += bytes;
+        self.busy += elapsed;
+    }
+
+    // Bytes per second while fetching.
+    pub fn download_rate(&self) -> f64 {
+        if self.busy.is_zero() {
+            return 0.0;
+        }
+
+        self.downloaded as f64 / self.busy.as_secs_f64()
+    }
+}